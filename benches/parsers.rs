@@ -0,0 +1,62 @@
+#[macro_use] extern crate criterion;
+extern crate http_file_headers;
+
+use criterion::{Criterion, black_box};
+use http_file_headers::{Config, Input};
+use http_file_headers::parser::{AcceptEncodingParser, RangeParser};
+
+// values sent by real browsers, curl and download managers
+const ACCEPT_ENCODING: &[&str] = &[
+    "gzip, deflate, br",
+    "gzip, deflate, br, zstd",
+    "br;q=1.0, gzip;q=0.8, *;q=0.1",
+    "identity",
+];
+const RANGE: &[&str] = &[
+    "bytes=0-",
+    "bytes=1048576-2097151",
+    "bytes=-500",
+    "bytes=0-499, 1000-1499, 2000-",
+];
+
+fn accept_encoding(c: &mut Criterion) {
+    c.bench_function("accept_encoding", |b| b.iter(|| {
+        for value in ACCEPT_ENCODING {
+            let mut parser = AcceptEncodingParser::new();
+            parser.add_header(black_box(value.as_bytes()));
+            black_box(parser.done());
+        }
+    }));
+}
+
+fn range(c: &mut Criterion) {
+    c.bench_function("range", |b| b.iter(|| {
+        for value in RANGE {
+            let mut parser = RangeParser::new();
+            parser.add_header(black_box(value.as_bytes()));
+            black_box(parser.done()).ok();
+        }
+    }));
+}
+
+fn from_headers(c: &mut Criterion) {
+    let cfg = Config::new().done();
+    let headers: &[(&str, &[u8])] = &[
+        ("Host", b"example.com"),
+        ("User-Agent", b"Mozilla/5.0 (X11; Linux x86_64; rv:120.0) \
+                         Gecko/20100101 Firefox/120.0"),
+        ("Accept", b"text/html,application/xhtml+xml,\
+                     application/xml;q=0.9,*/*;q=0.8"),
+        ("Accept-Encoding", b"gzip, deflate, br, zstd"),
+        ("Range", b"bytes=1048576-"),
+        ("If-None-Match", b"W/\"tYJT9KJUI0KX2I5q\""),
+        ("If-Modified-Since", b"Tue, 22 Aug 2017 20:47:13 GMT"),
+    ];
+    c.bench_function("from_headers", |b| b.iter(|| {
+        black_box(Input::from_headers(&cfg, "GET",
+            black_box(headers).iter().cloned()))
+    }));
+}
+
+criterion_group!(benches, accept_encoding, range, from_headers);
+criterion_main!(benches);
@@ -9,7 +9,6 @@ extern crate tokio_io;
 #[macro_use] extern crate lazy_static;
 
 use std::time::Duration;
-use std::path::{Path};
 use std::sync::Arc;
 
 use futures::{Future, Stream, Async};
@@ -48,11 +47,20 @@ fn common_headers<S>(e: &mut server::Encoder<S>) {
         format_args!("serve_dir/{}", env!("CARGO_PKG_VERSION"))).unwrap();
 }
 
-fn respond_error<S: 'static>(status: Status, mut e: server::Encoder<S>)
+fn respond_error<S: 'static>(status: Status, e: server::Encoder<S>)
     -> FutureResult<server::EncoderDone<S>, server::Error>
+{
+    respond_error_with(status, e, |_| {})
+}
+
+fn respond_error_with<S: 'static, H>(status: Status,
+    mut e: server::Encoder<S>, headers: H)
+    -> FutureResult<server::EncoderDone<S>, server::Error>
+    where H: FnOnce(&mut server::Encoder<S>)
 {
     let body = format!("{} {}", status.code(), status.reason());
     e.status(status);
+    headers(&mut e);
     e.add_length(body.as_bytes().len() as u64).unwrap();
     common_headers(&mut e);
     if e.done_headers().unwrap() {
@@ -91,16 +99,22 @@ impl<S: AsyncWrite + Send + 'static> server::Codec<S> for Codec {
                     // add headers
                     if e.done_headers().unwrap() {
                         // start writing body
-                        Either::B(loop_fn((e, outf), |(mut e, mut outf)| {
+                        // guard stays in this future, so disk thread stops
+                        // reading when the connection is dropped
+                        let (stream, guard) = outf.into_stream();
+                        Either::B(loop_fn((e, stream, guard),
+                            |(mut e, mut stream, guard)|
+                        {
                             POOL.spawn_fn(move || {
-                                outf.read_chunk(&mut e).map(|b| (b, e, outf))
-                            }).and_then(|(b, e, outf)| {
-                                e.wait_flush(4096).map(move |e| (b, e, outf))
-                            }).map(|(b, e, outf)| {
+                                stream.read_chunk(&mut e)
+                                    .map(|b| (b, e, stream))
+                            }).and_then(|(b, e, stream)| {
+                                e.wait_flush(4096).map(move |e| (b, e, stream))
+                            }).map(|(b, e, stream)| {
                                 if b == 0 {
                                     Loop::Break(e.done())
                                 } else {
-                                    Loop::Continue((e, outf))
+                                    Loop::Continue((e, stream, guard))
                                 }
                             }).map_err(|e| server::Error::custom(e))
                         }))
@@ -108,7 +122,8 @@ impl<S: AsyncWrite + Send + 'static> server::Codec<S> for Codec {
                         Either::A(ok(e.done()))
                     }
                 }
-                Ok(Output::FileHead(head)) | Ok(Output::NotModified(head)) => {
+                Ok(Output::FileHead(head)) | Ok(Output::NotModified(head)) |
+                Ok(Output::InternalRedirect(head)) => {
                     if head.is_not_modified() {
                         e.status(Status::NotModified);
                     } else if head.is_partial() {
@@ -125,10 +140,42 @@ impl<S: AsyncWrite + Send + 'static> server::Codec<S> for Codec {
                     assert_eq!(e.done_headers().unwrap(), false);
                     Either::A(ok(e.done()))
                 }
+                Ok(Output::PreconditionFailed(head)) => {
+                    e.status(Status::PreconditionFailed);
+                    e.add_length(head.content_length()).unwrap();
+                    common_headers(&mut e);
+                    for (name, val) in head.headers() {
+                        e.format_header(name, val).unwrap();
+                    }
+                    assert_eq!(e.done_headers().unwrap(), false);
+                    Either::A(ok(e.done()))
+                }
+                Ok(Output::RangeNotSatisfiable(err)) => {
+                    e.status(Status::RequestRangeNotSatisfiable);
+                    e.format_header("Content-Range", err.content_range())
+                        .unwrap();
+                    if let Some(ctype) = err.content_type() {
+                        e.add_header("Content-Type", ctype).unwrap();
+                    }
+                    e.add_length(err.body().len() as u64).unwrap();
+                    common_headers(&mut e);
+                    if e.done_headers().unwrap() {
+                        e.write_body(err.body());
+                    }
+                    Either::A(ok(e.done()))
+                }
                 Ok(Output::InvalidRange) => {
                     Either::A(respond_error(
                         Status::RequestRangeNotSatisfiable, e))
                 }
+                Ok(Output::Options) => {
+                    e.status(Status::Ok);
+                    e.add_length(0).unwrap();
+                    common_headers(&mut e);
+                    e.add_header("Allow", "GET, HEAD, OPTIONS").unwrap();
+                    assert_eq!(e.done_headers().unwrap(), false);
+                    Either::A(ok(e.done()))
+                }
                 Ok(Output::InvalidMethod) => {
                     Either::A(respond_error(
                         Status::MethodNotAllowed, e))
@@ -136,6 +183,14 @@ impl<S: AsyncWrite + Send + 'static> server::Codec<S> for Codec {
                 Ok(Output::NotFound) | Ok(Output::Directory) => {
                     Either::A(respond_error(Status::NotFound, e))
                 }
+                Ok(Output::ServiceUnavailable(seconds)) => {
+                    Either::A(respond_error_with(Status::ServiceUnavailable, e,
+                        |e| e.format_header("Retry-After", seconds).unwrap()))
+                }
+                Ok(_) => {
+                    // variants added in future versions
+                    Either::A(respond_error(Status::InternalServerError, e))
+                }
                 Err(status) => {
                     Either::A(respond_error(status, e))
                 }
@@ -150,11 +205,16 @@ impl<S: AsyncWrite + Send + 'static> server::Dispatcher<S> for Dispatcher {
         -> Result<Self::Codec, server::Error>
     {
         let inp = Input::from_headers(&*CONFIG, head.method(), head.headers());
-        let path = Path::new("./public").join(head.path()
-            .expect("only static requests expected") // fails on OPTIONS *
-            .trim_left_matches(|x| x == '/'));
+        let path = match *head.request_target() {
+            server::RequestTarget::Asterisk => String::from("*"),
+            _ => head.path()
+                .expect("only static requests expected") // fails on CONNECT
+                .to_string(),
+        };
         let fut = POOL.spawn_fn(move || {
-            inp.probe_file(&path).map_err(|e| {
+            inp.probe_file_under("./public", &path)
+            .or_else(|e| Output::from_io_error(e, 1))
+            .map_err(|e| {
                 error!("Error reading file {:?}: {}", path, e);
                 Status::InternalServerError
             })
@@ -0,0 +1,130 @@
+use std::str::from_utf8;
+
+use accept_encoding::parse_q;
+
+
+/// Maximum number of alternate formats probed for a file
+pub const MAX_FORMATS: usize = 4;
+
+/// Formats accepted by user agent in the order of preference
+///
+/// Formats are stored as indexes in the format table of the `Config`,
+/// as only those formats can be served anyway.
+#[derive(Debug, Clone, Copy)]
+pub struct AcceptFormats {
+    ordered: [u8; MAX_FORMATS],
+    len: u8,
+}
+
+/// Parser for `Accept` header
+///
+/// Only media types listed in the table are kept. Wildcards, like
+/// `image/*`, are ignored as they don't express any preference of the
+/// alternate format over the original one.
+pub struct AcceptParser<'a> {
+    table: &'a [(String, String)],
+    buf: Vec<(usize, u16 /*0..1000*/)>,
+}
+
+impl AcceptFormats {
+    pub fn none() -> AcceptFormats {
+        AcceptFormats {
+            ordered: [0; MAX_FORMATS],
+            len: 0,
+        }
+    }
+    /// Iterate over indexes in the format table, most preferred first
+    pub fn iter<'x>(&'x self) -> impl Iterator<Item=usize> + 'x {
+        self.ordered[..self.len as usize].iter().map(|&x| x as usize)
+    }
+    pub fn contains(&self, idx: usize) -> bool {
+        self.iter().any(|x| x == idx)
+    }
+}
+
+impl<'a> AcceptParser<'a> {
+    pub fn new(table: &'a [(String, String)]) -> AcceptParser<'a> {
+        AcceptParser {
+            table: table,
+            buf: Vec::new(),
+        }
+    }
+    fn add_chunk(&mut self, chunk: &[u8]) {
+        let mut piter = chunk.split(|&x| x == b';');
+        let media = match piter.next().and_then(|x| from_utf8(x).ok()) {
+            Some(media) => media.trim(),
+            None => return,
+        };
+        let idx = match self.table.iter()
+            .position(|(m, _)| m.eq_ignore_ascii_case(media))
+        {
+            Some(idx) if idx <= u8::MAX as usize => idx,
+            _ => return,
+        };
+        // media type parameters may precede the quality
+        let qparam = piter.find(|p| {
+            from_utf8(p).map(|p| p.trim().starts_with("q=")).unwrap_or(false)
+        });
+        let q = match parse_q(qparam) {
+            Some(q) => q,
+            None => return,
+        };
+        if !self.buf.iter().any(|&(i, _)| i == idx) {
+            self.buf.push((idx, q));
+        }
+    }
+    pub fn add_header(&mut self, header: &[u8]) {
+        for chunk in header.split(|&x| x == b',') {
+            self.add_chunk(chunk)
+        }
+    }
+    pub fn done(mut self) -> AcceptFormats {
+        self.buf.sort_by(|&(a, qa), &(b, qb)|
+            qb.cmp(&qa).then(a.cmp(&b)));
+        let mut result = AcceptFormats::none();
+        let it = self.buf.iter().filter(|&&(_, q)| q != 0).take(MAX_FORMATS);
+        for &(idx, _) in it {
+            result.ordered[result.len as usize] = idx as u8;
+            result.len += 1;
+        }
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table() -> Vec<(String, String)> {
+        vec![
+            (String::from("image/avif"), String::from("avif")),
+            (String::from("image/webp"), String::from("webp")),
+        ]
+    }
+
+    fn parse(val: &str) -> Vec<usize> {
+        let table = table();
+        let mut parser = AcceptParser::new(&table);
+        parser.add_header(val.as_bytes());
+        parser.done().iter().collect()
+    }
+
+    #[test]
+    fn browser() {
+        assert_eq!(parse("image/avif,image/webp,image/apng,*/*;q=0.8"),
+                   vec![0, 1]);
+        assert_eq!(parse("image/webp,*/*"), vec![1]);
+        assert_eq!(parse("image/*,*/*;q=0.8"), vec![]);
+        assert_eq!(parse(""), vec![]);
+    }
+
+    #[test]
+    fn quality() {
+        assert_eq!(parse("image/avif;q=0.5, image/webp"), vec![1, 0]);
+        assert_eq!(parse("image/avif;q=0, image/webp;q=0.1"), vec![1]);
+        assert_eq!(parse("Image/WebP; charset=x; q=0.3"), vec![1]);
+        assert_eq!(parse("image/webp;q=bad"), vec![]);
+        // first occurrence wins
+        assert_eq!(parse("image/webp;q=0, image/webp"), vec![]);
+    }
+}
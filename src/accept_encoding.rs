@@ -1,6 +1,6 @@
-use std::fmt;
-use std::str::from_utf8;
-use std::slice;
+use core::fmt;
+use core::str::from_utf8;
+use core::slice;
 
 /// Single encoding that might be accepted by user agent
 ///
@@ -12,6 +12,8 @@ use std::slice;
 pub enum Encoding {
     /// Brotli encoding (trasferred as "br", and has same extension)
     Brotli,
+    /// Zstandard encoding (trasferred as "zstd", and extension ".zst")
+    Zstd,
     /// Gzip encoding (trasferred as "gzip", and extension ".gz")
     Gzip,
     /// Identity means no encoding
@@ -20,27 +22,42 @@ pub enum Encoding {
     __Nonexhaustive,
 }
 
+/// Encodings accepted by user agent, in the order of preference
+///
+/// Contains only supported encodings, created by `AcceptEncodingParser`
 #[derive(Debug, Clone)]
 pub struct AcceptEncoding {
-    ordered: [Encoding; 3],
+    /// `None` terminates the list, identity is absent if it's excluded
+    ordered: [Option<Encoding>; 4],
 }
 
 /// Parser for accept encoding header
 ///
 /// It drops unaccepted encodings and returns only supported ones
+#[derive(Debug)]
 pub struct AcceptEncodingParser {
-    buf: Vec<(Encoding, u16 /*0..1000*/)>,
-    /// TODO(tailhook) it's unclear what to do with `allow_any`
+    /// each of the supported encodings is stored at most once, so this
+    /// never needs to allocate
+    buf: [(Encoding, u16 /*0..1000*/); 4],
+    len: usize,
+    /// `*;q=0` excludes identity too, unless it's listed explicitly
     allow_any: bool,
+    /// bytes of header values that can still be parsed
+    bytes_left: usize,
+    overflow: bool,
 }
 
+/// Default limit of summed length of `Accept-Encoding` headers
+///
+/// Real clients send less than a hundred bytes.
+const DEFAULT_LIMIT: usize = 1024;
+
 /// Iterator over encodings in preferred order
 ///
 /// You may create one using `Input::encodings()`
 #[derive(Debug)]
 pub struct Iter<'a> {
-    slice: slice::Iter<'a, Encoding>,
-    identity: bool,
+    slice: slice::Iter<'a, Option<Encoding>>,
 }
 
 impl Encoding {
@@ -52,44 +69,78 @@ impl Encoding {
             Identity => "",
             Gzip => ".gz",
             Brotli => ".br",
-            __Nonexhaustive => unimplemented!(),
+            Zstd => ".zst",
+            __Nonexhaustive => unreachable!("hidden variant"),
+        }
+    }
+    /// Returns the name of the encoding as used in `Accept-Encoding`
+    /// and `Content-Encoding` headers
+    pub fn name(&self) -> &'static str {
+        use self::Encoding::*;
+        match *self {
+            Identity => "identity",
+            Gzip => "gzip",
+            Brotli => "br",
+            Zstd => "zstd",
+            __Nonexhaustive => unreachable!("hidden variant"),
         }
     }
 }
 
 impl AcceptEncoding {
+    /// Iterate over encodings, most preferred first
+    ///
+    /// `Identity` is returned last, unless it's explicitly preferred. It's
+    /// not returned at all if excluded by `identity;q=0`, or by `*;q=0`
+    /// without an explicit `identity`.
     pub fn iter(&self) -> Iter {
         Iter {
             slice: self.ordered.iter(),
-            identity: false,
         }
     }
+    /// Only identity encoding is accepted
     pub fn identity() -> AcceptEncoding {
         AcceptEncoding {
-            ordered: [Encoding::Identity; 3],
+            ordered: [Some(Encoding::Identity), None, None, None],
         }
     }
+    /// Reorders encodings preferred over identity by position in `order`
+    ///
+    /// Encodings not in the `order` keep preference order after the listed
+    /// ones.
+    #[cfg(feature="std")]
+    pub(crate) fn probe_order(&self, order: &[Encoding]) -> AcceptEncoding {
+        let mut result = self.clone();
+        let preferred = result.ordered.iter()
+            .position(|&e| e == Some(Encoding::Identity) || e.is_none())
+            .unwrap_or(result.ordered.len());
+        result.ordered[..preferred].sort_by_key(|e| {
+            order.iter().position(|x| Some(*x) == *e).unwrap_or(order.len())
+        });
+        result
+    }
+    /// Drops encodings which are not in the `allowed` list
+    ///
+    /// Identity is never dropped (nor added if it's not accepted)
+    pub fn retain(&mut self, allowed: &[Encoding]) {
+        let mut result = [None; 4];
+        let it = self.iter()
+            .filter(|e| *e == Encoding::Identity || allowed.contains(e));
+        for (i, e) in it.enumerate() {
+            result[i] = Some(e);
+        }
+        self.ordered = result;
+    }
 }
 
 impl<'a> Iterator for Iter<'a> {
     type Item = Encoding;
     fn next(&mut self) -> Option<Encoding> {
-        loop {
-            match self.slice.next() {
-                Some(&Encoding::Identity) if !self.identity => {
-                    self.identity = true;
-                    break Some(Encoding::Identity)
-                }
-                Some(&Encoding::Identity) => {}
-                Some(&Encoding::__Nonexhaustive) => unreachable!(),
-                Some(value) => break Some(*value),
-                None => break None,
-            }
-        }
+        self.slice.next().and_then(|&e| e)
     }
 }
 
-fn parse_q(val: Option<&[u8]>) -> Option<u16> {
+pub(crate) fn parse_q(val: Option<&[u8]>) -> Option<u16> {
     if let Some(qbytes) = val {
         if let Ok(qstr) = from_utf8(qbytes) {
             let qstr = qstr.trim();
@@ -134,10 +185,22 @@ fn parse_q(val: Option<&[u8]>) -> Option<u16> {
 }
 
 impl AcceptEncodingParser {
+    /// Create a parser (when no headers added, only identity is accepted)
     pub fn new() -> AcceptEncodingParser {
+        AcceptEncodingParser::with_limit(DEFAULT_LIMIT)
+    }
+    /// Create a parser that accepts at most `max_bytes` of headers
+    ///
+    /// Lengths of all the headers are summed. If they are longer than the
+    /// limit, the headers are ignored, so only identity is accepted. The
+    /// default (in `new`) is 1024 bytes.
+    pub fn with_limit(max_bytes: usize) -> AcceptEncodingParser {
         AcceptEncodingParser {
-            buf: Vec::new(),
+            buf: [(Encoding::Identity, 0); 4],
+            len: 0,
             allow_any: true,
+            bytes_left: max_bytes,
+            overflow: false,
         }
     }
     fn add_chunk(&mut self, chunk: &[u8]) {
@@ -147,7 +210,10 @@ impl AcceptEncodingParser {
         let enc = match enc {
             Some("identity") => Some(Identity),
             Some("br") => Some(Brotli),
-            Some("gzip") => Some(Gzip),
+            // x-gzip is sent by some old clients as a synonym of gzip
+            // (x-compress is not supported, as compress itself isn't)
+            Some("gzip") | Some("x-gzip") => Some(Gzip),
+            Some("zstd") => Some(Zstd),
             Some("*") => None,
             _ => return,
         };
@@ -159,44 +225,84 @@ impl AcceptEncodingParser {
         match (enc, q) {
             (None, 0) => self.allow_any = false,
             (None, _) => {}, // useless?
-            (Some(x), _) => self.buf.push((x, q)),
+            (Some(x), _) => {
+                // the last occurrence wins, even in another header
+                let known = self.buf[..self.len].iter_mut()
+                    .find(|&&mut (e, _)| e == x);
+                match known {
+                    Some(item) => item.1 = q,
+                    None => {
+                        self.buf[self.len] = (x, q);
+                        self.len += 1;
+                    }
+                }
+            }
         }
     }
+    /// Add a value of `Accept-Encoding` header
+    ///
+    /// Can be called multiple times if there are multiple headers, this
+    /// is the same as joining them with comma. If the encoding is listed
+    /// more than once, only the last q-value is used. Encodings with the
+    /// same q-value are ordered as: br, zstd, gzip, identity.
     pub fn add_header(&mut self, header: &[u8]) {
+        if self.overflow || header.len() > self.bytes_left {
+            self.overflow = true;
+            return;
+        }
+        self.bytes_left -= header.len();
         for chunk in header.split(|&x| x == b',') {
             self.add_chunk(chunk)
         }
     }
+    /// Finish parsing and return accepted encodings
     pub fn done(mut self) -> AcceptEncoding {
-        self.buf.sort_by(|&(a, qa), &(b, qb)|
+        if self.overflow {
+            return AcceptEncoding::identity();
+        }
+        let buf = &mut self.buf[..self.len];
+        // encodings are unique, so unstable sort gives the same order
+        buf.sort_unstable_by(|&(a, qa), &(b, qb)|
             qb.cmp(&qa).then(a.cmp(&b)));
         let mut result = AcceptEncoding {
-            ordered: [Encoding::Identity; 3],
+            ordered: [None; 4],
+        };
+        let accepted = buf.iter().filter(|&&(_, q)| q != 0).map(|&(e, _)| e);
+        // identity is acceptable by default, so it's added last if not
+        // listed (the listed one is either ordered by q or excluded)
+        let listed = buf.iter().any(|&(e, _)| e == Encoding::Identity);
+        let identity = if listed || !self.allow_any {
+            None
+        } else {
+            Some(Encoding::Identity)
         };
-        // TODO(tailhook) process disabled (q=0) encodings
-        let it = self.buf.iter().filter(|&&(_, q)| q != 0).take(3).enumerate();
-        for (i, &(e, _)) in it {
-            result.ordered[i] = e;
+        for (slot, e) in result.ordered.iter_mut()
+            .zip(accepted.chain(identity))
+        {
+            *slot = Some(e);
         }
         return result;
     }
 }
 
+impl Default for AcceptEncodingParser {
+    fn default() -> AcceptEncodingParser {
+        AcceptEncodingParser::new()
+    }
+}
+
 impl fmt::Display for Encoding {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use self::Encoding::*;
-        match *self {
-            Brotli => f.write_str("br"),
-            Gzip => f.write_str("gzip"),
-            Identity => f.write_str("identity"),
-            __Nonexhaustive => unreachable!(),
-        }
+        f.write_str(self.name())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::mem::size_of;
+    use core::mem::size_of;
+    use alloc::string::ToString;
+    use alloc::vec;
+    use alloc::vec::Vec;
     use super::*;
 
     #[cfg(target_arch="x86_64")]
@@ -277,6 +383,43 @@ mod test {
     fn test_gz_br_q() {
         assert_eq!(to_ext("gzip, br;q=0.5"), vec![".gz", ".br", ""]);
     }
+    #[test]
+    fn retain() {
+        let mut parser = AcceptEncodingParser::new();
+        parser.add_header(b"br, gzip, zstd;q=0.5");
+        let mut ae = parser.done();
+        ae.retain(&[Encoding::Gzip, Encoding::Zstd]);
+        assert_eq!(ae.iter().collect::<Vec<_>>(),
+                   vec![Encoding::Gzip, Encoding::Zstd, Encoding::Identity]);
+        ae.retain(&[]);
+        assert_eq!(ae.iter().collect::<Vec<_>>(), vec![Encoding::Identity]);
+    }
+
+    #[test]
+    fn test_x_gzip() {
+        assert_eq!(to_ext("x-gzip"), vec![".gz", ""]);
+        assert_eq!(to_ext("x-compress, x-gzip;q=0.5, br"),
+                   vec![".br", ".gz", ""]);
+    }
+
+    #[test]
+    fn test_zstd() {
+        assert_eq!(to_ext("gzip, zstd, br"), vec![".br", ".zst", ".gz", ""]);
+        assert_eq!(to_ext("gzip, zstd;q=0.9"), vec![".gz", ".zst", ""]);
+    }
+
+    #[test]
+    fn names() {
+        use self::Encoding::*;
+        let all = [Brotli, Zstd, Gzip, Identity];
+        assert_eq!(all.iter().map(|x| x.name()).collect::<Vec<_>>(),
+                   vec!["br", "zstd", "gzip", "identity"]);
+        assert_eq!(all.iter().map(|x| x.suffix()).collect::<Vec<_>>(),
+                   vec![".br", ".zst", ".gz", ""]);
+        assert_eq!(all.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+                   vec!["br", "zstd", "gzip", "identity"]);
+    }
+
     #[test]
     fn test_identity() {
         assert_eq!(to_ext("identity"), vec![""]);
@@ -284,4 +427,70 @@ mod test {
         assert_eq!(to_ext("identity, br"), vec![".br", ""]);
         assert_eq!(to_ext("identity, br;q=0.5"), vec!["", ".br"]);
     }
+
+    #[test]
+    fn excluded_identity() {
+        assert_eq!(to_ext("gzip, identity;q=0"), vec![".gz"]);
+        assert_eq!(to_ext("identity;q=0"), Vec::<&str>::new());
+        assert_eq!(to_ext("br, *;q=0"), vec![".br"]);
+        assert_eq!(to_ext("br, *;q=0, identity;q=0.5"), vec![".br", ""]);
+        assert_eq!(to_ext("gzip, *;q=0.5"), vec![".gz", ""]);
+    }
+
+    fn to_ext_multi(headers: &[&str]) -> Vec<&'static str> {
+        let mut parser = AcceptEncodingParser::new();
+        for h in headers {
+            parser.add_header(h.as_bytes());
+        }
+        parser.done().iter().map(|x| x.suffix()).collect()
+    }
+
+    #[test]
+    fn multiple_headers() {
+        assert_eq!(to_ext_multi(&["gzip", "br;q=0.5"]), vec![".gz", ".br", ""]);
+        assert_eq!(to_ext_multi(&["gzip, br", "gzip;q=0.1"]),
+                   vec![".br", ".gz", ""]);
+        assert_eq!(to_ext_multi(&["br;q=0.1", "br"]), vec![".br", ""]);
+        assert_eq!(to_ext_multi(&["br, gzip", "br;q=0"]), vec![".gz", ""]);
+        assert_eq!(to_ext_multi(&["x-gzip;q=0.2", "zstd;q=0.5, gzip"]),
+                   vec![".gz", ".zst", ""]);
+        // duplicates don't use up the slots
+        assert_eq!(to_ext_multi(&["gzip", "gzip", "gzip", "gzip", "br"]),
+                   vec![".br", ".gz", ""]);
+    }
+
+    #[test]
+    fn benchmark_values() {
+        // same values as in `benches/parsers.rs`
+        assert_eq!(to_ext("gzip, deflate, br"), vec![".br", ".gz", ""]);
+        assert_eq!(to_ext("gzip, deflate, br, zstd"),
+                   vec![".br", ".zst", ".gz", ""]);
+        assert_eq!(to_ext("br;q=1.0, gzip;q=0.8, *;q=0.1"),
+                   vec![".br", ".gz", ""]);
+        assert_eq!(to_ext("identity"), vec![""]);
+        assert_eq!(to_ext("identity, zstd;q=0.1, gzip, br;q=0.9, gzip;q=0"),
+                   vec!["", ".br", ".zst"]);
+    }
+
+    #[test]
+    fn oversized() {
+        let mut parser = AcceptEncodingParser::with_limit(16);
+        parser.add_header(b"gzip");
+        parser.add_header(b"br, zstd");
+        assert_eq!(parser.done().iter().count(), 4);
+
+        // limit is for all the headers summed
+        let mut parser = AcceptEncodingParser::with_limit(16);
+        parser.add_header(b"gzip, br");
+        parser.add_header(b"zstd, identity");
+        parser.add_header(b"gzip");
+        assert_eq!(parser.done().iter().collect::<Vec<_>>(),
+                   vec![Encoding::Identity]);
+
+        let huge = "gzip, ".repeat(1 << 20);
+        let mut parser = AcceptEncodingParser::new();
+        parser.add_header(huge.as_bytes());
+        assert_eq!(parser.done().iter().collect::<Vec<_>>(),
+                   vec![Encoding::Identity]);
+    }
 }
@@ -0,0 +1,257 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Read, Write, Seek};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use output::{FileWrapper, Head, Output, size_of_range};
+
+
+/// Body of the response that may be read in a disk thread
+///
+/// Created by `FileWrapper::into_stream` together with a `BodyGuard`. The
+/// guard is kept by the connection and the stream is sent to a disk thread
+/// for each chunk. When connection is closed by the client, the guard is
+/// dropped and the stream doesn't read anything from disk anymore, even if
+/// reading of the next chunk is already scheduled.
+#[derive(Debug)]
+pub struct BodyStream<F=File> {
+    file: FileWrapper<F>,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Cancels the `BodyStream` when dropped
+#[derive(Debug)]
+pub struct BodyGuard {
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Body of the response for callers that stream files themselves
+///
+/// Returned by `Output::into_parts` and `FileWrapper::into_parts`. Offsets
+/// are absolute offsets in the file, so they can be used with `sendfile`,
+/// `pread` or similar calls directly.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Body<F=File> {
+    /// Send `len` bytes of the `file` starting at `offset`
+    File {
+        /// The opened file
+        file: F,
+        /// Offset of the first byte to send
+        offset: u64,
+        /// Number of bytes to send
+        len: u64,
+    },
+    /// Send the bytes as is (these are empty for responses without a body)
+    Bytes(Vec<u8>),
+    /// Send `multipart/byteranges` body, chunks in order
+    Multipart {
+        /// The opened file
+        file: F,
+        /// Delimiters and parts of the file
+        chunks: Vec<Chunk>,
+    },
+}
+
+/// A piece of the `Body::Multipart`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Chunk {
+    /// Delimiter and headers of the part (or a closing delimiter)
+    Bytes(Vec<u8>),
+    /// Send `len` bytes of the file starting at `offset`
+    File {
+        /// Offset of the first byte to send
+        offset: u64,
+        /// Number of bytes to send
+        len: u64,
+    },
+}
+
+impl<F> FileWrapper<F> {
+    /// Split into headers and the body to be sent by the caller
+    ///
+    /// Must be called before `read_chunk`, as body describes the whole
+    /// response.
+    pub fn into_parts(self) -> (Head, Body<F>) {
+        let FileWrapper { head, file, .. } = self;
+        let body = if let Some(ref mp) = head.multipart {
+            let ctype = head.content_type.as_ref().map(|x| x as &Display);
+            let mut chunks = Vec::with_capacity(mp.parts.len()*2 + 1);
+            for (idx, part) in mp.parts.iter().enumerate() {
+                chunks.push(Chunk::Bytes(mp.part_header(idx, ctype)));
+                chunks.push(Chunk::File {
+                    offset: part.start,
+                    // parts are checked when the ranges are resolved
+                    len: size_of_range(part)
+                        .expect("multipart ranges are valid"),
+                });
+            }
+            chunks.push(Chunk::Bytes(mp.closing()));
+            Body::Multipart { file: file, chunks: chunks }
+        } else {
+            let offset = head.range.as_ref().map(|r| r.start).unwrap_or(0);
+            let len = head.content_length;
+            Body::File { file: file, offset: offset, len: len }
+        };
+        (head, body)
+    }
+    /// Convert the file to a stream that can be cancelled by the guard
+    pub fn into_stream(self) -> (BodyStream<F>, BodyGuard) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let stream = BodyStream {
+            file: self,
+            cancelled: cancelled.clone(),
+        };
+        (stream, BodyGuard { cancelled: cancelled })
+    }
+}
+
+impl<F> Output<F> {
+    /// Split into headers and the body to be sent by the caller
+    ///
+    /// This is useful to stream the file in own pipeline (e.g. for TLS
+    /// offload or rate limiting), so the crate is used only to build
+    /// headers. Responses having a `Head` but no body, like `304`, return
+    /// an empty `Body::Bytes`. Returns `None` for the responses without a
+    /// `Head` (`NotFound`, `Directory`, errors).
+    pub fn into_parts(self) -> Option<(Head, Body<F>)> {
+        match self {
+            Output::File(outf) | Output::FileRange(outf) => {
+                Some(outf.into_parts())
+            }
+            Output::FileHead(head)
+            | Output::InternalRedirect(head)
+            | Output::NotModified(head)
+            | Output::PreconditionFailed(head)
+            => Some((head, Body::Bytes(Vec::new()))),
+            _ => None,
+        }
+    }
+}
+
+impl<F> BodyStream<F> {
+    /// Returns true if the guard is dropped or `cancel` was called
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+    /// Returns the file wrapper, e.g. to get headers
+    pub fn get_ref(&self) -> &FileWrapper<F> {
+        &self.file
+    }
+    /// Returns the file wrapper discarding the cancellation token
+    pub fn into_inner(self) -> FileWrapper<F> {
+        self.file
+    }
+}
+
+impl<F: Read + Seek> BodyStream<F> {
+    /// Same as `FileWrapper::read_chunk` unless the stream is cancelled
+    ///
+    /// Returns an error of kind `ConnectionAborted` without touching the
+    /// file when the stream is cancelled.
+    ///
+    /// **Must be run in disk thread**
+    pub fn read_chunk<O>(&mut self, output: O) -> io::Result<usize>
+        where O: Write
+    {
+        if self.is_cancelled() {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted,
+                "response body is cancelled"));
+        }
+        self.file.read_chunk(output)
+    }
+}
+
+impl BodyGuard {
+    /// Cancel the stream without dropping the guard
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for BodyGuard {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::thread;
+
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use source::mock::MockSource;
+    use super::{Body, Chunk};
+
+    /// A writer that accepts only few bytes at a time
+    struct Slow(Vec<u8>);
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let bytes = if buf.len() > 4 { &buf[..4] } else { buf };
+            self.0.write(bytes)
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn drop_guard() {
+        let source = MockSource::new().file("/www/video.mp4", b"0123456789");
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET", Vec::new().into_iter());
+        let outf = match inp.probe_with(&source, "/www/video.mp4").unwrap() {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        let (mut stream, guard) = outf.into_stream();
+        let mut body = Slow(Vec::new());
+        assert_eq!(stream.read_chunk(&mut body).unwrap(), 4);
+        assert_eq!(source.reads(), 1);
+
+        drop(guard);
+        let err = thread::spawn(move || {
+            stream.read_chunk(&mut body).unwrap_err()
+        }).join().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(source.reads(), 1);
+    }
+
+    #[test]
+    fn into_parts() {
+        let source = MockSource::new().file("/www/file.txt", b"0123456789");
+        let cfg = Config::new().done();
+        let headers = vec![("Range", &b"bytes=2-5"[..])];
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        let output = inp.probe_with(&source, "/www/file.txt").unwrap();
+        let (head, body) = output.into_parts().unwrap();
+        assert_eq!(head.status(), 206);
+        match body {
+            Body::File { mut file, offset, len } => {
+                assert_eq!((offset, len), (2, 4));
+                let mut buf = vec![0; len as usize];
+                file.read_exact(&mut buf).unwrap();
+                assert_eq!(buf, b"2345");
+            }
+            x => panic!("unexpected body {:?}", x),
+        }
+
+        let headers = vec![("Range", &b"bytes=0-1,5-6"[..])];
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        let output = inp.probe_with(&source, "/www/file.txt").unwrap();
+        match output.into_parts().unwrap().1 {
+            Body::Multipart { chunks, .. } => {
+                assert_eq!(chunks.len(), 5);
+                assert_eq!(chunks[1], Chunk::File { offset: 0, len: 2 });
+                assert_eq!(chunks[3], Chunk::File { offset: 5, len: 2 });
+            }
+            x => panic!("unexpected body {:?}", x),
+        }
+        assert!(inp.probe_with(&source, "/www/none").unwrap()
+                .into_parts().is_none());
+    }
+}
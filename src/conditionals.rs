@@ -1,8 +1,12 @@
+use std::cmp::min;
+use std::ops::Deref;
+use std::slice;
 use std::str::from_utf8;
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use httpdate;
-use etag::Etag;
+use accept_encoding::Encoding;
+use etag::{Etag, is_entity_tag};
 
 
 pub struct ModifiedParser {
@@ -10,9 +14,117 @@ pub struct ModifiedParser {
 }
 
 pub struct NoneMatchParser {
-    etags: Vec<Etag>,
+    first: Option<Etag>,
+    rest: Vec<Etag>,
 }
 
+/// Entity tags of `If-Match` or `If-None-Match`
+///
+/// Most requests have at most one etag, so it's stored inline and the
+/// list allocates only for two or more etags. Boxed slice (rather than a
+/// `Vec`) keeps the list the same size as an `Etag`. The list is built once
+/// by the parser, so there is no way to push into it.
+#[derive(Debug, Clone)]
+pub enum EtagList {
+    Inline(Option<Etag>),
+    Heap(Box<[Etag]>),
+}
+
+
+/// Returns modification time that is safe to send in `Last-Modified`
+///
+/// Modification time in the future (i.e. clock skew on upload) is replaced
+/// by the current time, as RFC 7232 requires. Otherwise such file would
+/// be considered not modified by any client for a long time.
+pub fn clamp_modified(mtime: SystemTime, now: SystemTime) -> SystemTime {
+    min(mtime, now)
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+}
+
+/// Evaluates `If-Modified-Since`, returns true if 304 should be sent
+///
+/// Dates are compared with the precision of a second, as they are sent
+/// in `Last-Modified` header. The `If-Modified-Since` later than current
+/// time is ignored, as it can't be a date that we have sent.
+pub fn is_not_modified_since(if_modified: SystemTime,
+    last_modified: SystemTime, now: SystemTime)
+    -> bool
+{
+    if if_modified > now {
+        return false;
+    }
+    seconds(last_modified) <= seconds(if_modified)
+}
+
+/// Evaluates `If-Unmodified-Since`, returns true if 412 should be sent
+///
+/// Dates are compared with the precision of a second too.
+pub fn is_modified_since(if_unmodified: SystemTime,
+    last_modified: SystemTime)
+    -> bool
+{
+    seconds(last_modified) > seconds(if_unmodified)
+}
+
+/// Evaluates `If-Range`, returns true if the range should be served
+///
+/// Dates must be exactly equal (with the precision of a second) and etags
+/// are compared using the strong comparison. So etags derived from the
+/// metadata, which are weak, never match (and clients should send the
+/// `Last-Modified` date instead).
+pub fn if_range_matches(if_range: &Result<SystemTime, Etag>,
+    last_modified: Option<SystemTime>, etag: Option<&Etag>)
+    -> bool
+{
+    match *if_range {
+        Ok(date) => {
+            last_modified.map(|x| seconds(x) == seconds(date))
+                .unwrap_or(false)
+        }
+        Err(ref tag) => etag.map(|x| x.strong_eq(tag)).unwrap_or(false),
+    }
+}
+
+/// Parses `If-Range`, it's either an entity tag or a date
+///
+/// Returns `None` if the value is invalid.
+pub fn parse_if_range(header: &[u8]) -> Option<Result<SystemTime, Etag>> {
+    let value = from_utf8(trim(header)).ok()?;
+    if is_entity_tag(value.as_bytes()) {
+        Some(Err(Etag::Opaque(value.into())))
+    } else {
+        httpdate::parse_http_date(value).ok().map(Ok)
+    }
+}
+
+impl EtagList {
+    pub fn new() -> EtagList {
+        EtagList::Inline(None)
+    }
+    pub fn one(etag: Etag) -> EtagList {
+        EtagList::Inline(Some(etag))
+    }
+}
+
+impl Deref for EtagList {
+    type Target = [Etag];
+    fn deref(&self) -> &[Etag] {
+        match *self {
+            EtagList::Inline(None) => &[],
+            EtagList::Inline(Some(ref etag)) => slice::from_ref(etag),
+            EtagList::Heap(ref list) => list,
+        }
+    }
+}
+
+impl PartialEq for EtagList {
+    fn eq(&self, other: &EtagList) -> bool {
+        **self == **other
+    }
+}
 
 impl ModifiedParser {
     pub fn new() -> ModifiedParser {
@@ -44,33 +156,63 @@ impl ModifiedParser {
     }
 }
 
+fn trim(mut chunk: &[u8]) -> &[u8] {
+    while !chunk.is_empty() && chunk[0] == b' ' {
+        chunk = &chunk[1..];
+    }
+    while !chunk.is_empty() && chunk[chunk.len()-1] == b' ' {
+        chunk = &chunk[..chunk.len()-1];
+    }
+    chunk
+}
+
+/// Returns true if header value is `*`
+pub fn is_any(header: &[u8]) -> bool {
+    trim(header) == b"*"
+}
+
 impl NoneMatchParser {
     pub fn new() -> NoneMatchParser {
         NoneMatchParser {
-            etags: Vec::new(),
+            first: None,
+            rest: Vec::new(),
         }
     }
-    fn add_chunk(&mut self, mut chunk: &[u8]) {
-        while chunk.len() > 0 && chunk[0] == b' ' {
-            chunk = &chunk[1..];
+    fn push(&mut self, etag: Etag) {
+        if self.first.is_none() {
+            self.first = Some(etag);
+        } else {
+            self.rest.push(etag);
         }
-        if chunk.len() < 4 + 16 {  // the 'W/"xx"' and 16 bytes of base64
-            // Is not our etag
+    }
+    fn add_chunk(&mut self, chunk: &[u8]) {
+        let chunk = trim(chunk);
+        if !is_entity_tag(chunk) {
+            // skip invalid tags
             return;
         }
-        if chunk[0] != b'W' || chunk[1] != b'/' || chunk[2] != b'"' ||
-            chunk[16+3] != b'"'
-        {
-            // Is not a weak tag (or wrong length)
-            return;
+        // the 'W/"xx"' and 16 bytes of base64
+        if chunk.len() == 4 + 16 && chunk[0] == b'W' {
+            if let Ok(etag) = Etag::decode_base64(&chunk[3..16+3]) {
+                self.push(etag);
+                return;
+            }
         }
-        if !chunk[16+4..].iter().all(|&x| x == b' ') {
-            // invalid trailing bytes
-            return;
+        // same with encoding name, like 'W/"xx-gzip"'
+        if chunk.len() > 5 + 16 && chunk[0] == b'W' && chunk[16+3] == b'-' {
+            let name = &chunk[16+4..chunk.len()-1];
+            let enc = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip]
+                .iter().cloned().find(|e| e.name().as_bytes() == name);
+            if let Some(enc) = enc {
+                if let Ok(etag) = Etag::decode_base64(&chunk[3..16+3]) {
+                    self.push(etag.encoded(enc));
+                    return;
+                }
+            }
         }
-        match Etag::decode_base64(&chunk[3..16+3]) {
-            Ok(etag) => self.etags.push(etag),
-            Err(()) => return, // skip invalid tags
+        // is not our etag, but might match precomputed one
+        if let Ok(value) = from_utf8(chunk) {
+            self.push(Etag::Opaque(value.into()));
         }
     }
     pub fn add_header(&mut self, header: &[u8]) {
@@ -78,8 +220,17 @@ impl NoneMatchParser {
             self.add_chunk(chunk);
         }
     }
-    pub fn done(self) -> Vec<Etag> {
-        self.etags
+    pub fn done(self) -> EtagList {
+        match self.first {
+            None => EtagList::new(),
+            Some(first) if self.rest.is_empty() => EtagList::one(first),
+            Some(first) => {
+                let mut list = Vec::with_capacity(self.rest.len() + 1);
+                list.push(first);
+                list.extend(self.rest);
+                EtagList::Heap(list.into_boxed_slice())
+            }
+        }
     }
 }
 
@@ -89,10 +240,13 @@ mod test {
     use etag::Etag;
     use super::*;
 
+    const TAG: [u8; 12] = [181, 130, 83, 244, 162, 84, 35, 66,
+                           151, 216, 142, 106];
+
     fn parse_etag(val: &str) -> Vec<Etag> {
         let mut parser = NoneMatchParser::new();
         parser.add_header(val.as_bytes());
-        parser.done()
+        parser.done().to_vec()
     }
 
     fn parse_mod(val: &str) -> Option<SystemTime> {
@@ -104,10 +258,10 @@ mod test {
     #[test]
     fn single_etag() {
         assert_eq!(parse_etag(r#"W/"tYJT9KJUI0KX2I5q""#), vec![
-            Etag([181, 130, 83, 244, 162, 84, 35, 66, 151, 216, 142, 106])
+            Etag::Hash(TAG)
         ]);
         assert_eq!(parse_etag(r#"    W/"tYJT9KJUI0KX2I5q"  "#), vec![
-            Etag([181, 130, 83, 244, 162, 84, 35, 66, 151, 216, 142, 106])
+            Etag::Hash(TAG)
         ]);
     }
 
@@ -115,8 +269,17 @@ mod test {
     fn two_tags() {
         assert_eq!(parse_etag(r#"W/"tYJT9KJUI0KX2I5q", W/"tYJT9KJUI0KX2I5q""#),
         vec![
-            Etag([181, 130, 83, 244, 162, 84, 35, 66, 151, 216, 142, 106]),
-            Etag([181, 130, 83, 244, 162, 84, 35, 66, 151, 216, 142, 106]),
+            Etag::Hash(TAG),
+            Etag::Hash(TAG),
+        ]);
+    }
+
+    #[test]
+    fn three_tags() {
+        assert_eq!(parse_etag(r#""a", "b", "c""#), vec![
+            Etag::opaque("a"),
+            Etag::opaque("b"),
+            Etag::opaque("c"),
         ]);
     }
 
@@ -126,12 +289,60 @@ mod test {
             Some(UNIX_EPOCH + Duration::new(1503434833, 0)));
     }
 
+    #[test]
+    fn modified_since() {
+        let now = UNIX_EPOCH + Duration::new(1503434833, 0);
+        let day = Duration::new(86400, 0);
+        assert!(is_not_modified_since(now - day, now - day*2, now));
+        assert!(!is_not_modified_since(now - day*2, now - day, now));
+        // sub-second precision is not sent in `Last-Modified`
+        assert!(is_not_modified_since(now - day,
+            now - day + Duration::new(0, 500_000_000), now));
+        // if-modified-since in the future is ignored
+        assert!(!is_not_modified_since(now + day, now - day, now));
+    }
+
+    #[test]
+    fn future_modified() {
+        let now = UNIX_EPOCH + Duration::new(1503434833, 0);
+        let day = Duration::new(86400, 0);
+        let mtime = clamp_modified(now + day, now);
+        assert_eq!(mtime, now);
+        assert!(!is_not_modified_since(now - day, mtime, now));
+    }
+
     #[test]
     fn bad_etags() {
-        assert_eq!(parse_etag(r#"W/"tYJT9KJ^^UI0KX2I5q""#), vec![]);
-        assert_eq!(parse_etag(r#""tYJT9KJUI0KX2I5q""#), vec![]);
         assert_eq!(parse_etag(r#""tYJT9KJUI  0KX2I5q""#), vec![]);
         assert_eq!(parse_etag(r#""tYJT9KJUI0KX2I5q"+1"#), vec![]);
         assert_eq!(parse_etag(r#"X/"tYJT9KJUI0KX2I5q""#), vec![]);
+        assert_eq!(parse_etag(r#"tYJT9KJUI0KX2I5q"#), vec![]);
+    }
+
+    #[test]
+    fn foreign_etags() {
+        // not generated by us, but kept to compare with precomputed ones
+        assert_eq!(parse_etag(r#"W/"tYJT9KJ^^UI0KX2I5q""#), vec![
+            Etag::Opaque(r#"W/"tYJT9KJ^^UI0KX2I5q""#.into()),
+        ]);
+        assert_eq!(parse_etag(r#""tYJT9KJUI0KX2I5q", W/"tYJT9KJUI0KX2I5q""#),
+        vec![
+            Etag::Opaque(r#""tYJT9KJUI0KX2I5q""#.into()),
+            Etag::Hash(TAG),
+        ]);
+    }
+
+    #[test]
+    fn encoded_etags() {
+        assert_eq!(parse_etag(r#"W/"tYJT9KJUI0KX2I5q-gzip""#), vec![
+            Etag::Encoded(TAG, Encoding::Gzip),
+        ]);
+        assert_eq!(parse_etag(r#"W/"tYJT9KJUI0KX2I5q-br", W/"x-br""#), vec![
+            Etag::Encoded(TAG, Encoding::Brotli),
+            Etag::Opaque(r#"W/"x-br""#.into()),
+        ]);
+        assert_eq!(parse_etag(r#"W/"tYJT9KJUI0KX2I5q-identity""#), vec![
+            Etag::Opaque(r#"W/"tYJT9KJUI0KX2I5q-identity""#.into()),
+        ]);
     }
 }
@@ -1,5 +1,10 @@
+use std::fmt;
+use std::panic::RefUnwindSafe;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
+use accept_encoding::Encoding;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum EncodingSupport {
     Never,
@@ -8,15 +13,232 @@ pub(crate) enum EncodingSupport {
 }
 
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MultirangeMode {
+    Identity,
+    FullEncoded,
+}
+
+/// Which files are refused to be served based on their permissions
+///
+/// Refused files are treated as not found (other encodings can be
+/// served instead), and a warning is logged. Permissions are only checked
+/// on unix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PermissionPolicy {
+    /// Serve any file that can be read (default)
+    Permissive,
+    /// Refuse files that are writable by anyone (i.e. mode `o+w`)
+    RefuseWorldWritable,
+}
+
+impl PermissionPolicy {
+    pub(crate) fn allows(&self, mode: Option<u32>) -> bool {
+        match (*self, mode) {
+            (_, None) => true,
+            (PermissionPolicy::RefuseWorldWritable, Some(mode)) => {
+                mode & 0o002 == 0
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Whether files and directories starting with a dot are served
+///
+/// This is checked only for paths relative to the root, i.e. in
+/// `Input::probe_file_under`. Refused paths are served as not found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DotfilePolicy {
+    /// Serve dotfiles as any other files (default)
+    Allow,
+    /// Refuse any path that has a component starting with a dot
+    Deny,
+    /// Refuse dotfiles except ones inside these paths, e.g. `.well-known`
+    ///
+    /// Prefixes are relative to the root and are matched by whole path
+    /// components, so the whole directory tree under the prefix is served.
+    AllowList(Vec<PathBuf>),
+}
+
+impl DotfilePolicy {
+    /// Refuse dotfiles except ones inside prefixes
+    pub fn allow_list<I, P>(prefixes: I) -> DotfilePolicy
+        where I: IntoIterator<Item=P>, P: AsRef<Path>,
+    {
+        DotfilePolicy::AllowList(prefixes.into_iter()
+            .map(|p| normalize(p.as_ref()))
+            .collect())
+    }
+    pub(crate) fn allows(&self, rel: &Path) -> bool {
+        let has_dot = rel.components().any(|c| match c {
+            Component::Normal(name) => {
+                name.to_str().map(|x| x.starts_with('.')).unwrap_or(true)
+            }
+            _ => false,
+        });
+        if !has_dot {
+            return true;
+        }
+        match *self {
+            DotfilePolicy::Allow => true,
+            DotfilePolicy::AllowList(ref prefixes) => {
+                let rel = normalize(rel);
+                prefixes.iter().any(|p| rel.starts_with(p))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Function deciding on the symlink, see `SymlinkPolicy::custom`
+type SymlinkFn = Fn(&Path, Encoding) -> bool + Send + Sync + RefUnwindSafe;
+
+/// Whether files that are symlinks are served
+///
+/// Only the file itself is checked, i.e. each probed candidate like
+/// `app.js` or `app.js.gz`, not the directories in the path. Refused files
+/// are treated as not found (other encodings can be served instead), and a
+/// warning is logged.
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum SymlinkPolicy {
+    /// Serve symlinks as any other files (default)
+    Follow,
+    /// Refuse any file that is a symlink
+    Deny,
+    /// Serve the symlink if the function returns true, see `custom`
+    Custom(Arc<SymlinkFn>),
+}
+
+impl SymlinkPolicy {
+    /// Decide by the path of the candidate and the encoding it represents
+    ///
+    /// The function is called only for candidates that are symlinks. E.g.
+    /// to serve symlinked compressed variants but not the identity file:
+    ///
+    /// ```rust
+    /// # use http_file_headers::{Encoding, SymlinkPolicy};
+    /// let policy = SymlinkPolicy::custom(|_path, encoding| {
+    ///     encoding != Encoding::Identity
+    /// });
+    /// ```
+    pub fn custom<F>(f: F) -> SymlinkPolicy
+        where F: Fn(&Path, Encoding) -> bool + Send + Sync + RefUnwindSafe
+                 + 'static,
+    {
+        SymlinkPolicy::Custom(Arc::new(f))
+    }
+    /// Returns true if symlinks need to be checked at all
+    pub(crate) fn checks(&self) -> bool {
+        !matches!(*self, SymlinkPolicy::Follow)
+    }
+    /// Whether symlink at `path` is served
+    pub(crate) fn allows(&self, path: &Path, encoding: Encoding) -> bool {
+        match *self {
+            SymlinkPolicy::Deny => false,
+            SymlinkPolicy::Custom(ref f) => f(path, encoding),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Debug for SymlinkPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SymlinkPolicy::Follow => f.write_str("Follow"),
+            SymlinkPolicy::Deny => f.write_str("Deny"),
+            SymlinkPolicy::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Strips root and `.` components
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| *c != Component::RootDir && *c != Component::CurDir)
+        .collect()
+}
+
+/// How etags are derived from file metadata
+///
+/// Both kinds of etags are weak. Etags from the manifest are used as is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EtagStrategy {
+    /// Hash of size, modification and creation times with nanoseconds,
+    /// and also of device, inode and ctime on unix (default)
+    Metadata,
+    /// Hash of size and modification time in whole seconds only
+    ///
+    /// This is for network filesystems, where inode numbers and precision
+    /// of timestamps differ between servers (or between mounts), so the
+    /// default etag changes even if the file is not.
+    MtimeSizeWeak,
+}
+
+/// Body of the response on unsatisfiable range (416)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RangeErrorBody {
+    /// No body (default)
+    Empty,
+    /// A line of `text/plain` with the length of the file
+    Text,
+    /// An `application/json` object like `{"length": 1234}`
+    Json,
+}
+
 /// A configuration with the builder interface
+///
+/// This holds all the options of serving files. It's built once (usually
+/// at startup) and shared by all requests: `done()` returns an `Arc`,
+/// that is passed to `Input::from_headers`. Per-request tweaks are made
+/// using methods of `Input` instead (e.g. `Input::override_content_type`).
+///
+/// ```rust
+/// # use http_file_headers::{Config, PermissionPolicy};
+/// let config = Config::new()
+///     .add_index_file("index.html")
+///     .encodings_on_all_files()
+///     .permission_policy(PermissionPolicy::RefuseWorldWritable)
+///     .date(true)
+///     .done();
+/// ```
 #[derive(Clone, Debug)]
 pub struct Config {
     pub(crate) text_charset: Option<String>,
     pub(crate) index_files: Vec<String>,
     pub(crate) encoding_support: EncodingSupport,
+    pub(crate) assume_gzip: bool,
+    pub(crate) probe_order: Vec<Encoding>,
+    pub(crate) max_accept_encoding: usize,
     pub(crate) content_type: bool,
     pub(crate) etag: bool,
+    pub(crate) etag_strategy: EtagStrategy,
+    pub(crate) strong_etags: bool,
     pub(crate) last_modified: bool,
+    pub(crate) date: bool,
+    pub(crate) cache_control: Option<String>,
+    pub(crate) server_header: Option<String>,
+    pub(crate) content_digest: bool,
+    pub(crate) redirect_header: String,
+    pub(crate) multipart_boundary: Option<String>,
+    pub(crate) unknown_as_text: bool,
+    pub(crate) sniff: bool,
+    pub(crate) lenient_method: bool,
+    pub(crate) permission_policy: PermissionPolicy,
+    pub(crate) ranges: bool,
+    pub(crate) max_range: usize,
+    pub(crate) multirange: MultirangeMode,
+    pub(crate) formats: Vec<(String, String)>,
+    pub(crate) compound_extensions: Vec<(String, String, Encoding)>,
+    pub(crate) range_error_body: RangeErrorBody,
+    pub(crate) dotfiles: DotfilePolicy,
+    pub(crate) symlinks: SymlinkPolicy,
+    pub(crate) preloads: Vec<(PathBuf, Vec<String>)>,
 }
 
 impl Config {
@@ -26,17 +248,62 @@ impl Config {
     ///
     /// * `text_charset("utf-8")`
     /// * no index files
-    /// * `encodings_on_text_files()`
+    /// * `encodings_on_text_files()`, only identity encoding if client
+    ///   sends no `Accept-Encoding`, files are probed in order of
+    ///   client's preference
     /// * etags, last-modified and conditions based on them are enabled
-    /// * content-type is enabled
+    /// * `EtagStrategy::Metadata`, etags derived from metadata are weak
+    /// * content-type is enabled, unknown types are
+    ///   `application/octet-stream`, content is not sniffed
+    /// * date, server, cache-control and content digests are disabled
+    /// * `X-Accel-Redirect` for `Output::internal_redirect`
+    /// * method names are case-sensitive
+    /// * `PermissionPolicy::Permissive`
+    /// * ranges are enabled, `multirange_identity()`
+    /// * `Accept-Encoding` and `TE` are limited to 1024 bytes, `Range`
+    ///   to 8192 bytes
+    /// * no alternate formats
+    /// * `.svgz` is served as gzipped `image/svg+xml`
+    /// * random multipart boundary
+    /// * no body on unsatisfiable range
+    /// * `DotfilePolicy::Allow`
+    /// * `SymlinkPolicy::Follow`
+    /// * no preload links
     pub fn new() -> Config {
         Config {
             text_charset: Some(String::from("utf-8")),
             index_files: Vec::new(),
             encoding_support: EncodingSupport::TextFiles,
+            assume_gzip: false,
+            probe_order: Vec::new(),
+            max_accept_encoding: 1024,
             content_type: true,
             etag: true,
+            etag_strategy: EtagStrategy::Metadata,
+            strong_etags: false,
             last_modified: true,
+            date: false,
+            cache_control: None,
+            server_header: None,
+            content_digest: false,
+            redirect_header: String::from("X-Accel-Redirect"),
+            multipart_boundary: None,
+            unknown_as_text: false,
+            sniff: false,
+            lenient_method: false,
+            permission_policy: PermissionPolicy::Permissive,
+            ranges: true,
+            max_range: 8192,
+            multirange: MultirangeMode::Identity,
+            formats: Vec::new(),
+            compound_extensions: vec![
+                (String::from("svgz"), String::from("image/svg+xml"),
+                 Encoding::Gzip),
+            ],
+            range_error_body: RangeErrorBody::Empty,
+            dotfiles: DotfilePolicy::Allow,
+            symlinks: SymlinkPolicy::Follow,
+            preloads: Vec::new(),
         }
     }
 
@@ -59,19 +326,91 @@ impl Config {
     ///
     /// Multiple names can be added. They are probed in the order in which
     /// they are defined here. Also, these filenames with encoding extensions
-    /// are tried too.
+    /// are tried too (so `index.html.gz` is served if there is
+    /// `index.html`, as for files requested by path, see
+    /// `Input::probe_with`).
+    ///
+    /// Note: only first four names are probed, to limit number of files
+    /// opened for each request.
     pub fn add_index_file(&mut self, name: &str) -> &mut Self {
         self.index_files.push(String::from(name));
         self
     }
 
-    /// Do not search for `.br` and `.gz` files
+    /// Add alternate format of files negotiated by `Accept` header
+    ///
+    /// When client explicitly accepts `media_type` (e.g. `image/webp`),
+    /// the file with the same name but the `extension` (e.g. `webp`) is
+    /// served instead of requested one, if it exists. Formats are probed
+    /// in the order of client's preference, and in the order they are
+    /// added here if preference is the same. Only first four formats
+    /// accepted by the client are probed.
+    ///
+    /// Every alternate file is probed with encodings as usual.
+    pub fn add_format(&mut self, media_type: &str, extension: &str)
+        -> &mut Self
+    {
+        self.formats.push((media_type.into(),
+                           extension.trim_start_matches('.').into()));
+        self
+    }
+
+    /// Serve files with the `extension` as `content_type` with `encoding`
+    ///
+    /// This is for compressed files that have own extension rather than
+    /// the suffix, like `logo.svgz` (the only one added by default). Such
+    /// files are served with `Content-Encoding` even if client doesn't
+    /// accept the encoding (there is nothing else to serve), and no
+    /// (other) encodings are probed for them. The extension is matched
+    /// case-insensitively, adding an existing one replaces it.
+    pub fn add_compound_extension(&mut self, extension: &str,
+        content_type: &str, encoding: Encoding)
+        -> &mut Self
+    {
+        let extension = extension.trim_start_matches('.');
+        self.compound_extensions
+            .retain(|(ext, _, _)| !ext.eq_ignore_ascii_case(extension));
+        self.compound_extensions.push(
+            (extension.into(), content_type.into(), encoding));
+        self
+    }
+
+    /// Serve all files by their extension, including `.svgz`
+    pub fn no_compound_extensions(&mut self) -> &mut Self {
+        self.compound_extensions.clear();
+        self
+    }
+
+    /// Add `Link: <target>; rel=preload; as=<kind>` header for the `path`
+    ///
+    /// The `path` is the path of the file on the filesystem (an index file
+    /// for directories), the link is sent when either the file itself or
+    /// any of its compressed variants is served. Multiple links can be
+    /// added for the same path, each one is sent as a separate header in
+    /// the order they are added here. Links are not sent with 304 and 412.
+    /// Use `Output::early_hints` to send them in `103 Early Hints` too.
+    pub fn add_preload(&mut self, path: &str, target: &str, kind: &str)
+        -> &mut Self
+    {
+        let link = format!("<{}>; rel=preload; as={}", target, kind);
+        let path = Path::new(path);
+        if let Some(&mut (_, ref mut links)) = self.preloads.iter_mut()
+            .find(|&&mut (ref p, _)| p == path)
+        {
+            links.push(link);
+            return self;
+        }
+        self.preloads.push((path.to_path_buf(), vec![link]));
+        self
+    }
+
+    /// Do not search for `.br`, `.zst` and `.gz` files
     pub fn no_encodings(&mut self) -> &mut Self {
         self.encoding_support = EncodingSupport::Never;
         self
     }
 
-    /// Search for `.br` and `.gz` files for text files
+    /// Search for `.br`, `.zst` and `.gz` files for text files
     ///
     /// Text files re those having `text/*` mime type
     /// or `application/javascript`
@@ -80,11 +419,65 @@ impl Config {
         self
     }
 
-    /// Search for `.br` and `.gz` files for all files regardless of mime type
+    /// Search for `.br`, `.zst` and `.gz` files for all files regardless
+    /// of mime type
     pub fn encodings_on_all_files(&mut self) -> &mut Self {
         self.encoding_support = EncodingSupport::AllFiles;
         self
     }
+
+    /// Serve `.gz` files to clients that send no `Accept-Encoding`
+    ///
+    /// By the spec, absent header means that only identity encoding is
+    /// acceptable. But when all clients are known to support gzip (e.g.
+    /// in an intranet), this saves bandwidth for clients that don't
+    /// bother to send the header. Which files are compressed is still
+    /// decided by `encodings_on_text_files` or `encodings_on_all_files`.
+    pub fn assume_gzip_when_unspecified(&mut self) -> &mut Self {
+        self.assume_gzip = true;
+        self
+    }
+
+    /// Probe encoded files in this order instead of client's preference
+    ///
+    /// This is useful when some kinds of compressed files are known to
+    /// exist for all files (e.g. `.br`), and others only for few, so that
+    /// the files that exist are tried first. Only encodings acceptable by
+    /// the client are probed, and the ones not listed are probed after
+    /// listed ones in order of preference. Identity file is still probed
+    /// last (unless client prefers it explicitly).
+    pub fn probe_order(&mut self, order: &[Encoding]) -> &mut Self {
+        self.probe_order = order.to_vec();
+        self
+    }
+    /// Ignore `Accept-Encoding` (and `TE`) longer than `bytes`
+    ///
+    /// Lengths of all the headers of the request are summed. When they are
+    /// longer, only identity encoding is served. This limits the work done
+    /// on malicious requests. Default is 1024 bytes.
+    pub fn max_accept_encoding_length(&mut self, bytes: usize) -> &mut Self {
+        self.max_accept_encoding = bytes;
+        self
+    }
+    /// Serve identity file for requests with multiple ranges (default)
+    ///
+    /// Multiple ranges of compressed file are never served, as almost no
+    /// client is able to combine them. With this setting compressed files
+    /// are skipped for such requests, so ranges are served from the
+    /// original file.
+    pub fn multirange_identity(&mut self) -> &mut Self {
+        self.multirange = MultirangeMode::Identity;
+        self
+    }
+
+    /// Serve whole compressed file for requests with multiple ranges
+    ///
+    /// Ranges are ignored and `Accept-Ranges: none` is sent instead.
+    pub fn multirange_full_encoded(&mut self) -> &mut Self {
+        self.multirange = MultirangeMode::FullEncoded;
+        self
+    }
+
     /// Togggles generation of Content-Type header (so user can override)
     ///
     /// By default it's enabled
@@ -100,6 +493,51 @@ impl Config {
         self
     }
 
+    /// Toggles support of `Range` requests
+    ///
+    /// When disabled, `Range` and `If-Range` headers are ignored, so the
+    /// whole file is always served, and `Accept-Ranges: none` is sent.
+    /// See also `Input::disable_ranges` to do the same for single request.
+    ///
+    /// By default it's enabled
+    pub fn ranges(&mut self, value: bool) -> &mut Self {
+        self.ranges = value;
+        self
+    }
+
+    /// Refuse `Range` longer than `bytes` with `Output::InvalidRange`
+    ///
+    /// This limits memory used to parse malicious requests. Default is
+    /// 8192 bytes.
+    pub fn max_range_length(&mut self, bytes: usize) -> &mut Self {
+        self.max_range = bytes;
+        self
+    }
+
+    /// Set which metadata is used for etags
+    ///
+    /// By default it's `EtagStrategy::Metadata`
+    pub fn etag_strategy(&mut self, strategy: EtagStrategy) -> &mut Self {
+        self.etag_strategy = strategy;
+        self
+    }
+
+    /// Send only strong etags and compare `If-None-Match` strongly
+    ///
+    /// This is for CDNs which don't cache responses with weak etags.
+    /// Etags derived from metadata (and weak ones from the manifest) are
+    /// sent without the `W/` prefix. And the weak etags sent by clients
+    /// never match, so `If-None-Match: W/"x"` is not 304 for `ETag: "x"`.
+    ///
+    /// **This deviates from RFC 7232** which requires weak comparison for
+    /// `If-None-Match`, and the metadata based etag doesn't guarantee that
+    /// the files are byte-for-byte equal (though it's very unlikely that
+    /// they differ). By default it's disabled.
+    pub fn strong_etags(&mut self, value: bool) -> &mut Self {
+        self.strong_etags = value;
+        self
+    }
+
     /// Toggles generation of Last-Modified (and so `If-Modified-Since` too)
     ///
     /// Note: Last-Modified date is never sent if date is earlier than
@@ -109,8 +547,211 @@ impl Config {
         self
     }
 
+    /// Toggles generation of `Date` header
+    ///
+    /// It's required for all responses including 304 and 412, but is
+    /// usually added by the HTTP library, so by default it's disabled.
+    pub fn date(&mut self, value: bool) -> &mut Self {
+        self.date = value;
+        self
+    }
+
+    /// Send `Cache-Control` header with the specified value
+    ///
+    /// It's sent on 304 responses too, so caches can extend freshness of
+    /// the stored response. But not on error pages (`Output::error`) and
+    /// 412 responses. By default no `Cache-Control` is sent.
+    pub fn cache_control(&mut self, value: &str) -> &mut Self {
+        self.cache_control = Some(value.into());
+        self
+    }
+
+    /// Send `Server` header with the specified value, or none at all
+    ///
+    /// It's sent in every response that has a `Head`, including 304, 412
+    /// and error pages. By default no `Server` header is sent, so the
+    /// server isn't fingerprinted (or the outer layer adds its own).
+    pub fn server_header(&mut self, value: Option<&str>) -> &mut Self {
+        self.server_header = value.map(|x| x.into());
+        self
+    }
+
+    /// Toggles `Repr-Digest` and `Content-Digest` headers (RFC 9530)
+    ///
+    /// Both are SHA-256. `Content-Digest` covers only the bytes sent for
+    /// range requests, and isn't sent for `HEAD` and multiple ranges.
+    /// Note: the whole file is read to compute digests when the response
+    /// is built (in disk thread), so this is for small files or for the
+    /// responses cached by a proxy. Error pages don't have digests.
+    pub fn content_digest(&mut self, value: bool) -> &mut Self {
+        self.content_digest = value;
+        self
+    }
+
+    /// Header used by `Output::internal_redirect`
+    ///
+    /// Default is `X-Accel-Redirect` which is understood by nginx, use
+    /// `X-Sendfile` for Apache or lighttpd.
+    pub fn internal_redirect_header(&mut self, name: &str) -> &mut Self {
+        self.redirect_header = name.into();
+        self
+    }
+
+    /// Use fixed boundary for `multipart/byteranges` responses
+    ///
+    /// By default random boundary is generated for each response. Fixed
+    /// one is useful for tests and for reproducible responses. Boundary
+    /// must only contain characters allowed by RFC 2046 (letters, digits
+    /// and few punctuation characters).
+    ///
+    /// Note: if boundary occurs in the bytes being served, random boundary
+    /// is used for that response regardless of this setting. To find that
+    /// out, all the requested ranges are read before the response is
+    /// returned (for `HEAD` too), using a 64 KiB buffer. Random boundary
+    /// is never checked, so this setting makes multipart responses
+    /// slower.
+    pub fn multipart_boundary(&mut self, boundary: &str) -> &mut Self {
+        self.multipart_boundary = Some(boundary.into());
+        self
+    }
+
+    /// Serve files with unknown (or no) extension as `text/plain`
+    ///
+    /// By default such files are `application/octet-stream`, which means
+    /// that text assets with unusual extensions are downloaded instead of
+    /// shown, and are blocked by browsers if `X-Content-Type-Options:
+    /// nosniff` is sent. With this option `text_charset` is appended and
+    /// encodings are used (as for any other text file).
+    pub fn unknown_as_text(&mut self) -> &mut Self {
+        self.unknown_as_text = true;
+        self
+    }
+
+    /// Detect content type by the first bytes of files of unknown type
+    ///
+    /// When extension of the file is unknown or missing, a few bytes are
+    /// read from the already opened file and checked for signatures of
+    /// PNG, JPEG, GIF, PDF, gzip and WebAssembly. The file is rewound
+    /// afterwards, so the whole file is served. If nothing is detected,
+    /// the type is chosen as without sniffing (see `unknown_as_text`).
+    /// Compressed variants, files listed in the manifest and files with a
+    /// type set by `Input::override_content_type` are never sniffed.
+    ///
+    /// Only binary formats are detected, never text or HTML, so this can't
+    /// make browsers execute a file which they'd refuse to execute with
+    /// `X-Content-Type-Options: nosniff`.
+    pub fn sniff_content_type(&mut self, value: bool) -> &mut Self {
+        self.sniff = value;
+        self
+    }
+
+    /// Accept `get` and `head` methods in any letter case
+    ///
+    /// Methods are case-sensitive, so by default `Get` is an invalid
+    /// method. Enable this to work around buggy proxies.
+    pub fn lenient_method_case(&mut self) -> &mut Self {
+        self.lenient_method = true;
+        self
+    }
+
+    /// Refuse to serve files with unsafe permissions
+    ///
+    /// By default it's `PermissionPolicy::Permissive`
+    pub fn permission_policy(&mut self, policy: PermissionPolicy)
+        -> &mut Self
+    {
+        self.permission_policy = policy;
+        self
+    }
+
+    /// Set body of the response on unsatisfiable range
+    ///
+    /// See `Output::RangeNotSatisfiable`
+    pub fn range_error_body(&mut self, body: RangeErrorBody) -> &mut Self {
+        self.range_error_body = body;
+        self
+    }
+
+    /// Set which files starting with a dot are served
+    pub fn dotfile_policy(&mut self, policy: DotfilePolicy) -> &mut Self {
+        self.dotfiles = policy;
+        self
+    }
+
+    /// Set whether files that are symlinks are served
+    ///
+    /// Symlinks are checked using `FileSource::is_symlink`, that is an
+    /// additional `lstat` for each candidate unless the policy is
+    /// `SymlinkPolicy::Follow` (default).
+    pub fn symlink_policy(&mut self, policy: SymlinkPolicy) -> &mut Self {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Returns content type and encoding if `path` has compound extension
+    pub(crate) fn compound_extension(&self, path: &Path)
+        -> Option<(&str, Encoding)>
+    {
+        let ext = path.extension().and_then(|x| x.to_str())?;
+        self.compound_extensions.iter()
+            .find(|(e, _, _)| e.eq_ignore_ascii_case(ext))
+            .map(|(_, ctype, enc)| (&ctype[..], *enc))
+    }
+
     /// Finalize configuration and wrap into an Arc
     pub fn done(&self) -> Arc<Config> {
         Arc::new(self.clone())
     }
 }
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::empty;
+
+    use input::Input;
+    use output::Output;
+    use source::mock::MockSource;
+    use super::{Config, PermissionPolicy};
+
+    #[test]
+    fn serve_with_all_options() {
+        let cfg = Config::new()
+            .text_charset("koi8-r")
+            .add_index_file("index.txt")
+            .encodings_on_all_files()
+            .multirange_full_encoded()
+            .content_type(true)
+            .etag(false)
+            .last_modified(false)
+            .date(true)
+            .multipart_boundary("BOUNDARY")
+            .unknown_as_text()
+            .lenient_method_case()
+            .permission_policy(PermissionPolicy::RefuseWorldWritable)
+            .add_format("image/webp", "webp")
+            .done();
+        let source = MockSource::new()
+            .dir("/www")
+            .file("/www/index.txt", b"hello");
+        let inp = Input::from_headers(&cfg, "get", empty());
+        let outf = match inp.probe_with(&source, "/www").unwrap() {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        let headers = outf.headers()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect::<Vec<_>>();
+        assert_eq!(headers[0].split(':').next(), Some("Date"));
+        assert_eq!(&headers[1..], &[
+            "Accept-Ranges: bytes",
+            "Content-Type: text/plain; charset=koi8-r",
+        ][..]);
+        assert!(!Config::default().lenient_method);
+    }
+}
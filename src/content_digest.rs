@@ -0,0 +1,153 @@
+use std::cmp::min;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use sha2::{Sha256, Digest};
+
+use output::Head;
+
+
+/// Adds `Repr-Digest` and `Content-Digest` (RFC 9530) to the `head`
+///
+/// The whole file is read to compute the digest of the representation,
+/// and the digest of the range being sent is computed in the same pass.
+/// `Content-Digest` is not added if there is no body (i.e. `HEAD`) and for
+/// `multipart/byteranges`, as the body contains delimiters. The file is
+/// seeked to the start afterwards.
+///
+/// **Must be run in disk thread**
+pub fn add_digests<F: Read + Seek>(head: &mut Head, file: &mut F,
+    len: u64, body: bool)
+    -> io::Result<()>
+{
+    let range = head.range.as_ref().map(|r| (r.start, r.end + 1));
+    let mut repr = Sha256::default();
+    let mut content = Sha256::default();
+    let mut buf = [0u8; 65536];
+    let mut pos = 0;
+    file.seek(SeekFrom::Start(0))?;
+    while pos < len {
+        let max = min(buf.len() as u64, len - pos) as usize;
+        let bytes = file.read(&mut buf[..max])?;
+        if bytes == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                "file is shorter than its metadata"));
+        }
+        let chunk = &buf[..bytes];
+        repr.input(chunk);
+        if let Some((start, end)) = range {
+            let end_pos = pos + bytes as u64;
+            if start < end_pos && end > pos {
+                let lo = start.saturating_sub(pos) as usize;
+                let hi = (min(end, end_pos) - pos) as usize;
+                content.input(&chunk[lo..hi]);
+            }
+        }
+        pos += bytes as u64;
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let repr = format_sha256(&repr.result());
+    if body && head.multipart.is_none() {
+        let content = if range.is_some() {
+            format_sha256(&content.result())
+        } else {
+            repr.clone()
+        };
+        head.append_header("Content-Digest", &content);
+    }
+    head.append_header("Repr-Digest", &repr);
+    Ok(())
+}
+
+/// Formats digest as a dictionary member of the structured header
+fn format_sha256(digest: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                           abcdefghijklmnopqrstuvwxyz\
+                           0123456789+/";
+    // 44 chars of base64 for 32 bytes of SHA-256
+    let mut result = String::with_capacity(54);
+    result.push_str("sha-256=:");
+    for chunk in digest.chunks(3) {
+        let n = chunk.iter().enumerate()
+            .fold(0usize, |n, (i, &b)| n | (b as usize) << (16 - i*8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(CHARS[(n >> (18 - i*6)) & 63] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result.push(':');
+    return result;
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use body::Body;
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use source::mock::MockSource;
+
+    fn digests(method: &str, range: Option<&str>)
+        -> (Vec<(String, String)>, Vec<u8>)
+    {
+        let source = MockSource::new().file("/www/file.txt", b"0123456789");
+        let cfg = Config::new().content_digest(true).done();
+        let headers = range.map(|r| ("Range", r.as_bytes()));
+        let inp = Input::from_headers(&cfg, method, headers.into_iter());
+        let (head, body) = inp.probe_with(&source, "/www/file.txt").unwrap()
+            .into_parts().unwrap();
+        let mut data = Vec::new();
+        if let Body::File { mut file, offset, len } = body {
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.take(len).read_to_end(&mut data).unwrap();
+        }
+        let headers = head.headers()
+            .filter(|&(name, _)| name.ends_with("-Digest"))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        (headers, data)
+    }
+
+    #[test]
+    fn full_response() {
+        let digest = "sha-256=:hNiYd/DUBB77a/kaFvAkjy/Vc+avBcGflr7bn4gveII=:";
+        assert_eq!(digests("GET", None), (vec![
+            (String::from("Content-Digest"), String::from(digest)),
+            (String::from("Repr-Digest"), String::from(digest)),
+        ], b"0123456789".to_vec()));
+        assert_eq!(digests("HEAD", None).0, vec![
+            (String::from("Repr-Digest"), String::from(digest)),
+        ]);
+    }
+
+    #[test]
+    fn ranged_response() {
+        assert_eq!(digests("GET", Some("bytes=2-5")), (vec![
+            (String::from("Content-Digest"), String::from(
+                "sha-256=:OAg8fukSHhdAGINWahSKpcLi1V3FO8SpSgJlF9v/PGs=:")),
+            (String::from("Repr-Digest"), String::from(
+                "sha-256=:hNiYd/DUBB77a/kaFvAkjy/Vc+avBcGflr7bn4gveII=:")),
+        ], b"2345".to_vec()));
+        let (headers, _) = digests("GET", Some("bytes=0-1,5-6"));
+        assert_eq!(headers.iter().map(|h| &h.0[..]).collect::<Vec<_>>(),
+                   vec!["Repr-Digest"]);
+    }
+
+    #[test]
+    fn disabled() {
+        let source = MockSource::new().file("/www/file.txt", b"0123456789");
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      Vec::new().into_iter());
+        match inp.probe_with(&source, "/www/file.txt").unwrap() {
+            Output::File(outf) => {
+                assert!(!outf.headers().any(|(n, _)| n.ends_with("-Digest")));
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        assert_eq!(source.reads(), 0);
+    }
+}
@@ -1,5 +1,3 @@
-use std::io::Write;
-use std::fs::Metadata;
 use std::fmt;
 use std::time::{Duration, UNIX_EPOCH};
 use std::str::from_utf8_unchecked;
@@ -8,31 +6,114 @@ use blake2::{Blake2b, Digest, digest::VariableOutput};
 use digest_writer::Writer;
 use byteorder::{WriteBytesExt, BigEndian};
 
+use accept_encoding::Encoding;
+use config::EtagStrategy;
+use source::Metadata;
 
+
+/// Entity tag, either derived from the file or supplied by user
 #[derive(Clone, PartialEq, Eq)]
-pub struct Etag(pub(crate) [u8; 12]);
+#[non_exhaustive]
+pub enum Etag {
+    /// Weak etag derived from the file metadata
+    Hash([u8; 12]),
+    /// Weak etag of the identity file, served with this content encoding
+    ///
+    /// Formatted as the identity etag with the encoding name appended,
+    /// e.g. `W/"tYJT9KJUI0KX2I5q-gzip"`.
+    Encoded([u8; 12], Encoding),
+    /// Any other entity tag, including quotes and `W/` prefix if any
+    Opaque(Box<str>),
+}
 
 
 impl Etag {
-    pub fn from_metadata(metadata: &Metadata) -> Etag {
-        let mut wr = Writer::new(<Blake2b as VariableOutput>::new(12)
-            .expect("blake2b supports 12 bytes"));
-        wr.write_u64::<BigEndian>(metadata.len()).unwrap();
-        let fmod = metadata.modified().ok()
-            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
-            .unwrap_or(Duration::new(0, 0));
-        wr.write_u64::<BigEndian>(fmod.as_secs()).unwrap();
-        wr.write_u32::<BigEndian>(fmod.subsec_nanos()).unwrap();
-        let fcreated = metadata.created().ok()
-            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
-            .unwrap_or(Duration::new(0, 0));
-        wr.write_u64::<BigEndian>(fcreated.as_secs()).unwrap();
-        wr.write_u32::<BigEndian>(fcreated.subsec_nanos()).unwrap();
-        extra(&mut wr, metadata);
-        let digest = wr.into_inner();
+    /// Makes an entity tag from a user-supplied value
+    ///
+    /// The value is quoted if it isn't already
+    pub fn opaque(value: &str) -> Etag {
+        if is_entity_tag(value.as_bytes()) {
+            Etag::Opaque(value.into())
+        } else {
+            Etag::Opaque(format!("\"{}\"", value).into())
+        }
+    }
+    /// Etag derived from the content itself, for in-memory bodies
+    pub fn from_bytes(data: &[u8]) -> Etag {
+        let mut hash = <Blake2b as VariableOutput>::new(12)
+            .expect("blake2b supports 12 bytes");
+        hash.input(data);
+        let mut value = [0u8; 12];
+        hash.variable_result(&mut value[..])
+            .expect("blake2b produces 12 bytes");
+        return Etag::Hash(value);
+    }
+    /// Weak comparison, as used for `If-None-Match`
+    pub fn weak_eq(&self, other: &Etag) -> bool {
+        match (self, other) {
+            (Etag::Hash(a), Etag::Hash(b)) => a == b,
+            (Etag::Encoded(a, x), Etag::Encoded(b, y)) => a == b && x == y,
+            (Etag::Opaque(a), Etag::Opaque(b)) => {
+                a.trim_start_matches("W/") == b.trim_start_matches("W/")
+            }
+            _ => false,
+        }
+    }
+    /// The `*` value of `If-Match`, that matches any existing file
+    pub(crate) fn any() -> Etag {
+        Etag::Opaque("*".into())
+    }
+    /// Strong comparison, as used for `If-Match`
+    ///
+    /// Etags derived from metadata are weak, so never match
+    pub fn strong_eq(&self, other: &Etag) -> bool {
+        match (self, other) {
+            (Etag::Opaque(a), Etag::Opaque(b)) => {
+                !a.starts_with("W/") && a == b
+            }
+            _ => false,
+        }
+    }
+    /// Weak etag derived from the file metadata
+    pub fn from_metadata(metadata: &Metadata, strategy: EtagStrategy)
+        -> Etag
+    {
+        Etag::from_digest(metadata_digest(metadata, strategy).into_inner())
+    }
+    /// Etag of the window of the file, `metadata` has length of the window
+    pub(crate) fn from_window(metadata: &Metadata, offset: u64,
+                              strategy: EtagStrategy)
+        -> Etag
+    {
+        let mut wr = metadata_digest(metadata, strategy);
+        wr.write_u64::<BigEndian>(offset).unwrap();
+        Etag::from_digest(wr.into_inner())
+    }
+    /// Etag of the same file served with the content `encoding`
+    ///
+    /// Only etags derived from metadata are changed
+    pub(crate) fn encoded(self, encoding: Encoding) -> Etag {
+        match self {
+            Etag::Hash(h) if encoding != Encoding::Identity => {
+                Etag::Encoded(h, encoding)
+            }
+            etag => etag,
+        }
+    }
+    /// Same etag without the `W/` prefix, see `Config::strong_etags`
+    pub(crate) fn into_strong(self) -> Etag {
+        match self {
+            Etag::Opaque(ref value) if value.starts_with("W/") => {
+                Etag::Opaque(value[2..].into())
+            }
+            etag @ Etag::Opaque(_) => etag,
+            etag => Etag::Opaque(etag.to_string()[2..].into()),
+        }
+    }
+    fn from_digest(digest: Blake2b) -> Etag {
         let mut value = [0u8; 12];
         digest.variable_result(&mut value[..]);
-        return Etag(value);
+        return Etag::Hash(value);
     }
     pub(crate) fn decode_base64(slice: &[u8]) -> Result<Etag, ()> {
         debug_assert!(slice.len() == 16);
@@ -41,23 +122,47 @@ impl Etag {
         decode4(&slice[4..8], &mut value[3..6])?;
         decode4(&slice[8..12], &mut value[6..9])?;
         decode4(&slice[12..], &mut value[9..])?;
-        Ok(Etag(value))
+        Ok(Etag::Hash(value))
     }
 }
 
-#[cfg(unix)]
-fn extra<W: Write>(wr: &mut W, metadata: &Metadata) {
-    use std::os::unix::fs::MetadataExt;
-    // sometimes last_modified date is not reliable
-    // so we use inode number and `ctime` date on unix systems too
-    wr.write_u64::<BigEndian>(metadata.dev()).unwrap();
-    wr.write_u64::<BigEndian>(metadata.ino()).unwrap();
-    wr.write_i64::<BigEndian>(metadata.ctime()).unwrap();
-    wr.write_i64::<BigEndian>(metadata.ctime_nsec()).unwrap();
+fn metadata_digest(metadata: &Metadata, strategy: EtagStrategy)
+    -> Writer<Blake2b>
+{
+    let mut wr = Writer::new(<Blake2b as VariableOutput>::new(12)
+        .expect("blake2b supports 12 bytes"));
+    wr.write_u64::<BigEndian>(metadata.len).unwrap();
+    let fmod = metadata.modified
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or(Duration::new(0, 0));
+    wr.write_u64::<BigEndian>(fmod.as_secs()).unwrap();
+    if strategy == EtagStrategy::MtimeSizeWeak {
+        return wr;
+    }
+    wr.write_u32::<BigEndian>(fmod.subsec_nanos()).unwrap();
+    let fcreated = metadata.created
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or(Duration::new(0, 0));
+    wr.write_u64::<BigEndian>(fcreated.as_secs()).unwrap();
+    wr.write_u32::<BigEndian>(fcreated.subsec_nanos()).unwrap();
+    if let Some((dev, ino, ctime, ctime_nsec)) = metadata.unix {
+        // sometimes last_modified date is not reliable
+        // so we use inode number and `ctime` date on unix systems too
+        wr.write_u64::<BigEndian>(dev).unwrap();
+        wr.write_u64::<BigEndian>(ino).unwrap();
+        wr.write_i64::<BigEndian>(ctime).unwrap();
+        wr.write_i64::<BigEndian>(ctime_nsec).unwrap();
+    }
+    return wr;
 }
 
-#[cfg(not(unix))]
-fn extra<W: Write>(_: &mut W, _: &Metadata) {
+/// Checks that value is a valid (optionally weak) quoted entity tag
+pub fn is_entity_tag(value: &[u8]) -> bool {
+    let value = if value.starts_with(b"W/") { &value[2..] } else { value };
+    value.len() >= 2 &&
+        value[0] == b'"' && value[value.len()-1] == b'"' &&
+        value[1..value.len()-1].iter()
+            .all(|&c| c == 0x21 || c >= 0x23 && c != 0x7F)
 }
 
 #[inline(always)]
@@ -109,12 +214,21 @@ fn decode4(src: &[u8], dest: &mut [u8]) -> Result<(), ()> {
 
 impl fmt::Display for Etag {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (hash, encoding) = match *self {
+            Etag::Hash(ref hash) => (hash, None),
+            Etag::Encoded(ref hash, enc) => (hash, Some(enc)),
+            Etag::Opaque(ref value) => return f.write_str(value),
+        };
         let mut result = [0u8; 16];
-        base64triple(&self.0[..3], &mut result[..4]);
-        base64triple(&self.0[3..6], &mut result[4..8]);
-        base64triple(&self.0[6..9], &mut result[8..12]);
-        base64triple(&self.0[9..], &mut result[12..]);
-        write!(f, r#"W/"{}""#, unsafe { from_utf8_unchecked(&result[..]) })
+        base64triple(&hash[..3], &mut result[..4]);
+        base64triple(&hash[3..6], &mut result[4..8]);
+        base64triple(&hash[6..9], &mut result[8..12]);
+        base64triple(&hash[9..], &mut result[12..]);
+        let hash = unsafe { from_utf8_unchecked(&result[..]) };
+        match encoding {
+            Some(enc) => write!(f, r#"W/"{}-{}""#, hash, enc),
+            None => write!(f, r#"W/"{}""#, hash),
+        }
     }
 }
 
@@ -128,10 +242,32 @@ impl fmt::Debug for Etag {
 mod test {
     use super::*;
 
+    #[test]
+    fn into_strong() {
+        let hash = [181, 130, 83, 244, 162, 84, 35, 66, 151, 216, 142, 106];
+        assert_eq!(Etag::Hash(hash).into_strong().to_string(),
+                   r#""tYJT9KJUI0KX2I5q""#);
+        assert_eq!(Etag::Encoded(hash, Encoding::Gzip).into_strong()
+                   .to_string(), r#""tYJT9KJUI0KX2I5q-gzip""#);
+        assert_eq!(Etag::opaque(r#"W/"v1""#).into_strong(),
+                   Etag::opaque("v1"));
+        assert_eq!(Etag::opaque("v1").into_strong(), Etag::opaque("v1"));
+    }
+
     #[test]
     fn format() {
         assert_eq!(format!("{}",
-            Etag([181, 130, 83, 244, 162, 84, 35, 66, 151, 216, 142, 106])),
+            Etag::Hash([181, 130, 83, 244, 162, 84, 35, 66,
+                        151, 216, 142, 106])),
             String::from(r#"W/"tYJT9KJUI0KX2I5q""#));
     }
+
+    #[test]
+    fn opaque() {
+        assert_eq!(format!("{}", Etag::opaque("abc")), r#""abc""#);
+        assert_eq!(format!("{}", Etag::opaque(r#""abc""#)), r#""abc""#);
+        assert_eq!(format!("{}", Etag::opaque(r#"W/"abc""#)), r#"W/"abc""#);
+        assert!(Etag::opaque("abc").weak_eq(&Etag::opaque(r#"W/"abc""#)));
+        assert!(!Etag::opaque("abc").weak_eq(&Etag::opaque("abd")));
+    }
 }
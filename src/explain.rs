@@ -0,0 +1,276 @@
+use std::ffi::OsString;
+use std::fmt::{self, Write};
+use std::path::{Path, PathBuf};
+
+use accept_encoding::Encoding;
+use input::{Input, Mode, MAX_INDEX_FILES, is_present};
+use output::Output;
+use source::{FileSource, Filesystem};
+
+
+/// Explanation of how the request is served, see `Input::explain`
+///
+/// Formatting it with `Display` gives a JSON object like this:
+///
+/// ```json
+/// {"mode": "GET", "encodings": ["br", "gzip", "identity"],
+///  "candidates": [{"path": "/www/app.js.br", "encoding": "br",
+///                  "exists": false}, ...],
+///  "output": "File", "status": 200, "encoding": "gzip",
+///  "etag": "W/\"...\"", "condition": null, "error": null}
+/// ```
+///
+/// The `condition` is the request header that made the response 304 or
+/// 412, if any.
+#[derive(Debug)]
+pub struct Explanation {
+    mode: &'static str,
+    encodings: Vec<Encoding>,
+    candidates: Vec<(PathBuf, Encoding, bool)>,
+    output: Option<&'static str>,
+    status: Option<u16>,
+    encoding: Option<Encoding>,
+    etag: Option<String>,
+    condition: Option<&'static str>,
+    error: Option<String>,
+}
+
+impl Input {
+    /// Explain how the file at `base_path` would be served
+    ///
+    /// The file is probed as in `probe_file`, but the result is
+    /// described instead of being returned. This is meant for debugging
+    /// endpoints, to find out why some encoding is not served, so it's
+    /// never used unless called explicitly. Note: the explanation contains
+    /// filesystem paths, so it shouldn't be exposed publicly.
+    ///
+    /// **Must be run in disk thread**
+    pub fn explain<P: AsRef<Path>>(&self, base_path: P) -> Explanation {
+        self.explain_with(&Filesystem, base_path)
+    }
+    /// Same as `explain` but uses the custom file source
+    pub fn explain_with<S, P>(&self, source: &S, base_path: P)
+        -> Explanation
+        where S: FileSource, P: AsRef<Path>,
+    {
+        let base_path = base_path.as_ref();
+        let mut expl = Explanation {
+            mode: match self.mode {
+                Mode::Get => "GET",
+                Mode::Head => "HEAD",
+                Mode::Options => "OPTIONS",
+                Mode::InvalidMethod => "invalid method",
+                Mode::InvalidRange => "invalid range",
+            },
+            encodings: self.probe_encodings().iter().collect(),
+            candidates: Vec::new(),
+            output: None,
+            status: None,
+            encoding: None,
+            etag: None,
+            condition: None,
+            error: None,
+        };
+        match source.stat(base_path) {
+            Ok(ref meta) if meta.is_dir() => {
+                let mut buf = base_path.to_path_buf();
+                for name in self.config.index_files.iter()
+                    .take(MAX_INDEX_FILES)
+                {
+                    buf.push(name);
+                    self.add_candidates(source, &buf, &mut expl);
+                    buf.pop();
+                }
+            }
+            Ok(_) => self.add_candidates(source, base_path, &mut expl),
+            Err(_) => {
+                expl.candidates.push((base_path.to_path_buf(),
+                                      Encoding::Identity, false));
+            }
+        }
+        match self.probe_with(source, base_path) {
+            Ok(output) => expl.describe(self, &output),
+            Err(e) => expl.error = Some(e.to_string()),
+        }
+        expl
+    }
+    fn add_candidates<S: FileSource>(&self, source: &S, path: &Path,
+        expl: &mut Explanation)
+    {
+        if !self.use_encodings(&self.content_type_of(path)) {
+            let exists = is_present(source, path);
+            expl.candidates.push((path.to_path_buf(), Encoding::Identity,
+                                  exists));
+            return;
+        }
+        let mut buf = OsString::with_capacity(path.as_os_str().len() + 4);
+        for enc in self.probe_encodings().iter() {
+            buf.clear();
+            buf.push(path.as_os_str());
+            buf.push(enc.suffix());
+            let exists = is_present(source, Path::new(&buf));
+            expl.candidates.push((PathBuf::from(&buf), enc, exists));
+        }
+    }
+}
+
+impl Explanation {
+    fn describe<F>(&mut self, inp: &Input, output: &Output<F>) {
+        let (name, head) = match *output {
+            Output::NotFound => ("NotFound", None),
+            Output::FileHead(ref head) => ("FileHead", Some(head)),
+            Output::NotModified(ref head) => ("NotModified", Some(head)),
+            Output::PreconditionFailed(ref head) => {
+                ("PreconditionFailed", Some(head))
+            }
+            Output::File(_) => ("File", None),
+            Output::FileRange(_) => ("FileRange", None),
+            Output::Directory => ("Directory", None),
+            Output::Options => ("Options", None),
+            Output::InvalidMethod => ("InvalidMethod", None),
+            Output::InvalidRange => ("InvalidRange", None),
+            Output::RangeNotSatisfiable(_) => ("RangeNotSatisfiable", None),
+            Output::InternalRedirect(ref head) => {
+                ("InternalRedirect", Some(head))
+            }
+            Output::ServiceUnavailable(_) => ("ServiceUnavailable", None),
+        };
+        self.output = Some(name);
+        self.encoding = output.encoding();
+        let (status, mut headers) = match (head, output) {
+            (Some(head), _) => (Some(head.status()), Some(head.headers())),
+            (None, &Output::File(ref outf))
+            | (None, &Output::FileRange(ref outf))
+            => (Some(outf.status()), Some(outf.headers())),
+            _ => (None, None),
+        };
+        self.status = status;
+        self.etag = headers.as_mut()
+            .and_then(|h| h.find(|&(name, _)| name == "ETag"))
+            .map(|(_, value)| value.to_string());
+        self.condition = match *output {
+            Output::NotModified(_) if !inp.if_none.is_empty() => {
+                Some("If-None-Match")
+            }
+            Output::NotModified(_) => Some("If-Modified-Since"),
+            Output::PreconditionFailed(_) if !inp.if_match.is_empty() => {
+                Some("If-Match")
+            }
+            Output::PreconditionFailed(_) if inp.if_unmodified.is_some() => {
+                Some("If-Unmodified-Since")
+            }
+            Output::PreconditionFailed(_) => Some("If-None-Match"),
+            _ => None,
+        };
+    }
+}
+
+/// Writes JSON string with escapes
+fn string(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in value.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+fn optional(f: &mut fmt::Formatter, value: Option<&str>) -> fmt::Result {
+    match value {
+        Some(value) => string(f, value),
+        None => f.write_str("null"),
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("{\"mode\": ")?;
+        string(f, self.mode)?;
+        f.write_str(", \"encodings\": [")?;
+        for (idx, enc) in self.encodings.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(", ")?;
+            }
+            string(f, enc.name())?;
+        }
+        f.write_str("], \"candidates\": [")?;
+        for (idx, (path, enc, exists)) in self.candidates.iter().enumerate()
+        {
+            if idx > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str("{\"path\": ")?;
+            string(f, &path.to_string_lossy())?;
+            f.write_str(", \"encoding\": ")?;
+            string(f, enc.name())?;
+            write!(f, ", \"exists\": {}}}", exists)?;
+        }
+        f.write_str("], \"output\": ")?;
+        optional(f, self.output)?;
+        f.write_str(", \"status\": ")?;
+        match self.status {
+            Some(status) => write!(f, "{}", status)?,
+            None => f.write_str("null")?,
+        }
+        f.write_str(", \"encoding\": ")?;
+        optional(f, self.encoding.as_ref().map(|e| e.name()))?;
+        f.write_str(", \"etag\": ")?;
+        optional(f, self.etag.as_ref().map(|x| &x[..]))?;
+        f.write_str(", \"condition\": ")?;
+        optional(f, self.condition)?;
+        f.write_str(", \"error\": ")?;
+        optional(f, self.error.as_ref().map(|x| &x[..]))?;
+        f.write_str("}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::Config;
+    use input::Input;
+    use source::mock::MockSource;
+
+    #[test]
+    fn gzip_chosen() {
+        let source = MockSource::new()
+            .file("/www/app.js", b"app()")
+            .file("/www/app.js.gz", b"compressed");
+        let headers = vec![("Accept-Encoding", &b"br, gzip"[..])];
+        let inp = Input::from_headers(&Config::new().etag(false).done(),
+                                      "GET", headers.into_iter());
+        assert_eq!(inp.explain_with(&source, "/www/app.js").to_string(),
+            "{\"mode\": \"GET\", \
+              \"encodings\": [\"br\", \"gzip\", \"identity\"], \
+              \"candidates\": [\
+                {\"path\": \"/www/app.js.br\", \"encoding\": \"br\", \
+                 \"exists\": false}, \
+                {\"path\": \"/www/app.js.gz\", \"encoding\": \"gzip\", \
+                 \"exists\": true}, \
+                {\"path\": \"/www/app.js\", \"encoding\": \"identity\", \
+                 \"exists\": true}], \
+              \"output\": \"File\", \"status\": 200, \"encoding\": \"gzip\", \
+              \"etag\": null, \"condition\": null, \"error\": null}");
+    }
+
+    #[test]
+    fn not_modified() {
+        let source = MockSource::new().file("/www/a\"b.txt", b"hello");
+        let headers = vec![
+            ("If-Modified-Since", &b"Tue, 22 Aug 2017 20:47:13 GMT"[..]),
+        ];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        let json = inp.explain_with(&source, "/www/a\"b.txt").to_string();
+        assert!(json.contains("\"path\": \"/www/a\\\"b.txt\""));
+        assert!(json.contains("\"output\": \"NotModified\", \"status\": 304"));
+        assert!(json.contains("\"condition\": \"If-Modified-Since\""));
+        assert!(json.contains("\"etag\": \"W/\\\""));
+    }
+}
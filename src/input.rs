@@ -1,28 +1,65 @@
 use std::io;
+use std::borrow::Cow;
 use std::time::SystemTime;
-use std::fs::{File};
-use std::path::Path;
+use std::path::{Path, PathBuf, Component};
 use std::ffi::OsString;
 use std::sync::Arc;
 
+use accept::{AcceptFormats, AcceptParser};
 use accept_encoding::{AcceptEncoding, AcceptEncodingParser};
 use accept_encoding::{Iter as EncodingIter, Encoding};
 use config::{Config, EncodingSupport};
-use conditionals::{ModifiedParser, NoneMatchParser};
+use conditionals::{ModifiedParser, NoneMatchParser, EtagList};
+use conditionals::{is_any, parse_if_range};
 use etag::Etag;
-use output::{Head, FileWrapper};
-use range::{Range, RangeParser};
+use range::{Range, RangeParser, RangeSet};
+use sniff::sniff;
+use source::{FileSource, Filesystem};
 use mime_guess::get_mime_type_str;
+use output::Vary;
 use {Output};
 
+/// Maximum number of index files probed for a directory
+///
+/// Each index file is probed with every acceptable encoding, so this limits
+/// number of open attempts on each directory request.
+pub(crate) const MAX_INDEX_FILES: usize = 4;
+
+/// All encodings except identity
+const ENCODED: [Encoding; 3] = [
+    Encoding::Brotli, Encoding::Zstd, Encoding::Gzip,
+];
+
+/// Method of the request, see `Input::from_headers_method`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Method {
+    /// `GET`
+    Get,
+    /// `HEAD`
+    Head,
+    /// `OPTIONS`
+    Options,
+    /// Any other method, responded with `Output::InvalidMethod`
+    Other,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
     Head,
     Get,
+    Options,
     InvalidMethod,
     InvalidRange,
 }
 
+impl Mode {
+    /// Methods for which 304 Not Modified can be returned
+    pub fn is_get_or_head(&self) -> bool {
+        *self == Mode::Get || *self == Mode::Head
+    }
+}
+
 pub fn is_text_file(val: &str) -> bool {
     return val.starts_with("text/") || val == "application/javascript"
 }
@@ -37,12 +74,79 @@ pub struct Input {
     pub(crate) config: Arc<Config>,
     pub(crate) mode: Mode,
     pub(crate) accept_encoding: AcceptEncoding,
+    pub(crate) transfer_encoding: AcceptEncoding,
+    pub(crate) accept: AcceptFormats,
+    pub(crate) ranges: bool,
     pub(crate) range: Option<Range>,
     pub(crate) if_range: Option<Result<SystemTime, Etag>>,
-    pub(crate) if_match: Vec<Etag>,
-    pub(crate) if_none: Vec<Etag>,
+    pub(crate) if_match: EtagList,
+    pub(crate) if_none: EtagList,
     pub(crate) if_unmodified: Option<SystemTime>,
     pub(crate) if_modified: Option<SystemTime>,
+    pub(crate) overrides: Option<Box<Overrides>>,
+}
+
+/// Values set by `Input::override_*`, boxed as they are rarely used
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Overrides {
+    content_type: Option<String>,
+    last_modified: Option<SystemTime>,
+}
+
+/// Directory to serve files from, see `Input::probe_file_first`
+#[derive(Debug, Clone)]
+pub struct Root {
+    path: PathBuf,
+    config: Option<Arc<Config>>,
+}
+
+impl Root {
+    /// Root directory that uses config of the `Input`
+    pub fn new<P: AsRef<Path>>(path: P) -> Root {
+        Root {
+            path: path.as_ref().to_path_buf(),
+            config: None,
+        }
+    }
+    /// Root directory that has own config
+    ///
+    /// The config is used for looking up files and for response headers.
+    /// But request headers are parsed using the config of the `Input`, so
+    /// options like `Config::etag`, `Config::ranges` and alternate formats
+    /// should be the same for all roots.
+    pub fn with_config<P: AsRef<Path>>(path: P, config: &Arc<Config>)
+        -> Root
+    {
+        Root {
+            path: path.as_ref().to_path_buf(),
+            config: Some(config.clone()),
+        }
+    }
+}
+
+/// All the conditional headers of the request, see `Input::conditionals`
+///
+/// Headers are listed only if parsing of them is enabled in the config,
+/// i.e. etags are empty if `Config::etag` is disabled and dates are `None`
+/// if `Config::last_modified` is disabled. Invalid headers are skipped.
+#[derive(Debug, Clone, Copy)]
+pub struct Conditionals<'a> {
+    /// Entity tags in `If-Match`
+    pub if_match: &'a [Etag],
+    /// Whether `If-Match: *` is sent (`if_match` is empty then)
+    pub if_match_any: bool,
+    /// Entity tags in `If-None-Match`
+    pub if_none_match: &'a [Etag],
+    /// Whether `If-None-Match: *` is sent (`if_none_match` is empty then)
+    pub if_none_match_any: bool,
+    /// Date in `If-Modified-Since`
+    pub if_modified_since: Option<SystemTime>,
+    /// Date in `If-Unmodified-Since`
+    pub if_unmodified_since: Option<SystemTime>,
+    /// Date in `If-Range` (only if there is a `Range` too)
+    pub if_range_date: Option<SystemTime>,
+    /// Entity tag in `If-Range` (only if there is a `Range` too)
+    pub if_range_etag: Option<&'a Etag>,
 }
 
 impl Input {
@@ -50,33 +154,101 @@ impl Input {
     pub fn from_headers<'x, I>(cfg: &Arc<Config>, method: &str, headers: I)
         -> Input
         where I: Iterator<Item=(&'x str, &'x[u8])>
+    {
+        Input::from_header_pairs(cfg, method, headers)
+    }
+    /// Same as `from_headers` but for the method that is already parsed
+    ///
+    /// This is for frameworks having own enum for the method, so it
+    /// isn't converted to a string and back. Note: `Config::lenient_method`
+    /// doesn't apply here.
+    pub fn from_headers_method<'x, I>(cfg: &Arc<Config>, method: Method,
+        headers: I)
+        -> Input
+        where I: Iterator<Item=(&'x str, &'x[u8])>
+    {
+        let mode = match method {
+            Method::Get => Mode::Get,
+            Method::Head => Mode::Head,
+            Method::Options => Mode::Options,
+            _ => Mode::InvalidMethod,
+        };
+        Input::from_mode(cfg, mode, headers)
+    }
+    /// Same as `from_headers` but accepts any (including owned) pairs
+    ///
+    /// This is useful if your framework can't easily give out borrowed
+    /// header values, e.g. `(String, Vec<u8>)` pairs can be passed as is.
+    pub fn from_header_pairs<I, K, V>(cfg: &Arc<Config>, method: &str,
+        headers: I)
+        -> Input
+        where I: IntoIterator<Item=(K, V)>,
+              K: AsRef<str>,
+              V: AsRef<[u8]>,
     {
         let mode = match method {
             "HEAD" => Mode::Head,
             "GET" => Mode::Get,
-            _ => return Input {
-                config: cfg.clone(),
-                mode: Mode::InvalidMethod,
-                accept_encoding: AcceptEncoding::identity(),
-                range: None,
-                if_range: None,
-                if_match: Vec::new(),
-                if_none: Vec::new(),
-                if_unmodified: None,
-                if_modified: None,
-            },
+            "OPTIONS" => Mode::Options,
+            _ if cfg.lenient_method && method.eq_ignore_ascii_case("HEAD")
+            => Mode::Head,
+            _ if cfg.lenient_method && method.eq_ignore_ascii_case("GET")
+            => Mode::Get,
+            _ if cfg.lenient_method && method.eq_ignore_ascii_case("OPTIONS")
+            => Mode::Options,
+            _ => Mode::InvalidMethod,
         };
-        let mut ae_parser = AcceptEncodingParser::new();
-        let mut range_parser = RangeParser::new();
+        Input::from_mode(cfg, mode, headers)
+    }
+    fn from_mode<I, K, V>(cfg: &Arc<Config>, mode: Mode, headers: I)
+        -> Input
+        where I: IntoIterator<Item=(K, V)>,
+              K: AsRef<str>,
+              V: AsRef<[u8]>,
+    {
+        if mode == Mode::InvalidMethod {
+            return Input::empty(cfg, mode);
+        }
+        let mut ae_parser = AcceptEncodingParser::with_limit(
+            cfg.max_accept_encoding);
+        let mut te_parser = AcceptEncodingParser::with_limit(
+            cfg.max_accept_encoding);
+        let mut accept_parser = AcceptParser::new(&cfg.formats);
+        let mut range_parser = RangeParser::with_limit(cfg.max_range);
         let mut modified_parser = ModifiedParser::new();
+        let mut unmodified_parser = ModifiedParser::new();
         let mut none_match_parser = NoneMatchParser::new();
+        let mut match_parser = NoneMatchParser::new();
+        let mut match_any = false;
+        let mut none_match_any = false;
+        let mut if_range = None;
+        let mut has_accept_encoding = false;
         for (key, val) in headers {
+            let (key, val) = (key.as_ref(), val.as_ref());
             if cfg.encoding_support != EncodingSupport::Never &&
                key.eq_ignore_ascii_case("accept-encoding")
             {
                 ae_parser.add_header(val);
-            } else if key.eq_ignore_ascii_case("range") {
+                has_accept_encoding = true;
+            } else if !cfg.formats.is_empty() &&
+                      key.eq_ignore_ascii_case("accept")
+            {
+                accept_parser.add_header(val);
+            } else if key.eq_ignore_ascii_case("te") {
+                te_parser.add_header(val);
+            } else if mode != Mode::Options && cfg.ranges &&
+                      key.eq_ignore_ascii_case("range")
+            {
+                // range is meaningful only for GET and HEAD
                 range_parser.add_header(val);
+            } else if mode != Mode::Options && cfg.ranges &&
+                      key.eq_ignore_ascii_case("if-range")
+            {
+                // duplicate header is invalid
+                if_range = match if_range {
+                    None => Some(parse_if_range(val)),
+                    Some(_) => Some(None),
+                };
             } else if cfg.last_modified &&
                       key.eq_ignore_ascii_case("if-modified-since")
             {
@@ -84,120 +256,581 @@ impl Input {
             } else if cfg.etag &&
                       key.eq_ignore_ascii_case("if-none-match")
             {
-                none_match_parser.add_header(val);
+                if is_any(val) {
+                    none_match_any = true;
+                } else {
+                    none_match_parser.add_header(val);
+                }
+            } else if cfg.etag && key.eq_ignore_ascii_case("if-match") {
+                if is_any(val) {
+                    match_any = true;
+                } else {
+                    match_parser.add_header(val);
+                }
+            } else if cfg.last_modified &&
+                      key.eq_ignore_ascii_case("if-unmodified-since")
+            {
+                unmodified_parser.add_header(val);
             }
         }
-        let range = match range_parser.done() {
+        if !has_accept_encoding && cfg.assume_gzip {
+            ae_parser.add_header(b"gzip");
+        }
+        let mut range = match range_parser.done() {
             Ok(range) => range,
-            Err(()) => return Input {
-                config: cfg.clone(),
-                mode: Mode::InvalidRange,
-                accept_encoding: AcceptEncoding::identity(),
-                range: None,
-                if_range: None,
-                if_match: Vec::new(),
-                if_none: Vec::new(),
-                if_unmodified: None,
-                if_modified: None,
-            },
+            Err(_) => return Input::empty(cfg, Mode::InvalidRange),
+        };
+        let if_range = match if_range {
+            Some(Some(_)) if range.is_none() => None,
+            Some(Some(validator)) => Some(validator),
+            Some(None) => {
+                // can't validate the range, so send the whole file
+                range = None;
+                None
+            }
+            None => None,
+        };
+        let if_match = if match_any {
+            EtagList::one(Etag::any())
+        } else {
+            match_parser.done()
+        };
+        let if_none = if none_match_any {
+            EtagList::one(Etag::any())
+        } else {
+            none_match_parser.done()
         };
         Input {
             config: cfg.clone(),
             mode: mode,
             accept_encoding: ae_parser.done(),
+            transfer_encoding: te_parser.done(),
+            accept: accept_parser.done(),
+            ranges: cfg.ranges,
             range: range,
+            if_range: if_range,
+            if_match: if_match,
+            if_none: if_none,
+            if_unmodified: unmodified_parser.done(),
+            if_modified: modified_parser.done(),
+            overrides: None,
+        }
+    }
+    pub(crate) fn is_head(&self) -> bool {
+        self.mode == Mode::Head
+    }
+    fn empty(cfg: &Arc<Config>, mode: Mode) -> Input {
+        Input {
+            config: cfg.clone(),
+            mode: mode,
+            accept_encoding: AcceptEncoding::identity(),
+            transfer_encoding: AcceptEncoding::identity(),
+            accept: AcceptFormats::none(),
+            ranges: cfg.ranges,
+            range: None,
             if_range: None,
-            if_match: Vec::new(),
-            if_none: none_match_parser.done(),
+            if_match: EtagList::new(),
+            if_none: EtagList::new(),
             if_unmodified: None,
-            if_modified: modified_parser.done(),
+            if_modified: None,
+            overrides: None,
+        }
+    }
+    /// Returns ranges of the `Range` header, if any
+    ///
+    /// This is `None` if there is no header, or ranges are disabled (see
+    /// `Config::ranges`), or the range is ignored because `If-Range` is
+    /// invalid.
+    pub fn range(&self) -> Option<RangeSet> {
+        self.range.as_ref().map(|range| range.as_set())
+    }
+    /// Returns the conditional headers parsed from the request
+    pub fn conditionals(&self) -> Conditionals {
+        let (if_match, if_match_any) = split_any(&self.if_match);
+        let (if_none_match, if_none_match_any) = split_any(&self.if_none);
+        Conditionals {
+            if_match: if_match,
+            if_match_any: if_match_any,
+            if_none_match: if_none_match,
+            if_none_match_any: if_none_match_any,
+            if_modified_since: self.if_modified,
+            if_unmodified_since: self.if_unmodified,
+            if_range_date: match self.if_range {
+                Some(Ok(date)) => Some(date),
+                _ => None,
+            },
+            if_range_etag: match self.if_range {
+                Some(Err(ref etag)) => Some(etag),
+                _ => None,
+            },
         }
     }
+    /// Use specified `Content-Type` instead of one guessed by extension
+    ///
+    /// The value is used as is, except charset is appended to `text/*`
+    /// types (if `Config::text_charset` is set and the value has no
+    /// charset already). The value also determines whether compressed
+    /// files are looked for when `Config::encodings_on_text_files` is used.
+    pub fn override_content_type(&mut self, value: &str) -> &mut Input {
+        self.overrides_mut().content_type = Some(value.into());
+        self
+    }
+    /// Use specified modification time instead of one of the file
+    ///
+    /// The time is sent in `Last-Modified` and is compared to
+    /// `If-Modified-Since`, `If-Unmodified-Since` and `If-Range` dates.
+    /// This is useful when the modification time of the file is
+    /// meaningless, e.g. for files that are just unpacked from an archive.
+    /// Note: etags are still derived from the real metadata, use a manifest
+    /// to override them too.
+    pub fn override_last_modified(&mut self, value: SystemTime)
+        -> &mut Input
+    {
+        self.overrides_mut().last_modified = Some(value);
+        self
+    }
+    fn overrides_mut(&mut self) -> &mut Overrides {
+        self.overrides.get_or_insert_with(Default::default)
+    }
+    pub(crate) fn content_type_override(&self) -> Option<&str> {
+        self.overrides.as_ref()
+            .and_then(|o| o.content_type.as_ref().map(|x| &x[..]))
+    }
+    pub(crate) fn last_modified_override(&self) -> Option<SystemTime> {
+        self.overrides.as_ref().and_then(|o| o.last_modified)
+    }
+    /// Respond on `HEAD` request exactly as on `GET`
+    ///
+    /// Headers (including `Content-Length`) of `FileHead` are always the
+    /// same as for `GET`. But with this flag `File` is returned for `HEAD`
+    /// too, which is useful if the body is stripped by somebody else, e.g.
+    /// a caching proxy that fetches the whole file on a client's `HEAD`.
+    pub fn head_as_get(&mut self) -> &mut Input {
+        if self.mode == Mode::Head {
+            self.mode = Mode::Get;
+        }
+        self
+    }
+    /// Serve the whole file ignoring `Range` header of this request
+    ///
+    /// This also sends `Accept-Ranges: none`, so it's useful for resources
+    /// which are known to change often, or are generated. Note: a malformed
+    /// `Range` header still results in `Output::InvalidRange`, use
+    /// `Config::ranges(false)` to ignore the header entirely.
+    pub fn disable_ranges(&mut self) -> &mut Input {
+        self.ranges = false;
+        self.range = None;
+        self.if_range = None;
+        self
+    }
+    /// Only consider encodings from the `allowed` list for this request
+    ///
+    /// This is useful to avoid looking for compressed files which are known
+    /// to not exist. Identity encoding (i.e. the original file) is not
+    /// affected.
+    pub fn allow_encodings(&mut self, allowed: &[Encoding]) -> &mut Input {
+        self.accept_encoding.retain(allowed);
+        self
+    }
     /// Iterate over encodings accepted by user-agent in preferred order
     pub fn encodings(&self) -> EncodingIter {
         self.accept_encoding.iter()
     }
+    /// Iterate over transfer codings accepted by user-agent (in `TE` header)
+    /// in preferred order
+    ///
+    /// This is never used for choosing files to serve, transfer codings
+    /// are applied by the HTTP implementation on the fly (if supported).
+    /// Note: `trailers` and `chunked` are not reported here.
+    pub fn transfer_encodings(&self) -> EncodingIter {
+        self.transfer_encoding.iter()
+    }
     /// Open files from filesystem
     ///
     /// **Must be run in disk thread**
     pub fn probe_file<P: AsRef<Path>>(&self, base_path: P)
         -> Result<Output, io::Error>
     {
+        self.probe_with(&Filesystem, base_path)
+    }
+    /// Open files using custom file source
+    ///
+    /// This is the same as `probe_file` but all filesystem access is
+    /// done through the `source`.
+    ///
+    /// The identity path is always `stat`ed first, so a missing file is
+    /// `Output::NotFound` without trying to open any encoded variant
+    /// (i.e. `file.js.gz` is never served if there is no `file.js`). Same
+    /// is true for each index file of a directory.
+    pub fn probe_with<S, P>(&self, source: &S, base_path: P)
+        -> Result<Output<S::File>, io::Error>
+        where S: FileSource, P: AsRef<Path>,
+    {
+        let base_path = base_path.as_ref();
         match self.mode {
             Mode::Head | Mode::Get => {}
             Mode::InvalidMethod => return Ok(Output::InvalidMethod),
             Mode::InvalidRange => return Ok(Output::InvalidRange),
+            Mode::Options if base_path == Path::new("*") => {
+                return Ok(Output::Options);
+            }
+            Mode::Options => {
+                return match source.stat(base_path) {
+                    Ok(_) => Ok(Output::Options),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        Ok(Output::NotFound)
+                    }
+                    Err(e) => Err(e),
+                };
+            }
         }
-        let base_path = base_path.as_ref();
-        match base_path.metadata() {
-            Ok(ref m) if m.is_dir() => self.try_dir(base_path),
-            Ok(_) => self.try_file(base_path),
+        if base_path == Path::new("*") {
+            // asterisk-form of request target is not a file
+            return Ok(Output::NotFound);
+        }
+        match source.stat(base_path) {
+            Ok(ref m) if m.is_dir() => self.try_dir(source, base_path),
+            Ok(_) => self.try_file(source, base_path),
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
                 return Ok(Output::NotFound);
             }
             Err(e) => return Err(e),
         }
     }
-    fn try_dir(&self, base_path: &Path) -> Result<Output, io::Error> {
-        let mut buf = base_path.to_path_buf();
-        for name in &self.config.index_files {
-            buf.push(name);
-            if buf.exists() {
-                return self.try_file(&buf);
+    /// Open file at relative path `rel` inside the `root` directory
+    ///
+    /// Use this instead of joining paths manually when the path comes from
+    /// an URL. Leading slashes and `.` components are skipped, and paths
+    /// containing `..` are refused with `Output::NotFound`, so lookup can
+    /// never escape the `root`. The `*` (as in `OPTIONS *`) is passed as is,
+    /// so it never touches the filesystem. Paths refused by
+    /// `Config::dotfile_policy` are `Output::NotFound` too.
+    ///
+    /// **Must be run in disk thread**
+    pub fn probe_file_under<R, P>(&self, root: R, rel: P)
+        -> Result<Output, io::Error>
+        where R: AsRef<Path>, P: AsRef<Path>,
+    {
+        self.probe_under_with(&Filesystem, root.as_ref(), rel.as_ref())
+    }
+    fn probe_under_with<S: FileSource>(&self, source: &S, root: &Path,
+        rel: &Path)
+        -> Result<Output<S::File>, io::Error>
+    {
+        if rel == Path::new("*") {
+            return self.probe_with(source, "*");
+        }
+        if !self.config.dotfiles.allows(rel) {
+            return Ok(Output::NotFound);
+        }
+        match join_under(root, rel) {
+            Some(path) => self.probe_with(source, path),
+            None => Ok(Output::NotFound),
+        }
+    }
+    /// Open file at relative path `rel` in the first root that has it
+    ///
+    /// Each root is probed as in `probe_file_under`, e.g. to put overrides
+    /// on top of the base directory. Negotiation and conditionals are
+    /// evaluated for the file found. `Output::Directory` is returned only
+    /// if no root has a file at the path.
+    ///
+    /// **Must be run in disk thread**
+    pub fn probe_file_first<P: AsRef<Path>>(&self, roots: &[Root], rel: P)
+        -> Result<Output, io::Error>
+    {
+        self.probe_first_with(&Filesystem, roots, rel.as_ref())
+    }
+    fn probe_first_with<S: FileSource>(&self, source: &S, roots: &[Root],
+        rel: &Path)
+        -> Result<Output<S::File>, io::Error>
+    {
+        let mut result = Output::NotFound;
+        for root in roots {
+            let output = match root.config {
+                Some(ref config) => Input {
+                    config: config.clone(),
+                    .. self.clone()
+                }.probe_under_with(source, &root.path, rel)?,
+                None => self.probe_under_with(source, &root.path, rel)?,
+            };
+            match output {
+                Output::NotFound => {}
+                Output::Directory => result = Output::Directory,
+                output => return Ok(output),
             }
-            buf.pop();
         }
-        Ok(Output::Directory)
+        Ok(result)
+    }
+    /// Find out which encoding would be served without opening files
+    ///
+    /// Only cheap `stat` calls are made through the `source`. Returns `None`
+    /// if there is no file to serve (including directory without index
+    /// file, and invalid requests).
+    ///
+    /// Note: file may still be removed or changed before it's
+    /// actually opened by `probe_with`.
+    pub fn negotiate<S, P>(&self, source: &S, base_path: P)
+        -> Option<Encoding>
+        where S: FileSource, P: AsRef<Path>,
+    {
+        match self.mode {
+            Mode::Head | Mode::Get => {}
+            Mode::InvalidMethod | Mode::InvalidRange => return None,
+            Mode::Options => return None,
+        }
+        let base_path = base_path.as_ref();
+        let meta = source.stat(base_path).ok()?;
+        if meta.is_dir() {
+            let mut buf = base_path.to_path_buf();
+            for name in self.config.index_files.iter().take(MAX_INDEX_FILES) {
+                buf.push(name);
+                if source.stat(&buf).is_ok() {
+                    if let Some(enc) = self.negotiate_file(source, &buf) {
+                        return Some(enc);
+                    }
+                }
+                buf.pop();
+            }
+            None
+        } else {
+            self.negotiate_file(source, base_path)
+        }
+    }
+    fn negotiate_file<S: FileSource>(&self, source: &S, base_path: &Path)
+        -> Option<Encoding>
+    {
+        if !self.use_encodings(&self.content_type_of(base_path)) {
+            return source.stat(base_path).ok().map(|_| Encoding::Identity);
+        }
+        let path = base_path.as_os_str();
+        let mut buf = OsString::with_capacity(path.len() + 4);
+        for enc in self.probe_encodings().iter() {
+            buf.clear();
+            buf.push(path);
+            buf.push(enc.suffix());
+            if is_present(source, Path::new(&buf)) {
+                return Some(enc);
+            }
+        }
+        None
+    }
+    pub(crate) fn content_type_of(&self, path: &Path) -> Cow<'static, str> {
+        let compound = self.config.compound_extension(path);
+        match (self.content_type_override(), compound) {
+            (Some(ctype), _) => Cow::Owned(ctype.into()),
+            (None, Some((ctype, _))) => Cow::Owned(ctype.into()),
+            (None, None) => Cow::Borrowed(content_type(path)
+                .unwrap_or(if self.config.unknown_as_text {
+                    "text/plain"
+                } else {
+                    "application/octet-stream"
+                })),
+        }
+    }
+    fn sniffs(&self, path: &Path) -> bool {
+        self.config.sniff && self.content_type_override().is_none() &&
+            content_type(path).is_none()
     }
-    fn try_file(&self, base_path: &Path) -> Result<Output, io::Error> {
+    /// Accepted encodings in the order files are probed
+    pub(crate) fn probe_encodings(&self) -> Cow<AcceptEncoding> {
+        if self.config.probe_order.is_empty() {
+            Cow::Borrowed(&self.accept_encoding)
+        } else {
+            Cow::Owned(self.accept_encoding
+                .probe_order(&self.config.probe_order))
+        }
+    }
+    pub(crate) fn use_encodings(&self, ctype: &str) -> bool {
         use config::EncodingSupport as E;
-        let ctype = base_path.extension()
-            .and_then(|x| x.to_str())
-            .and_then(|x| get_mime_type_str(x))
-            .unwrap_or("application/octed-stream");
-        let encodings = match self.config.encoding_support {
+        match self.config.encoding_support {
             E::Never => false,
             E::TextFiles => is_text_file(ctype),
             E::AllFiles => true,
-        };
-        if encodings {
-            return self.try_encodings(base_path, ctype);
-        } else {
-            return self.try_path(base_path, Encoding::Identity, ctype);
         }
     }
+    fn try_dir<S: FileSource>(&self, source: &S, base_path: &Path)
+        -> Result<Output<S::File>, io::Error>
+    {
+        let mut buf = base_path.to_path_buf();
+        for name in self.config.index_files.iter().take(MAX_INDEX_FILES) {
+            buf.push(name);
+            // identity is checked first, as in `probe_with`, so missing
+            // index doesn't cost a probe of each encoding
+            match source.stat(&buf) {
+                Ok(_) => match self.try_file(source, &buf) {
+                    Ok(Output::NotFound) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    result => return result,
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            buf.pop();
+        }
+        Ok(Output::Directory)
+    }
+    fn try_file<S: FileSource>(&self, source: &S, base_path: &Path)
+        -> Result<Output<S::File>, io::Error>
+    {
+        let mut vary_accept = false;
+        if !self.config.formats.is_empty() && base_path.extension().is_some()
+        {
+            let mut buf = base_path.to_path_buf();
+            for idx in self.accept.iter() {
+                let (ref media_type, ref extension) = self.config.formats[idx];
+                buf.set_extension(extension);
+                if buf == base_path {
+                    // original format is preferred
+                    break;
+                }
+                let ctype = match self.content_type_override() {
+                    Some(ctype) => Cow::Owned(ctype.into()),
+                    None => Cow::Owned(media_type.clone()),
+                };
+                match self.try_file_as(source, &buf, ctype, true) {
+                    Ok(Output::NotFound) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    result => return result,
+                }
+            }
+            vary_accept = self.has_format_variants(source, base_path);
+        }
+        let ctype = self.content_type_of(base_path);
+        if let Some((_, enc)) = self.config.compound_extension(base_path) {
+            // file is already compressed, so no variants are probed
+            let vary = Vary { encoding: false, accept: vary_accept };
+            return match self.try_path(source, base_path, enc, ctype, vary,
+                                       None)
+            {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    Ok(Output::NotFound)
+                }
+                result => result,
+            };
+        }
+        self.try_file_as(source, base_path, ctype, vary_accept)
+    }
 
-    fn try_path(&self, path: &Path, enc: Encoding, ctype: &'static str)
-        -> Result<Output, io::Error>
+    fn try_file_as<S: FileSource>(&self, source: &S, base_path: &Path,
+        ctype: Cow<'static, str>, vary_accept: bool)
+        -> Result<Output<S::File>, io::Error>
+    {
+        if self.use_encodings(&ctype) {
+            return self.try_encodings(source, base_path, ctype, vary_accept);
+        }
+        let vary = Vary { encoding: false, accept: vary_accept };
+        match self.try_path(source, base_path, Encoding::Identity,
+                            ctype, vary, None)
+        {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Ok(Output::NotFound)
+            }
+            result => result,
+        }
+    }
+
+    fn try_path<S: FileSource>(&self, source: &S, path: &Path,
+        enc: Encoding, ctype: Cow<'static, str>, vary: Vary,
+        identity: Option<&Path>)
+        -> Result<Output<S::File>, io::Error>
     {
-        let f = File::open(path)?;
-        let meta = f.metadata()?;
+        if source.exists(path) == Some(false) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if self.config.symlinks.checks() && source.is_symlink(path)? &&
+            !self.config.symlinks.allows(path, enc)
+        {
+            warn!("Refusing to serve {:?}: is a symlink", path);
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let mut f = source.open(path)?;
+        // only failed `open` means there is no such file, when metadata of
+        // the opened file fails, other files must not be tried instead
+        let meta = source.metadata(&f).map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                io::Error::other(e)
+            } else {
+                e
+            }
+        })?;
         if !meta.is_file() {
             return Err(io::ErrorKind::PermissionDenied.into());
         }
-        let head = match Head::from_meta(self, enc, &meta, ctype) {
-            Err(output) => return Ok(output),
-            Ok(head) => head,
-        };
-        match self.mode {
-            Mode::InvalidMethod => unreachable!(),
-            Mode::InvalidRange => unreachable!(),
-            Mode::Head => Ok(Output::FileHead(head)),
-            Mode::Get => Ok(Output::File(FileWrapper::new(head, f)?)),
+        if !self.config.permission_policy.allows(meta.mode()) {
+            warn!("Refusing to serve {:?}: unsafe permissions {:o}",
+                path, meta.mode().unwrap_or(0));
+            return Err(io::ErrorKind::NotFound.into());
         }
+        let (etag, ctype) = match source.manifest(path) {
+            Some(entry) => {
+                let ctype = match entry.content_type {
+                    // explicit override takes precedence
+                    Some(ctype) if self.content_type_override().is_none() => {
+                        Cow::Owned(ctype)
+                    }
+                    _ => ctype,
+                };
+                (Some(entry.etag), ctype)
+            }
+            None if enc != Encoding::Identity && self.config.etag => {
+                // etag of the encoded file is the one of identity file
+                // with the encoding appended, so they are related but
+                // are still different for each representation
+                let imeta = identity
+                    .and_then(|p| source.stat(p).ok())
+                    .filter(|m| m.is_file());
+                let etag = Etag::from_metadata(
+                    imeta.as_ref().unwrap_or(&meta),
+                    self.config.etag_strategy);
+                (Some(etag.encoded(enc)), ctype)
+            }
+            None if enc == Encoding::Identity && self.sniffs(path) => {
+                let ctype = match sniff(&mut f)? {
+                    Some(sniffed) => Cow::Borrowed(sniffed),
+                    None => ctype,
+                };
+                (None, ctype)
+            }
+            None => (None, ctype),
+        };
+        let preload = self.config.preloads.iter()
+            .position(|(p, _)| p == identity.unwrap_or(path))
+            .map(|idx| idx as u32);
+        let mut output = Output::from_open_file_with(self, enc, &meta, f,
+                                                     ctype, etag, vary)?;
+        output.set_preload(preload);
+        Ok(output)
     }
 
-    fn try_encodings(&self, base_path: &Path, ctype: &'static str)
-        -> Result<Output, io::Error>
+    fn try_encodings<S: FileSource>(&self, source: &S, base_path: &Path,
+        ctype: Cow<'static, str>, vary_accept: bool)
+        -> Result<Output<S::File>, io::Error>
     {
+        use config::MultirangeMode;
+        let identity_only = self.config.multirange == MultirangeMode::Identity
+            && matches!(self.range, Some(Range::MultipleRangesOfBytes(_)));
+        let mut skipped_variant = false;
         let path = base_path.as_os_str();
-        let mut buf = OsString::with_capacity(path.len() + 3);
-        for enc in self.encodings() {
+        let mut buf = OsString::with_capacity(path.len() + 4);
+        for enc in self.probe_encodings().iter() {
             buf.clear();
             buf.push(path);
             buf.push(enc.suffix());
             let path = Path::new(&buf);
-            match self.try_path(&path, enc, ctype) {
+            if identity_only && enc != Encoding::Identity {
+                // only check for existence, to send `Vary`
+                skipped_variant = skipped_variant || is_present(source, path);
+                continue;
+            }
+            let vary = Vary {
+                encoding: enc != Encoding::Identity || skipped_variant ||
+                    self.has_encoded_variants(source, base_path),
+                accept: vary_accept,
+            };
+            match self.try_path(source, &path, enc, ctype.clone(), vary,
+                                Some(base_path))
+            {
                 Ok(x) => return Ok(x),
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound
                 => continue,
@@ -208,12 +841,97 @@ impl Input {
         // we are looking for encodings
         Ok(Output::NotFound)
     }
+
+    /// Checks whether any file in alternate format, that wasn't tried,
+    /// exists, i.e. whether response depends on `Accept`
+    fn has_format_variants<S: FileSource>(&self, source: &S,
+        base_path: &Path)
+        -> bool
+    {
+        let mut buf = base_path.to_path_buf();
+        for (idx, (_, extension)) in self.config.formats.iter()
+            .enumerate()
+        {
+            if self.accept.contains(idx) {
+                // already tried and not found
+                continue;
+            }
+            buf.set_extension(extension);
+            if buf != base_path && is_present(source, &buf) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Checks whether any encoded file, that wasn't tried (or checked)
+    /// before identity, exists, i.e. whether response depends on
+    /// `Accept-Encoding`
+    fn has_encoded_variants<S: FileSource>(&self, source: &S,
+        base_path: &Path)
+        -> bool
+    {
+        let path = base_path.as_os_str();
+        let mut buf = OsString::with_capacity(path.len() + 4);
+        for &enc in &ENCODED {
+            if self.encodings().take_while(|&e| e != Encoding::Identity)
+                .any(|e| e == enc)
+            {
+                // already tried and not found
+                continue;
+            }
+            buf.clear();
+            buf.push(path);
+            buf.push(enc.suffix());
+            if is_present(source, Path::new(&buf)) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// `*` is stored as a single `Etag::any()`
+fn split_any(etags: &[Etag]) -> (&[Etag], bool) {
+    if etags.len() == 1 && etags[0] == Etag::any() {
+        (&[], true)
+    } else {
+        (etags, false)
+    }
+}
+
+fn content_type(path: &Path) -> Option<&'static str> {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .and_then(|x| get_mime_type_str(x))
+}
+
+/// Checks presence map of the `source` first, and `stat`s the file if
+/// the source doesn't know
+pub(crate) fn is_present<S: FileSource>(source: &S, path: &Path) -> bool {
+    source.exists(path).unwrap_or_else(|| source.stat(path).is_ok())
+}
+
+fn join_under(root: &Path, rel: &Path) -> Option<PathBuf> {
+    let mut result = root.to_path_buf();
+    for component in rel.components() {
+        match component {
+            Component::Normal(name) => result.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(result)
 }
 
 #[cfg(test)]
 mod test {
     use std::mem::size_of;
+    use std::iter::empty;
     use accept_encoding::{AcceptEncodingParser};
+    use source::ManifestEntry;
+    use source::mock::MockSource;
+    use output::HeaderIter;
     use super::*;
 
     fn send<T: Send>(_: &T) {}
@@ -225,12 +943,16 @@ mod test {
             config: Config::new().done(),
             mode: Mode::Get,
             accept_encoding: AcceptEncodingParser::new().done(),
+            transfer_encoding: AcceptEncodingParser::new().done(),
+            accept: AcceptFormats::none(),
+            ranges: true,
             range: None,
             if_range: None,
-            if_match: Vec::new(),
-            if_none: Vec::new(),
+            if_match: EtagList::new(),
+            if_none: EtagList::new(),
             if_unmodified: None,
             if_modified: None,
+            overrides: None,
         };
         send(&v);
         self_contained(&v);
@@ -240,6 +962,1163 @@ mod test {
     #[test]
     fn size() {
         assert!(size_of::<Range>() <= 24);
+        // etag lists store one etag inline, but are no larger than a `Vec`
+        assert_eq!(size_of::<EtagList>(), size_of::<Etag>());
+        // 160 bytes currently: rarely used overrides are behind a single
+        // pointer and parsed flags fit in existing padding
         assert!(size_of::<Input>() <= 176);
     }
+
+    #[test]
+    fn join_relative() {
+        let root = Path::new("/srv/www");
+        assert_eq!(join_under(root, Path::new("a/b.css")),
+            Some(PathBuf::from("/srv/www/a/b.css")));
+        assert_eq!(join_under(root, Path::new("/a/./b.css")),
+            Some(PathBuf::from("/srv/www/a/b.css")));
+        assert_eq!(join_under(root, Path::new("../secret")), None);
+        assert_eq!(join_under(root, Path::new("a/../../secret")), None);
+    }
+
+    #[test]
+    fn probe_under_root() {
+        let inp = Input::from_headers(&Config::new().done(), "GET", empty());
+        match inp.probe_file_under("public", "../Cargo.toml").unwrap() {
+            Output::NotFound => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        match inp.probe_file_under("public", "/index.html").unwrap() {
+            Output::File(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn first_root() {
+        let source = MockSource::new()
+            .file("/app/style.css", b"override")
+            .file("/base/style.css", b"base")
+            .file("/base/logo.png", b"logo");
+        let base_cfg = Config::new().cache_control("max-age=86400").done();
+        let roots = [
+            Root::new("/app"),
+            Root::with_config("/base", &base_cfg),
+        ];
+        let inp = Input::from_headers(&Config::new().done(), "GET", empty());
+        let probe = |rel: &str| {
+            source.opens.borrow_mut().clear();
+            let output = inp.probe_first_with(&source, &roots,
+                                              Path::new(rel)).unwrap();
+            let cache = match output {
+                Output::File(ref outf) => outf.headers()
+                    .any(|(name, _)| name == "Cache-Control"),
+                Output::NotFound => return None,
+                ref x => panic!("unexpected output {:?}", x),
+            };
+            Some((source.opens.borrow().clone(), cache))
+        };
+        assert_eq!(probe("/style.css"),
+                   Some((vec![PathBuf::from("/app/style.css")], false)));
+        assert_eq!(probe("/logo.png"),
+                   Some((vec![PathBuf::from("/base/logo.png")], true)));
+        assert_eq!(probe("/../base/logo.png"), None);
+        assert_eq!(probe("/missing.css"), None);
+    }
+
+    #[test]
+    fn dotfile_allow_list() {
+        use std::env::temp_dir;
+        use std::fs::{File, create_dir_all, remove_dir_all};
+        use std::process;
+        use config::DotfilePolicy;
+
+        let root = temp_dir().join(format!("http-file-headers-{}-dotfiles",
+                                           process::id()));
+        create_dir_all(root.join(".well-known/acme-challenge")).unwrap();
+        File::create(root.join(".well-known/acme-challenge/x")).unwrap();
+        File::create(root.join(".env")).unwrap();
+
+        let open = Input::from_headers(&Config::new().done(), "GET", empty());
+        let cfg = Config::new()
+            .dotfile_policy(DotfilePolicy::allow_list([".well-known"]))
+            .done();
+        let inp = Input::from_headers(&cfg, "GET", empty());
+        let challenge = inp.probe_file_under(&root,
+                                             "/.well-known/acme-challenge/x");
+        let env = inp.probe_file_under(&root, "/.env");
+        let env_open = open.probe_file_under(&root, "/.env");
+        let cfg = Config::new().dotfile_policy(DotfilePolicy::Deny).done();
+        let inp = Input::from_headers(&cfg, "GET", empty());
+        let denied = inp.probe_file_under(&root,
+                                          ".well-known/acme-challenge/x");
+        remove_dir_all(&root).unwrap();
+
+        match challenge.unwrap() {
+            Output::File(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        match env.unwrap() {
+            Output::NotFound => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        match env_open.unwrap() {
+            Output::File(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        match denied.unwrap() {
+            Output::NotFound => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        assert!(!DotfilePolicy::allow_list([".well-known"])
+                .allows(Path::new("a/.well-known/x")));
+    }
+
+    #[test]
+    fn symlinked_variants() {
+        use config::SymlinkPolicy;
+
+        let source = MockSource::new()
+            .symlink("/www/app.js", b"app()")
+            .symlink("/www/app.js.gz", b"compressed")
+            .file("/www/style.css", b"body {}");
+        let probe = |policy: SymlinkPolicy, accept: &[u8], path: &str| {
+            let cfg = Config::new().symlink_policy(policy).done();
+            let headers = vec![("Accept-Encoding", accept)];
+            let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+            inp.probe_with(&source, path).unwrap().encoding()
+        };
+        let policy = || SymlinkPolicy::custom(|path, encoding| {
+            assert!(path.starts_with("/www"));
+            encoding != Encoding::Identity
+        });
+        assert_eq!(probe(policy(), b"gzip", "/www/app.js"),
+                   Some(Encoding::Gzip));
+        assert_eq!(probe(policy(), b"", "/www/app.js"), None);
+        assert_eq!(probe(policy(), b"", "/www/style.css"),
+                   Some(Encoding::Identity));
+        assert_eq!(probe(SymlinkPolicy::Deny, b"gzip", "/www/app.js"), None);
+        assert_eq!(probe(SymlinkPolicy::Follow, b"", "/www/app.js"),
+                   Some(Encoding::Identity));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_on_disk() {
+        use std::env::temp_dir;
+        use std::fs::{File, create_dir_all, remove_dir_all};
+        use std::os::unix::fs::symlink;
+        use std::process;
+        use config::SymlinkPolicy;
+
+        let root = temp_dir().join(format!("http-file-headers-{}-symlinks",
+                                           process::id()));
+        create_dir_all(&root).unwrap();
+        File::create(root.join("blob")).unwrap();
+        symlink(root.join("blob"), root.join("link.txt")).unwrap();
+
+        let cfg = Config::new().symlink_policy(SymlinkPolicy::Deny).done();
+        let inp = Input::from_headers(&cfg, "GET", empty());
+        let link = inp.probe_file_under(&root, "link.txt");
+        let blob = inp.probe_file_under(&root, "blob");
+        remove_dir_all(&root).unwrap();
+
+        match link.unwrap() {
+            Output::NotFound => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        match blob.unwrap() {
+            Output::File(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn negotiate_without_opening() {
+        let source = MockSource::new()
+            .file("/www/app.js", b"app()")
+            .file("/www/app.js.br", b"compressed");
+        let headers = vec![("Accept-Encoding", &b"gzip, br"[..])];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        assert_eq!(inp.negotiate(&source, "/www/app.js"),
+                   Some(Encoding::Brotli));
+        assert_eq!(inp.negotiate(&source, "/www/other.js"), None);
+        assert_eq!(source.opens.borrow().len(), 0);
+
+        let inp = Input::from_headers(&Config::new().done(), "GET", empty());
+        assert_eq!(inp.negotiate(&source, "/www/app.js"),
+                   Some(Encoding::Identity));
+    }
+
+    #[test]
+    fn negotiate_index() {
+        let source = MockSource::new()
+            .dir("/www")
+            .file("/www/index.html", b"hello")
+            .file("/www/index.html.gz", b"compressed");
+        let headers = vec![("Accept-Encoding", &b"gzip, br"[..])];
+        let cfg = Config::new().add_index_file("index.html").done();
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        assert_eq!(inp.negotiate(&source, "/www"), Some(Encoding::Gzip));
+        let inp = Input::from_headers(&Config::new().done(), "GET", empty());
+        assert_eq!(inp.negotiate(&source, "/www"), None);
+    }
+
+    fn content_type_for(inp: &Input, source: &MockSource, path: &str)
+        -> String
+    {
+        match inp.probe_with(source, path).unwrap() {
+            Output::File(outf) => outf.headers()
+                .find(|&(name, _)| name == "Content-Type")
+                .map(|(_, value)| value.to_string()).unwrap(),
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn override_content_type() {
+        let source = MockSource::new()
+            .file("/www/app.bin", b"\0asm")
+            .file("/www/page.html", b"<html>")
+            .file("/www/page.html.gz", b"compressed");
+        let headers = vec![("Accept-Encoding", &b"gzip"[..])];
+        let mut inp = Input::from_headers(&Config::new().done(), "GET",
+                                          headers.into_iter());
+        assert_eq!(content_type_for(&inp, &source, "/www/page.html"),
+                   "text/html; charset=utf-8");
+        inp.override_content_type("application/wasm");
+        assert_eq!(content_type_for(&inp, &source, "/www/app.bin"),
+                   "application/wasm");
+        // not a text file, so compressed file is not looked for
+        assert_eq!(inp.probe_with(&source, "/www/page.html").unwrap()
+                   .encoding(), Some(Encoding::Identity));
+        inp.override_content_type("text/plain");
+        assert_eq!(content_type_for(&inp, &source, "/www/page.html"),
+                   "text/plain; charset=utf-8");
+        inp.override_content_type("text/plain; charset=latin1");
+        assert_eq!(content_type_for(&inp, &source, "/www/page.html"),
+                   "text/plain; charset=latin1");
+    }
+
+    #[test]
+    fn override_last_modified() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let source = MockSource::new().file("/www/data.bin", b"data");
+        let probe = |since: &str| {
+            let headers = vec![("If-Modified-Since", since.as_bytes())];
+            let mut inp = Input::from_headers(&Config::new().done(), "GET",
+                                              headers.into_iter());
+            inp.override_last_modified(
+                UNIX_EPOCH + Duration::new(1577836800, 0));
+            match inp.probe_with(&source, "/www/data.bin").unwrap() {
+                Output::File(outf) => {
+                    all_headers(outf.headers(), outf.content_length())
+                }
+                Output::NotModified(head) => {
+                    assert_eq!(head.status(), 304);
+                    Vec::new()
+                }
+                x => panic!("unexpected output {:?}", x),
+            }
+        };
+        // file's own modification time is in 2017
+        let headers = probe("Tue, 22 Aug 2017 20:47:13 GMT");
+        assert_eq!(header(&headers, "Last-Modified"),
+                   Some("Wed, 01 Jan 2020 00:00:00 GMT"));
+        assert!(probe("Wed, 01 Jan 2020 00:00:00 GMT").is_empty());
+    }
+
+    #[test]
+    fn assume_gzip() {
+        let source = MockSource::new()
+            .file("/www/app.js", b"app()")
+            .file("/www/app.js.gz", b"compressed");
+        let encoding = |cfg: &Config, headers: Vec<(&str, &[u8])>| {
+            let inp = Input::from_headers(&cfg.done(), "GET",
+                                          headers.into_iter());
+            inp.probe_with(&source, "/www/app.js").unwrap().encoding()
+        };
+        let mut cfg = Config::new();
+        assert_eq!(encoding(&cfg, vec![]), Some(Encoding::Identity));
+        cfg.assume_gzip_when_unspecified();
+        assert_eq!(encoding(&cfg, vec![]), Some(Encoding::Gzip));
+        assert_eq!(encoding(&cfg, vec![("Accept-Encoding", b"identity")]),
+                   Some(Encoding::Identity));
+    }
+
+    #[test]
+    fn method_enum() {
+        let cfg = Config::new().done();
+        let headers = [
+            ("Accept-Encoding", &b"gzip, br"[..]),
+            ("Range", b"bytes=0-10"),
+            ("If-None-Match", b"W/\"tYJT9KJUI0KX2I5q\""),
+            ("If-Modified-Since", b"Tue, 22 Aug 2017 20:47:13 GMT"),
+        ];
+        let by_name = Input::from_headers(&cfg, "GET",
+                                          headers.iter().cloned());
+        let by_enum = Input::from_headers_method(&cfg, Method::Get,
+                                                 headers.iter().cloned());
+        assert_eq!(format!("{:?}", by_name), format!("{:?}", by_enum));
+        assert_eq!(by_enum.mode, Mode::Get);
+        assert_eq!(Input::from_headers_method(&cfg, Method::Head,
+                   empty()).mode, Mode::Head);
+        assert_eq!(Input::from_headers_method(&cfg, Method::Options,
+                   empty()).mode, Mode::Options);
+        assert_eq!(Input::from_headers_method(&cfg, Method::Other,
+                   headers.iter().cloned()).mode, Mode::InvalidMethod);
+    }
+
+    #[test]
+    fn range_set() {
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+            vec![("Range", &b"bytes=0-9, 20-"[..])].into_iter());
+        let ranges = inp.range().unwrap();
+        assert!(ranges.is_multipart());
+        assert_eq!(ranges.iter(25).collect::<Vec<_>>(),
+                   vec![(0, 9), (20, 24)]);
+        let inp = Input::from_headers(&Config::new().done(), "GET", empty());
+        assert!(inp.range().is_none());
+    }
+
+    #[test]
+    fn conditionals_view() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let headers = vec![
+            ("If-None-Match", &b"\"v1\", W/\"v2\""[..]),
+            ("If-Modified-Since", &b"Tue, 22 Aug 2017 20:47:13 GMT"[..]),
+        ];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        let cond = inp.conditionals();
+        assert_eq!(cond.if_none_match,
+                   &[Etag::opaque("v1"), Etag::opaque("W/\"v2\"")][..]);
+        assert!(!cond.if_none_match_any);
+        assert_eq!(cond.if_modified_since,
+                   Some(UNIX_EPOCH + Duration::new(1503434833, 0)));
+        assert!(cond.if_match.is_empty() && !cond.if_match_any);
+        assert_eq!(cond.if_unmodified_since, None);
+        assert_eq!(cond.if_range_date, None);
+        assert_eq!(cond.if_range_etag, None);
+
+        let headers = vec![("If-None-Match", &b"*"[..])];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        assert!(inp.conditionals().if_none_match_any);
+        assert!(inp.conditionals().if_none_match.is_empty());
+        let source = MockSource::new().file("/www/a.txt", b"hello");
+        match inp.probe_with(&source, "/www/a.txt").unwrap() {
+            Output::NotModified(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn allowed_encodings() {
+        let source = MockSource::new()
+            .file("/www/app.js", b"app()")
+            .file("/www/app.js.br", b"compressed");
+        let headers = vec![("Accept-Encoding", &b"br, gzip"[..])];
+        let mut inp = Input::from_headers(&Config::new().done(), "GET",
+                                          headers.into_iter());
+        inp.allow_encodings(&[Encoding::Gzip]);
+        assert_eq!(inp.encodings().collect::<Vec<_>>(),
+                   vec![Encoding::Gzip, Encoding::Identity]);
+        assert_eq!(inp.probe_with(&source, "/www/app.js").unwrap()
+                   .encoding(), Some(Encoding::Identity));
+        assert_eq!(*source.opens.borrow(), vec![
+            PathBuf::from("/www/app.js.gz"),
+            PathBuf::from("/www/app.js"),
+        ]);
+    }
+
+    #[test]
+    fn header_limits() {
+        let source = MockSource::new()
+            .file("/www/app.js", b"app()")
+            .file("/www/app.js.gz", b"compressed");
+        let cfg = Config::new()
+            .max_accept_encoding_length(8)
+            .max_range_length(10)
+            .done();
+        let probe = |name: &str, value: &[u8]| {
+            let inp = Input::from_headers(&cfg, "GET",
+                                          vec![(name, value)].into_iter());
+            inp.probe_with(&source, "/www/app.js").unwrap()
+        };
+        assert_eq!(probe("Accept-Encoding", b"gzip").encoding(),
+                   Some(Encoding::Gzip));
+        assert_eq!(probe("Accept-Encoding", b"gzip, br;q=0.9").encoding(),
+                   Some(Encoding::Identity));
+        assert_eq!(probe("Range", b"bytes=0-1").content_range(),
+                   Some((0, 1, 5)));
+        match probe("Range", b"bytes=0-1,3-4") {
+            Output::InvalidRange => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn probe_order() {
+        let source = MockSource::new()
+            .file("/www/app.js", b"app()")
+            .file("/www/app.js.gz", b"compressed")
+            .file("/www/app.js.br", b"compressed");
+        let headers = vec![("Accept-Encoding", &b"gzip, br;q=0.9"[..])];
+        let cfg = Config::new()
+            .probe_order(&[Encoding::Brotli, Encoding::Gzip])
+            .done();
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        assert_eq!(inp.encodings().collect::<Vec<_>>(),
+            vec![Encoding::Gzip, Encoding::Brotli, Encoding::Identity]);
+        assert_eq!(inp.negotiate(&source, "/www/app.js"),
+                   Some(Encoding::Brotli));
+        match inp.probe_with(&source, "/www/app.js").unwrap() {
+            Output::File(outf) => {
+                assert_eq!(outf.encoding(), Encoding::Brotli);
+                assert!(outf.headers().any(|(name, value)| {
+                    name == "Content-Encoding" && value.to_string() == "br"
+                }));
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        assert_eq!(*source.opens.borrow(),
+                   vec![PathBuf::from("/www/app.js.br")]);
+    }
+
+    #[test]
+    fn identity_suffix() {
+        assert_eq!(Encoding::Identity.suffix(), "");
+        let source = MockSource::new().file("/www/app.min.js", b"app()");
+        let probe = |accept_encoding: &[u8]| {
+            let headers = vec![("Accept-Encoding", accept_encoding)];
+            let inp = Input::from_headers(&Config::new().done(), "GET",
+                                          headers.into_iter());
+            source.opens.borrow_mut().clear();
+            let enc = inp.probe_with(&source, "/www/app.min.js").unwrap()
+                .encoding();
+            (enc, source.opens.borrow().clone())
+        };
+        // identity candidate is the path itself, and it's tried last
+        assert_eq!(probe(b"gzip, identity;q=1, br"),
+            (Some(Encoding::Identity), vec![
+                PathBuf::from("/www/app.min.js.br"),
+                PathBuf::from("/www/app.min.js.gz"),
+                PathBuf::from("/www/app.min.js"),
+            ]));
+        // unless identity is excluded, then nothing acceptable is found
+        assert_eq!(probe(b"gzip, identity;q=0"), (None, vec![
+                PathBuf::from("/www/app.min.js.gz"),
+            ]));
+        assert_eq!(probe(b"gzip, *;q=0"), (None, vec![
+                PathBuf::from("/www/app.min.js.gz"),
+            ]));
+    }
+
+    #[test]
+    fn identity_only() {
+        let source = MockSource::new()
+            .file("/www/app.js", b"function app() { return 1; }")
+            .file("/www/app.js.gz", b"gz")
+            .file("/www/app.js.br", b"br");
+        let input = |accept_encoding: &[u8]| {
+            let headers = vec![("Accept-Encoding", accept_encoding)];
+            Input::from_headers(&Config::new().done(), "GET",
+                                headers.into_iter())
+        };
+        assert_eq!(input(b"identity").encodings().collect::<Vec<_>>(),
+                   vec![Encoding::Identity]);
+        assert_eq!(input(b"identity, gzip").encodings().collect::<Vec<_>>(),
+                   vec![Encoding::Gzip, Encoding::Identity]);
+
+        let mut body = Vec::new();
+        match input(b"identity").probe_with(&source, "/www/app.js").unwrap() {
+            Output::File(mut outf) => {
+                assert_eq!(outf.encoding(), Encoding::Identity);
+                assert!(!outf.headers()
+                        .any(|(name, _)| name == "Content-Encoding"));
+                while outf.read_chunk(&mut body).unwrap() > 0 {}
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        assert_eq!(body, b"function app() { return 1; }");
+    }
+
+    #[test]
+    fn compound_extension() {
+        let source = MockSource::new()
+            .file("/www/logo.svgz", b"compressed svg")
+            .file("/www/logo.svgz.gz", b"twice")
+            .file("/www/font.woff2", b"font");
+        let headers = |cfg: &mut Config, path: &str| {
+            let cfg = cfg.encodings_on_all_files().done();
+            let inp = Input::from_headers(&cfg, "GET",
+                vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+            match inp.probe_with(&source, path).unwrap() {
+                Output::File(outf) => {
+                    all_headers(outf.headers(), outf.content_length())
+                    .into_iter()
+                    .filter(|(name, _)| name.starts_with("Content-"))
+                    .collect::<Vec<_>>()
+                }
+                x => panic!("unexpected output {:?}", x),
+            }
+        };
+        assert_eq!(headers(&mut Config::new(), "/www/logo.svgz"), vec![
+            (String::from("Content-Encoding"), String::from("gzip")),
+            (String::from("Content-Type"), String::from("image/svg+xml")),
+            (String::from("Content-Length"), String::from("14")),
+        ]);
+        let mut cfg = Config::new();
+        cfg.add_compound_extension(".woff2", "font/woff2", Encoding::Brotli);
+        assert_eq!(headers(&mut cfg, "/www/font.woff2")[0],
+            (String::from("Content-Encoding"), String::from("br")));
+        let mut cfg = Config::new();
+        cfg.no_compound_extensions();
+        assert_eq!(headers(&mut cfg, "/www/logo.svgz")[0],
+            (String::from("Content-Encoding"), String::from("gzip")));
+        assert_eq!(headers(&mut cfg, "/www/logo.svgz")[2],
+            (String::from("Content-Length"), String::from("5")));
+    }
+
+    #[test]
+    fn presence_map() {
+        let source = MockSource::new()
+            .file("/www/app.js", b"app()")
+            .file("/www/app.js.br", b"compressed")
+            .presence_map();
+        let headers = vec![("Accept-Encoding", &b"gzip"[..])];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        match inp.probe_with(&source, "/www/app.js").unwrap() {
+            Output::File(outf) => {
+                assert_eq!(outf.encoding(), Encoding::Identity);
+                // the `.br` file exists, so response depends on encoding
+                assert!(outf.headers().any(|(name, _)| name == "Vary"));
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        assert_eq!(*source.opens.borrow(), vec![PathBuf::from("/www/app.js")]);
+        assert_eq!(*source.stats.borrow(), vec![PathBuf::from("/www/app.js")]);
+    }
+
+    #[test]
+    fn missing_file_syscalls() {
+        // encoded variants without the identity file are never served, so
+        // a single `stat` is enough to answer 404
+        let source = MockSource::new().file("/www/app.js.gz", b"compressed");
+        let headers = vec![("Accept-Encoding", &b"br, gzip"[..])];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        match inp.probe_with(&source, "/www/app.js").unwrap() {
+            Output::NotFound => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        assert_eq!(*source.stats.borrow(), vec![PathBuf::from("/www/app.js")]);
+        assert_eq!(source.opens.borrow().len(), 0);
+
+        // same for each index file of a directory
+        let source = MockSource::new()
+            .dir("/www")
+            .file("/www/index.html.gz", b"compressed")
+            .file("/www/index.htm.br", b"compressed");
+        let cfg = Config::new()
+            .add_index_file("index.html")
+            .add_index_file("index.htm")
+            .done();
+        let headers = vec![("Accept-Encoding", &b"br, gzip"[..])];
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        match inp.probe_with(&source, "/www").unwrap() {
+            Output::Directory => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        assert_eq!(*source.stats.borrow(), vec![
+            PathBuf::from("/www"),
+            PathBuf::from("/www/index.html"),
+            PathBuf::from("/www/index.htm"),
+        ]);
+        assert_eq!(source.opens.borrow().len(), 0);
+        assert_eq!(inp.negotiate(&source, "/www"), None);
+    }
+
+    #[test]
+    fn encoded_index() {
+        let source = MockSource::new()
+            .dir("/www")
+            .file("/www/index.htm", b"hello")
+            .file("/www/index.htm.br", b"compressed");
+        let headers = vec![("Accept-Encoding", &b"br"[..])];
+        let cfg = Config::new()
+            .add_index_file("index.html")
+            .add_index_file("index.htm")
+            .done();
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        assert_eq!(inp.probe_with(&source, "/www").unwrap().encoding(),
+                   Some(Encoding::Brotli));
+        assert_eq!(*source.opens.borrow(), vec![
+            PathBuf::from("/www/index.htm.br"),
+        ]);
+    }
+
+    #[test]
+    fn missing_index() {
+        let source = MockSource::new().dir("/www");
+        let cfg = Config::new().add_index_file("index.html").done();
+        let inp = Input::from_headers(&cfg, "GET", empty());
+        match inp.probe_with(&source, "/www").unwrap() {
+            Output::Directory => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn te_is_not_content_coding() {
+        let headers = vec![("TE", &b"trailers, gzip, br;q=0.5"[..])];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        assert_eq!(inp.encodings().collect::<Vec<_>>(),
+                   vec![Encoding::Identity]);
+        assert_eq!(inp.transfer_encodings().collect::<Vec<_>>(),
+                   vec![Encoding::Gzip, Encoding::Brotli, Encoding::Identity]);
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::File(outf) => {
+                assert!(!outf.headers()
+                    .any(|(name, _)| name == "Content-Encoding"));
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn owned_header_pairs() {
+        let cfg = Config::new().done();
+        let owned = vec![
+            (String::from("Accept-Encoding"), b"gzip, br".to_vec()),
+            (String::from("Range"), b"bytes=10-20".to_vec()),
+            (String::from("If-Modified-Since"),
+             b"Tue, 22 Aug 2017 20:47:13 GMT".to_vec()),
+            (String::from("If-None-Match"),
+             br#"W/"tYJT9KJUI0KX2I5q""#.to_vec()),
+        ];
+        let borrowed = Input::from_headers(&cfg, "GET", owned.iter()
+            .map(|(k, v)| (&k[..], &v[..])));
+        let inp = Input::from_header_pairs(&cfg, "GET", owned);
+        assert_eq!(inp.encodings().collect::<Vec<_>>(),
+                   vec![Encoding::Brotli, Encoding::Gzip, Encoding::Identity]);
+        assert_eq!(inp.encodings().collect::<Vec<_>>(),
+                   borrowed.encodings().collect::<Vec<_>>());
+        assert_eq!(inp.range, borrowed.range);
+        assert!(inp.range.is_some());
+        assert_eq!(inp.if_modified, borrowed.if_modified);
+        assert!(inp.if_modified.is_some());
+        assert_eq!(inp.if_none, borrowed.if_none);
+        assert_eq!(inp.if_none.len(), 1);
+    }
+
+    #[test]
+    fn manifest_etag() {
+        let source = MockSource::new()
+            .file("/www/app.js", b"hello")
+            .manifest("/www/app.js", ManifestEntry::new("a1b2c3")
+                      .content_type("text/javascript"));
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET", empty());
+        match inp.probe_with(&source, "/www/app.js").unwrap() {
+            Output::File(outf) => {
+                let headers = outf.headers()
+                    .map(|(name, value)| (name, value.to_string()))
+                    .collect::<Vec<_>>();
+                assert!(headers.contains(
+                    &("ETag", String::from(r#""a1b2c3""#))));
+                assert!(headers.contains(&("Content-Type",
+                    String::from("text/javascript; charset=utf-8"))));
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+
+        let headers = vec![("If-None-Match", &br#"W/"a1b2c3""#[..])];
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        match inp.probe_with(&source, "/www/app.js").unwrap() {
+            Output::NotModified(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    fn vary_of(source: &MockSource, path: &str, accept: &str)
+        -> Option<String>
+    {
+        let headers = vec![("Accept-Encoding", accept.as_bytes())];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        match inp.probe_with(source, path).unwrap() {
+            Output::File(outf) => outf.headers()
+                .find(|&(name, _)| name == "Vary")
+                .map(|(_, value)| value.to_string()),
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn vary_only_with_variants() {
+        let source = MockSource::new()
+            .file("/www/plain.html", b"hello")
+            .file("/www/app.js", b"hello")
+            .file("/www/app.js.gz", b"compressed");
+        assert_eq!(vary_of(&source, "/www/plain.html", "gzip, br"), None);
+        assert_eq!(vary_of(&source, "/www/plain.html", "identity"), None);
+        assert_eq!(vary_of(&source, "/www/app.js", "gzip"),
+                   Some(String::from("Accept-Encoding")));
+        // identity is served, but response would differ for other clients
+        assert_eq!(vary_of(&source, "/www/app.js", "br"),
+                   Some(String::from("Accept-Encoding")));
+        assert_eq!(vary_of(&source, "/www/app.js", "identity"),
+                   Some(String::from("Accept-Encoding")));
+    }
+
+    fn content_type_with(cfg: &Config, path: &str) -> String {
+        let source = MockSource::new().file(path, b"hello");
+        let inp = Input::from_headers(&cfg.done(), "GET", empty());
+        match inp.probe_with(&source, path).unwrap() {
+            Output::File(outf) => outf.headers()
+                .find(|&(name, _)| name == "Content-Type")
+                .map(|(_, value)| value.to_string()).unwrap(),
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn unknown_extension() {
+        assert_eq!(content_type_with(&Config::new(), "/www/notes.unknownext"),
+                   "application/octet-stream");
+        assert_eq!(content_type_with(&Config::new(), "/www/LICENSE"),
+                   "application/octet-stream");
+        assert_eq!(content_type_with(&Config::new(), "/www/a.html"),
+                   "text/html; charset=utf-8");
+    }
+
+    #[test]
+    fn unknown_as_text() {
+        let mut cfg = Config::new();
+        cfg.unknown_as_text();
+        assert_eq!(content_type_with(&cfg, "/www/notes.unknownext"),
+                   "text/plain; charset=utf-8");
+        assert_eq!(content_type_with(&cfg, "/www/LICENSE"),
+                   "text/plain; charset=utf-8");
+        // known types are not affected
+        assert_eq!(content_type_with(&cfg, "/www/a.png"), "image/png");
+    }
+
+    #[test]
+    fn method_case() {
+        let strict = Config::new().done();
+        assert_eq!(Input::from_headers(&strict, "GET", empty()).mode,
+                   Mode::Get);
+        assert_eq!(Input::from_headers(&strict, "get", empty()).mode,
+                   Mode::InvalidMethod);
+        assert_eq!(Input::from_headers(&strict, "Head", empty()).mode,
+                   Mode::InvalidMethod);
+        let lenient = Config::new().lenient_method_case().done();
+        assert_eq!(Input::from_headers(&lenient, "get", empty()).mode,
+                   Mode::Get);
+        assert_eq!(Input::from_headers(&lenient, "Head", empty()).mode,
+                   Mode::Head);
+        assert_eq!(Input::from_headers(&lenient, "POST", empty()).mode,
+                   Mode::InvalidMethod);
+    }
+
+    #[test]
+    fn options_asterisk() {
+        let source = MockSource::new().file("/www/a.html", b"hello");
+        let cfg = Config::new().done();
+        let headers = vec![("Range", &b"invalid"[..])];
+        let inp = Input::from_headers(&cfg, "OPTIONS", headers.into_iter());
+        match inp.probe_with(&source, "*").unwrap() {
+            Output::Options => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        assert_eq!(source.stats.borrow().len(), 0);
+        assert_eq!(source.opens.borrow().len(), 0);
+        match inp.probe_file_under("/nonexistent", "*").unwrap() {
+            Output::Options => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+
+        match inp.probe_with(&source, "/www/a.html").unwrap() {
+            Output::Options => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        match inp.probe_with(&source, "/www/b.html").unwrap() {
+            Output::NotFound => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        assert_eq!(source.opens.borrow().len(), 0);
+
+        let inp = Input::from_headers(&cfg, "GET", empty());
+        match inp.probe_with(&source, "*").unwrap() {
+            Output::NotFound => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        assert_eq!(source.stats.borrow().len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn world_writable() {
+        use std::env::temp_dir;
+        use std::fs::{File, Permissions, set_permissions, remove_file};
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+        use std::process;
+        use config::PermissionPolicy;
+
+        let path = temp_dir().join(format!("http-file-headers-{}.txt",
+                                           process::id()));
+        File::create(&path).unwrap().write_all(b"hello").unwrap();
+        set_permissions(&path, Permissions::from_mode(0o666)).unwrap();
+
+        let inp = Input::from_headers(&Config::new().done(), "GET", empty());
+        let permissive = inp.probe_file(&path);
+        let cfg = Config::new()
+            .permission_policy(PermissionPolicy::RefuseWorldWritable)
+            .done();
+        let inp = Input::from_headers(&cfg, "GET", empty());
+        let strict = inp.probe_file(&path);
+        set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        let strict_safe = inp.probe_file(&path);
+        remove_file(&path).unwrap();
+
+        match permissive.unwrap() {
+            Output::File(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        match strict.unwrap() {
+            Output::NotFound => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        match strict_safe.unwrap() {
+            Output::File(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    fn multirange_headers(cfg: &Config) -> Vec<(String, String)> {
+        let source = MockSource::new()
+            .file("/www/app.js", b"hello world")
+            .file("/www/app.js.gz", b"compressed data");
+        let headers = vec![
+            ("Accept-Encoding", &b"gzip"[..]),
+            ("Range", &b"bytes=0-1,5-7"[..]),
+        ];
+        let inp = Input::from_headers(&cfg.done(), "GET",
+                                      headers.into_iter());
+        match inp.probe_with(&source, "/www/app.js").unwrap() {
+            Output::File(outf) => {
+                let mut headers = outf.headers()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect::<Vec<_>>();
+                headers.push((String::from("Content-Length"),
+                              outf.content_length().to_string()));
+                headers
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    fn header<'x>(headers: &'x [(String, String)], name: &str)
+        -> Option<&'x str>
+    {
+        headers.iter().find(|(n, _)| n == name)
+            .map(|(_, v)| &v[..])
+    }
+
+    #[test]
+    fn multirange_identity() {
+        let headers = multirange_headers(&Config::new());
+        assert_eq!(header(&headers, "Content-Encoding"), None);
+        assert!(header(&headers, "Content-Type").unwrap()
+                .starts_with("multipart/byteranges"));
+        assert_eq!(header(&headers, "Accept-Ranges"), Some("bytes"));
+        assert_eq!(header(&headers, "Vary"), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn multirange_full_encoded() {
+        let headers = multirange_headers(
+            Config::new().multirange_full_encoded());
+        assert_eq!(header(&headers, "Content-Encoding"), Some("gzip"));
+        assert_eq!(header(&headers, "Content-Type"),
+                   Some("application/javascript; charset=utf-8"));
+        assert_eq!(header(&headers, "Content-Range"), None);
+        assert_eq!(header(&headers, "Accept-Ranges"), Some("none"));
+        assert_eq!(header(&headers, "Content-Length"), Some("15"));
+    }
+
+    #[test]
+    fn options_ignore_range() {
+        let cfg = Config::new().done();
+        for range in &[&b"bytes=0-10"[..], &b"bytes=10-0"[..], &b"xx"[..]] {
+            let headers = vec![("Range", *range)];
+            let inp = Input::from_headers(&cfg, "OPTIONS",
+                                          headers.into_iter());
+            assert_eq!(inp.mode, Mode::Options);
+            assert_eq!(inp.range, None);
+        }
+        let headers = vec![("Range", &b"xx"[..])];
+        let inp = Input::from_headers(&cfg, "HEAD", headers.into_iter());
+        assert_eq!(inp.mode, Mode::InvalidRange);
+    }
+
+    fn negotiate(accept: Option<&str>) -> (String, Vec<(String, String)>) {
+        let source = MockSource::new()
+            .file("/www/photo.jpg", b"jpeg data")
+            .file("/www/photo.webp", b"webp data");
+        let mut cfg = Config::new();
+        cfg.add_format("image/avif", "avif").add_format("image/webp", ".webp");
+        let headers = accept.map(|x| ("Accept", x.as_bytes()));
+        let inp = Input::from_headers(&cfg.done(), "GET",
+                                      headers.into_iter());
+        match inp.probe_with(&source, "/www/photo.jpg").unwrap() {
+            Output::File(mut outf) => {
+                let headers = outf.headers()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect::<Vec<_>>();
+                let mut body = Vec::new();
+                while outf.read_chunk(&mut body).unwrap() > 0 {}
+                (String::from_utf8(body).unwrap(), headers)
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn accept_alternate_format() {
+        let (body, headers) = negotiate(
+            Some("image/avif,image/webp,*/*;q=0.8"));
+        assert_eq!(body, "webp data");
+        assert_eq!(header(&headers, "Content-Type"), Some("image/webp"));
+        assert_eq!(header(&headers, "Vary"), Some("Accept"));
+
+        let (body, headers) = negotiate(None);
+        assert_eq!(body, "jpeg data");
+        assert_eq!(header(&headers, "Content-Type"), Some("image/jpeg"));
+        assert_eq!(header(&headers, "Vary"), Some("Accept"));
+
+        let (body, _) = negotiate(Some("image/webp;q=0"));
+        assert_eq!(body, "jpeg data");
+    }
+
+    fn all_headers(headers: HeaderIter, content_length: u64)
+        -> Vec<(String, String)>
+    {
+        let mut headers = headers
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+        headers.push((String::from("Content-Length"),
+                      content_length.to_string()));
+        headers
+    }
+
+    #[test]
+    fn preload_links() {
+        let source = MockSource::new()
+            .file("/www/index.html", b"<html>")
+            .file("/www/index.html.gz", b"compressed")
+            .file("/www/app.js", b"app()");
+        let cfg = Config::new()
+            .add_preload("/www/index.html", "/app.js", "script")
+            .add_preload("/www/index.html", "/style.css", "style")
+            .done();
+        let links = |path: &str, accept: &str| {
+            let headers = vec![("Accept-Encoding", accept.as_bytes())];
+            let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+            match inp.probe_with(&source, path).unwrap() {
+                Output::File(outf) => {
+                    all_headers(outf.headers(), outf.content_length())
+                    .into_iter()
+                    .filter(|(name, _)| name == "Link")
+                    .map(|(_, value)| value)
+                    .collect::<Vec<_>>()
+                }
+                x => panic!("unexpected output {:?}", x),
+            }
+        };
+        let expected = vec![
+            String::from("</app.js>; rel=preload; as=script"),
+            String::from("</style.css>; rel=preload; as=style"),
+        ];
+        assert_eq!(links("/www/index.html", ""), expected);
+        assert_eq!(links("/www/index.html", "gzip"), expected);
+        assert!(links("/www/app.js", "").is_empty());
+    }
+
+    #[test]
+    fn early_hints() {
+        let source = MockSource::new()
+            .file("/www/index.html", b"<html>")
+            .file("/www/app.js", b"app()");
+        let cfg = Config::new()
+            .add_preload("/www/index.html", "/app.js", "script")
+            .done();
+        let probe = |method: &str, path: &str| {
+            let inp = Input::from_headers(&cfg, method, empty());
+            inp.probe_with(&source, path).unwrap()
+        };
+        let hints: &[(&str, &[u8])] = &[
+            ("Link", b"</app.js>; rel=preload; as=script"),
+        ];
+        assert_eq!(probe("GET", "/www/index.html").early_hints().unwrap(),
+                   hints);
+        assert_eq!(probe("HEAD", "/www/index.html").early_hints().unwrap(),
+                   hints);
+        assert!(probe("GET", "/www/app.js").early_hints().is_none());
+        assert!(probe("GET", "/www/none").early_hints().is_none());
+    }
+
+    #[test]
+    fn sniff_png() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let source = MockSource::new()
+            .file("/www/logo", png)
+            .file("/www/notes", b"just text");
+        let cfg = Config::new().sniff_content_type(true).done();
+        let inp = Input::from_headers(&cfg, "GET", empty());
+        let mut body = Vec::new();
+        match inp.probe_with(&source, "/www/logo").unwrap() {
+            Output::File(mut outf) => {
+                let headers = all_headers(outf.headers(),
+                                          outf.content_length());
+                assert_eq!(header(&headers, "Content-Type"),
+                           Some("image/png"));
+                assert_eq!(header(&headers, "Content-Length"), Some("16"));
+                while outf.read_chunk(&mut body).unwrap() > 0 {}
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        assert_eq!(body, &png[..]);
+        match inp.probe_with(&source, "/www/notes").unwrap() {
+            Output::File(outf) => {
+                let headers = all_headers(outf.headers(),
+                                          outf.content_length());
+                assert_eq!(header(&headers, "Content-Type"),
+                           Some("application/octet-stream"));
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn metadata_error() {
+        let source = MockSource::new()
+            .file("/www/app.js", b"app()")
+            .broken_file("/www/app.js.gz", b"compressed");
+        let headers = vec![("Accept-Encoding", &b"gzip"[..])];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        let err = inp.probe_with(&source, "/www/app.js").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(*source.opens.borrow(),
+                   vec![PathBuf::from("/www/app.js.gz")]);
+    }
+
+    #[test]
+    fn head_as_get() {
+        let source = MockSource::new().file("/www/a.txt", b"hello world");
+        let cfg = Config::new().done();
+        let probe = |method: &str, as_get: bool| {
+            let headers = vec![("Range", &b"bytes=0-4"[..])];
+            let mut inp = Input::from_headers(&cfg, method,
+                                              headers.into_iter());
+            if as_get {
+                inp.head_as_get();
+            }
+            inp.probe_with(&source, "/www/a.txt").unwrap()
+        };
+        let get = match probe("GET", false) {
+            Output::File(outf) => {
+                all_headers(outf.headers(), outf.content_length())
+            }
+            x => panic!("unexpected output {:?}", x),
+        };
+        match probe("HEAD", false) {
+            Output::FileHead(head) => {
+                assert_eq!(all_headers(head.headers(), head.content_length()),
+                           get);
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        match probe("HEAD", true) {
+            Output::File(outf) => {
+                assert_eq!(all_headers(outf.headers(), outf.content_length()),
+                           get);
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn ranges_disabled() {
+        let source = MockSource::new().file("/www/a.txt", b"hello world");
+        let probe = |cfg: &Arc<Config>, disable: bool| {
+            let headers = vec![("Range", &b"bytes=0-4"[..])];
+            let mut inp = Input::from_headers(cfg, "GET",
+                                              headers.into_iter());
+            if disable {
+                inp.disable_ranges();
+            }
+            match inp.probe_with(&source, "/www/a.txt").unwrap() {
+                Output::File(outf) => {
+                    assert_eq!(outf.status(), 200);
+                    assert_eq!(outf.content_length(), 11);
+                    all_headers(outf.headers(), outf.content_length())
+                }
+                x => panic!("unexpected output {:?}", x),
+            }
+        };
+        let accept_ranges = (String::from("Accept-Ranges"),
+                             String::from("none"));
+        let headers = probe(&Config::new().ranges(false).done(), false);
+        assert!(headers.contains(&accept_ranges));
+        assert_eq!(probe(&Config::new().done(), true), headers);
+    }
+
+    fn etag_of(source: &MockSource, headers: Vec<(&str, &[u8])>)
+        -> Result<String, u16>
+    {
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        match inp.probe_with(source, "/www/app.js").unwrap() {
+            Output::File(outf) => Ok(outf.headers()
+                .find(|&(name, _)| name == "ETag")
+                .map(|(_, value)| value.to_string()).unwrap()),
+            Output::NotModified(head) => Err(head.status()),
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn etag_per_encoding() {
+        let source = MockSource::new()
+            .file("/www/app.js", b"hello")
+            .file("/www/app.js.gz", b"compressed");
+        let gzip = etag_of(&source, vec![("Accept-Encoding", b"gzip")])
+            .unwrap();
+        let identity = etag_of(&source, vec![]).unwrap();
+        assert_ne!(gzip, identity);
+        assert_eq!(gzip, format!("{}-gzip\"", &identity[..identity.len()-1]));
+
+        assert_eq!(etag_of(&source, vec![("Accept-Encoding", b"gzip"),
+                                         ("If-None-Match", gzip.as_bytes())]),
+                   Err(304));
+        assert_eq!(etag_of(&source, vec![("If-None-Match", gzip.as_bytes())]),
+                   Ok(identity.clone()));
+        assert_eq!(etag_of(&source, vec![("Accept-Encoding", b"gzip"),
+                                         ("If-None-Match",
+                                          identity.as_bytes())]),
+                   Ok(gzip.clone()));
+        assert_eq!(etag_of(&source, vec![("If-None-Match",
+                                          identity.as_bytes())]),
+                   Err(304));
+    }
 }
@@ -7,26 +7,61 @@
 //! [Crate](https://crates.io/crates/http-file-headers)
 //!
 //! [1]: https://github.com/swindon-rs/http-file-headers/tree/master/examples
+//!
+//! # Features
+//!
+//! * `std` (enabled by default) -- everything that deals with files and
+//!   dates. Without this feature the crate is `no_std` (but requires
+//!   `alloc`) and contains only the header parsers from the `parser`
+//!   module.
 #![warn(missing_docs)]
 #![warn(missing_debug_implementations)]
+#![cfg_attr(not(feature="std"), no_std)]
 
-extern crate blake2;
-extern crate byteorder;
-extern crate digest_writer;
-extern crate generic_array;
-extern crate httpdate;
-extern crate mime_guess;
-extern crate typenum;
+extern crate alloc;
+#[cfg(feature="std")] extern crate core;
+#[cfg(feature="std")] extern crate blake2;
+#[cfg(feature="std")] extern crate byteorder;
+#[cfg(feature="std")] extern crate digest_writer;
+#[cfg(feature="std")] extern crate generic_array;
+#[cfg(feature="std")] extern crate httpdate;
+#[cfg(feature="std")] #[macro_use] extern crate log;
+#[cfg(feature="std")] extern crate mime_guess;
+#[cfg(feature="std")] extern crate sha2;
+#[cfg(feature="std")] extern crate typenum;
+#[cfg(all(feature="mmap", unix))] extern crate libc;
 
-mod conditionals;
-mod config;
-mod etag;
-mod input;
-mod output;
+#[cfg(feature="std")] mod accept;
+#[cfg(feature="std")] mod body;
+#[cfg(feature="std")] mod conditionals;
+#[cfg(feature="std")] mod config;
+#[cfg(feature="std")] mod content_digest;
+#[cfg(feature="std")] mod etag;
+#[cfg(feature="std")] mod explain;
+#[cfg(feature="std")] mod input;
+#[cfg(feature="std")] mod multipart;
+#[cfg(feature="std")] mod output;
+#[cfg(feature="std")] mod sink;
+#[cfg(feature="std")] mod sniff;
+#[cfg(feature="std")] mod source;
+#[cfg(feature="std")] mod window;
+#[cfg(all(feature="mmap", unix))] mod mmap;
 mod range;
 mod accept_encoding;
 
-pub use input::Input;
-pub use config::Config;
-pub use output::{Output, Head, FileWrapper};
+pub mod parser;
+
+#[cfg(feature="std")] pub use input::{Input, Conditionals, Method, Root};
+#[cfg(feature="std")] pub use etag::Etag;
+#[cfg(feature="std")] pub use explain::Explanation;
+#[cfg(feature="std")]
+pub use config::{Config, PermissionPolicy, RangeErrorBody, DotfilePolicy};
+#[cfg(feature="std")] pub use config::{EtagStrategy, SymlinkPolicy};
+#[cfg(feature="std")]
+pub use output::{Output, Head, FileWrapper, Unsatisfiable, Revalidation};
+#[cfg(feature="std")] pub use body::{BodyStream, BodyGuard, Body, Chunk};
+#[cfg(feature="std")] pub use window::FileWindow;
+#[cfg(all(feature="mmap", unix))] pub use mmap::{MmapSource, MmapFile};
+#[cfg(feature="std")]
+pub use source::{FileSource, Filesystem, Metadata, ManifestEntry};
 pub use accept_encoding::{Encoding, Iter as EncodingIter};
@@ -0,0 +1,280 @@
+use std::cmp::min;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use libc;
+
+use source::{FileSource, Metadata};
+
+
+/// Reads never cross a multiple of this size (which is a multiple of page
+/// size), so after the first read of a range all the reads are aligned
+const CHUNK: u64 = 65536;
+
+/// Kernel is advised to read ahead after this number of bytes is read
+/// sequentially
+const SEQUENTIAL_MIN: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Advice {
+    Normal,
+    Random,
+    Sequential,
+}
+
+/// Serves files using memory mapping
+///
+/// Note: if a file is truncated while it's mapped, reading it kills the
+/// process by `SIGBUS`. So use this only for files that are replaced
+/// atomically (i.e. by rename) rather than rewritten in place.
+#[derive(Debug, Clone, Copy)]
+pub struct MmapSource;
+
+/// Memory-mapped file
+///
+/// Access pattern is passed to the kernel with `madvise`: seeking far from
+/// the current position (e.g. to a start of the range) switches to
+/// `MADV_RANDOM`, and reading sequentially for more than a megabyte
+/// switches to `MADV_SEQUENTIAL`.
+pub struct MmapFile {
+    ptr: *mut libc::c_void,
+    len: u64,
+    pos: u64,
+    /// bytes read since the last seek
+    run: u64,
+    advice: Advice,
+    meta: Metadata,
+    // keep the file open while it's mapped
+    _file: File,
+}
+
+// mapping is read-only and is owned by the structure
+unsafe impl Send for MmapFile {}
+unsafe impl Sync for MmapFile {}
+
+impl MmapFile {
+    /// Map the whole file into memory
+    ///
+    /// Nothing is mapped for empty files and directories.
+    pub fn open(file: File) -> io::Result<MmapFile> {
+        let fmeta = file.metadata()?;
+        let len = if fmeta.is_file() { fmeta.len() } else { 0 };
+        if len > usize::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "file is too large to map"));
+        }
+        let ptr = if len == 0 {
+            ptr::null_mut()
+        } else {
+            let ptr = unsafe {
+                libc::mmap(ptr::null_mut(), len as usize, libc::PROT_READ,
+                           libc::MAP_SHARED, file.as_raw_fd(), 0)
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            ptr
+        };
+        Ok(MmapFile {
+            ptr: ptr,
+            len: len,
+            pos: 0,
+            run: 0,
+            advice: Advice::Normal,
+            meta: Metadata::from(&fmeta),
+            _file: file,
+        })
+    }
+    fn advise(&mut self, advice: Advice) {
+        if self.advice == advice || self.ptr.is_null() {
+            return;
+        }
+        let flag = match advice {
+            Advice::Normal => libc::MADV_NORMAL,
+            Advice::Random => libc::MADV_RANDOM,
+            Advice::Sequential => libc::MADV_SEQUENTIAL,
+        };
+        // it's just a hint, so errors are ignored
+        unsafe { libc::madvise(self.ptr, self.len as usize, flag) };
+        self.advice = advice;
+    }
+    fn data(&self) -> &[u8] {
+        if self.ptr.is_null() {
+            return &[];
+        }
+        unsafe {
+            slice::from_raw_parts(self.ptr as *const u8, self.len as usize)
+        }
+    }
+}
+
+impl Drop for MmapFile {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { libc::munmap(self.ptr, self.len as usize) };
+        }
+    }
+}
+
+impl fmt::Debug for MmapFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MmapFile")
+            .field("len", &self.len)
+            .field("pos", &self.pos)
+            .field("advice", &self.advice)
+            .finish()
+    }
+}
+
+impl Read for MmapFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        let left = self.len - self.pos;
+        let to_boundary = CHUNK - self.pos % CHUNK;
+        let bytes = min(min(left, to_boundary), buf.len() as u64) as usize;
+        let start = self.pos as usize;
+        buf[..bytes].copy_from_slice(&self.data()[start..start+bytes]);
+        self.pos += bytes as u64;
+        self.run += bytes as u64;
+        if self.run >= SEQUENTIAL_MIN {
+            self.advise(Advice::Sequential);
+        }
+        Ok(bytes)
+    }
+}
+
+impl Seek for MmapFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::Current(x) => offset(self.pos, x),
+            SeekFrom::End(x) => offset(self.len, x),
+        };
+        let pos = pos.ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position"))?;
+        // small rewinds happen when output doesn't accept whole chunk
+        if pos.abs_diff(self.pos) > CHUNK {
+            self.run = 0;
+            self.advise(Advice::Random);
+        }
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+fn offset(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    }
+}
+
+impl FileSource for MmapSource {
+    type File = MmapFile;
+    fn stat(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(|m| Metadata::from(&m))
+    }
+    fn open(&self, path: &Path) -> io::Result<MmapFile> {
+        MmapFile::open(File::open(path)?)
+    }
+    fn metadata(&self, file: &MmapFile) -> io::Result<Metadata> {
+        // metadata at the time of mapping, as the mapping has fixed size
+        Ok(file.meta.clone())
+    }
+    fn is_symlink(&self, path: &Path) -> io::Result<bool> {
+        fs::symlink_metadata(path).map(|m| m.file_type().is_symlink())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs::{File, remove_file};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+    use std::process;
+
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use super::{MmapFile, MmapSource, Advice, CHUNK};
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|x| (x % 251) as u8).collect()
+    }
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = temp_dir().join(format!("http-file-headers-{}-{}",
+                                           process::id(), name));
+        File::create(&path).unwrap().write_all(data).unwrap();
+        path
+    }
+
+    #[test]
+    fn unaligned_range() {
+        let data = pattern(5*CHUNK as usize + 1234);
+        let path = temp_file("mmap-range", &data);
+        let headers = vec![("Range", &b"bytes=70001-300000"[..])];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        let output = inp.probe_with(&MmapSource, &path).unwrap();
+        remove_file(&path).unwrap();
+        let mut outf = match output {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert_eq!(outf.content_length(), 230000);
+        let mut body = Vec::new();
+        let mut sizes = Vec::new();
+        loop {
+            let bytes = outf.read_chunk(&mut body).unwrap();
+            if bytes == 0 {
+                break;
+            }
+            sizes.push(bytes);
+        }
+        assert!(body[..] == data[70001..300001]);
+        // first read is up to the chunk boundary, all others are aligned
+        assert_eq!(sizes, vec![131072 - 70001, 65536, 65536,
+                               300001 - 262144]);
+    }
+
+    #[test]
+    fn advice() {
+        let data = pattern(2 << 20);
+        let path = temp_file("mmap-advice", &data);
+        let mut file = MmapFile::open(File::open(&path).unwrap()).unwrap();
+        remove_file(&path).unwrap();
+        let mut buf = vec![0u8; CHUNK as usize];
+        file.read_exact(&mut buf[..100]).unwrap();
+        file.seek(SeekFrom::Current(-50)).unwrap();
+        assert_eq!(file.advice, Advice::Normal);
+        file.seek(SeekFrom::Start(1 << 20)).unwrap();
+        assert_eq!(file.advice, Advice::Random);
+        let mut total = 0;
+        while total < (1 << 20) {
+            total += file.read(&mut buf).unwrap();
+        }
+        assert_eq!(file.advice, Advice::Sequential);
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        assert!(buf[..] == data[data.len() - CHUNK as usize..]);
+    }
+
+    #[test]
+    fn empty_file() {
+        let path = temp_file("mmap-empty", b"");
+        let mut file = MmapFile::open(File::open(&path).unwrap()).unwrap();
+        remove_file(&path).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+    }
+}
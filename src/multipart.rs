@@ -0,0 +1,256 @@
+use std::cmp::min;
+use std::collections::hash_map::RandomState;
+use std::fmt::{self, Display};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+
+use output::{ContentRange, size_of_range};
+
+
+/// Parts of the `multipart/byteranges` response
+///
+/// Part headers are formatted on demand both for computing
+/// `Content-Length` and for streaming the body, so they always match.
+#[derive(Debug)]
+pub struct Multipart {
+    boundary: String,
+    pub(crate) parts: Vec<ContentRange>,
+}
+
+fn random_boundary() -> String {
+    // RandomState is seeded randomly per process and gets a different key
+    // for each instance, this is enough for a boundary to be unpredictable
+    let a = RandomState::new().build_hasher().finish();
+    let b = RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", a, b)
+}
+
+impl Multipart {
+    pub fn new(parts: Vec<ContentRange>, boundary: Option<&str>)
+        -> Multipart
+    {
+        Multipart {
+            boundary: boundary.map(String::from)
+                .unwrap_or_else(random_boundary),
+            parts: parts,
+        }
+    }
+    /// Replaces boundary by a random one
+    pub fn regenerate_boundary(&mut self) {
+        self.boundary = random_boundary();
+    }
+    /// Checks whether boundary occurs in any of the parts of the file
+    pub fn boundary_collides<F: Read + Seek>(&self, file: &mut F)
+        -> io::Result<bool>
+    {
+        let boundary = self.boundary.as_bytes();
+        let mut buf = vec![0u8; 65536];
+        for part in &self.parts {
+            file.seek(SeekFrom::Start(part.start))?;
+            let mut bytes_left = size_of_range(part).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "invalid range")
+            })?;
+            // keep the tail of previous chunk to find boundary that
+            // spans two chunks
+            let mut tail = 0;
+            while bytes_left > 0 {
+                let max = min(buf.len() - tail, bytes_left as usize);
+                let bytes = file.read(&mut buf[tail..tail+max])?;
+                if bytes == 0 {
+                    break;
+                }
+                bytes_left -= bytes as u64;
+                let end = tail + bytes;
+                if buf[..end].windows(boundary.len()).any(|w| w == boundary) {
+                    return Ok(true);
+                }
+                tail = min(boundary.len() - 1, end);
+                let start = end - tail;
+                for i in 0..tail {
+                    buf[i] = buf[start + i];
+                }
+            }
+        }
+        Ok(false)
+    }
+    /// Delimiter and headers that precede the body of the part `idx`
+    pub fn part_header(&self, idx: usize, content_type: Option<&Display>)
+        -> Vec<u8>
+    {
+        let mut buf = Vec::with_capacity(128);
+        if idx > 0 {
+            buf.extend_from_slice(b"\r\n");
+        }
+        write!(buf, "--{}\r\n", self.boundary).unwrap();
+        if let Some(ctype) = content_type {
+            write!(buf, "Content-Type: {}\r\n", ctype).unwrap();
+        }
+        write!(buf, "Content-Range: {}\r\n\r\n", self.parts[idx]).unwrap();
+        return buf;
+    }
+    /// Closing delimiter, that is sent after the last part
+    pub fn closing(&self) -> Vec<u8> {
+        format!("\r\n--{}--\r\n", self.boundary).into_bytes()
+    }
+    /// Exact length of the whole body, including delimiters
+    ///
+    /// Returns `None` if length doesn't fit `u64`
+    pub fn content_length(&self, content_type: Option<&Display>)
+        -> Option<u64>
+    {
+        let mut clen = self.closing().len() as u64;
+        for (idx, part) in self.parts.iter().enumerate() {
+            clen = clen
+                .checked_add(self.part_header(idx, content_type).len() as u64)?
+                .checked_add(size_of_range(part)?)?;
+        }
+        return Some(clen);
+    }
+}
+
+impl fmt::Display for Multipart {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "multipart/byteranges; boundary={}", self.boundary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::str::from_utf8;
+
+    use config::Config;
+    use input::Input;
+    use output::{Output, FileWrapper};
+
+    /// A writer that accepts only few bytes at a time
+    struct Slow(Vec<u8>);
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let bytes = if buf.len() > 7 { &buf[..7] } else { buf };
+            self.0.write(bytes)
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    fn get_ranges(range: &str) -> Output {
+        get_ranges_with(&Config::new(), range)
+    }
+
+    fn get_ranges_with(cfg: &Config, range: &str) -> Output {
+        let cfg = cfg.done();
+        let headers = vec![("Range", range.as_bytes())];
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        inp.probe_file("public/index.html").unwrap()
+    }
+
+    #[test]
+    fn content_length() {
+        let mut outf = match get_ranges("bytes=0-9, 20-29, -5") {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert!(outf.is_partial());
+        let mut body = Slow(Vec::new());
+        while outf.read_chunk(&mut body).unwrap() > 0 {}
+        assert_eq!(body.0.len() as u64, outf.content_length());
+
+        let body = from_utf8(&body.0).unwrap();
+        let ctype = outf.headers()
+            .find(|&(name, _)| name == "Content-Type")
+            .map(|(_, value)| value.to_string()).unwrap();
+        let boundary = &ctype["multipart/byteranges; boundary=".len()..];
+        assert!(body.starts_with(&format!("--{}\r\n", boundary)));
+        assert!(body.ends_with(&format!("\r\n--{}--\r\n", boundary)));
+        assert_eq!(body.matches(boundary).count(), 4);
+        assert!(body.contains("Content-Range: bytes 0-9/126\r\n"));
+        assert!(body.contains("Content-Range: bytes 20-29/126\r\n"));
+        assert!(body.contains("Content-Range: bytes 121-125/126\r\n"));
+    }
+
+    #[test]
+    fn unsatisfiable_part_skipped() {
+        let outf = match get_ranges("bytes=0-9, 1000-2000") {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert_eq!(outf.content_length(), 10);
+        assert!(outf.headers().any(|(name, value)|
+            name == "Content-Range" && value.to_string() == "bytes 0-9/126"));
+    }
+
+    #[test]
+    fn all_unsatisfiable() {
+        match get_ranges("bytes=1000-2000, 3000-") {
+            Output::RangeNotSatisfiable(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    fn read_all(outf: &mut FileWrapper) -> String {
+        let mut body = Vec::new();
+        while outf.read_chunk(&mut body).unwrap() > 0 {}
+        assert_eq!(body.len() as u64, outf.content_length());
+        String::from_utf8(body).unwrap()
+    }
+
+    fn content_type(outf: &FileWrapper) -> String {
+        outf.headers()
+            .find(|&(name, _)| name == "Content-Type")
+            .map(|(_, value)| value.to_string()).unwrap()
+    }
+
+    #[test]
+    fn fixed_boundary() {
+        let mut outf = match get_ranges_with(
+            Config::new().multipart_boundary("BOUNDARY"), "bytes=0-4,10-13")
+        {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert_eq!(content_type(&outf),
+                   "multipart/byteranges; boundary=BOUNDARY");
+        assert_eq!(read_all(&mut outf), "\
+            --BOUNDARY\r\n\
+            Content-Type: text/html; charset=utf-8\r\n\
+            Content-Range: bytes 0-4/126\r\n\
+            \r\n\
+            <!DOC\r\n\
+            --BOUNDARY\r\n\
+            Content-Type: text/html; charset=utf-8\r\n\
+            Content-Range: bytes 10-13/126\r\n\
+            \r\n\
+            html\r\n\
+            --BOUNDARY--\r\n");
+    }
+
+    #[test]
+    fn colliding_boundary() {
+        let mut outf = match get_ranges_with(
+            Config::new().multipart_boundary("Hello"), "bytes=0-4,40-60")
+        {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        let ctype = content_type(&outf);
+        assert!(ctype.starts_with("multipart/byteranges; boundary="));
+        assert!(!ctype.ends_with("=Hello"));
+        assert!(read_all(&mut outf).contains("le>Hello world"));
+    }
+
+    #[test]
+    fn colliding_boundary_head() {
+        let cfg = Config::new().multipart_boundary("Hello").done();
+        let length = |method| {
+            let headers = vec![("Range", &b"bytes=0-4,40-60"[..])];
+            let inp = Input::from_headers(&cfg, method, headers.into_iter());
+            match inp.probe_file("public/index.html").unwrap() {
+                Output::FileHead(head) => head.content_length(),
+                Output::File(outf) => outf.content_length(),
+                x => panic!("unexpected output {:?}", x),
+            }
+        };
+        assert_eq!(length("HEAD"), length("GET"));
+    }
+}
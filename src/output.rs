@@ -1,16 +1,23 @@
+use std::borrow::Cow;
 use std::cmp::min;
 use std::fmt::{self, Display};
-use std::fs::{Metadata, File};
-use std::io::{self, Read, Write, Seek, SeekFrom};
-use std::time::{UNIX_EPOCH, Duration};
+use std::fs::File;
+use std::io::{self, Read, Write, Seek, SeekFrom, Cursor};
+use std::time::{UNIX_EPOCH, Duration, SystemTime};
+use std::path::Path;
 use std::sync::Arc;
 
 use httpdate::HttpDate;
 
 use accept_encoding::Encoding;
-use config::Config;
-use input::{Input, is_text_file};
+use conditionals::{clamp_modified, is_not_modified_since, is_modified_since};
+use conditionals::if_range_matches;
+use content_digest::add_digests;
+use config::{Config, RangeErrorBody};
+use input::{Input, Mode, is_text_file};
+use multipart::Multipart;
 use range::{Range, Slice};
+use source::Metadata;
 use etag::Etag;
 
 /// This is a heuristic that there are no valid dates before 1990-01-01
@@ -24,10 +31,12 @@ const MIN_DATE: u64 = 631152000;
 
 const BYTES: &str = "bytes";
 const BYTES_PTR: &&str = &BYTES;
+const NONE: &str = "none";
+const NONE_PTR: &&str = &NONE;
 
 
 #[derive(Debug)]
-struct ContentType(&'static str, Arc<Config>);
+pub(crate) struct ContentType(Cow<'static, str>, Arc<Config>);
 
 /// This enum represents all the information needed to form response for
 /// the HTTP request
@@ -35,7 +44,8 @@ struct ContentType(&'static str, Arc<Config>);
 /// Variants of this structure represent different modes of responding on
 /// request.
 #[derive(Debug)]
-pub enum Output {
+#[non_exhaustive]
+pub enum Output<F=File> {
     /// File not found
     NotFound,
     /// File was requested using `HEAD` method
@@ -43,19 +53,46 @@ pub enum Output {
     /// File is not modified, should return 304
     ///
     /// This might be returned if there is one of `If-None-Match`
-    /// or `If-Modified-Since`
+    /// or `If-Modified-Since`. When both are sent, the date is ignored
+    /// and only the etag is compared (RFC 7232, section 3.3).
     NotModified(Head),
+    /// Precondition is false, should return 412
+    ///
+    /// This might be returned if there is one of `If-Match` or
+    /// `If-Unmodified-Since`. The response has no body.
+    PreconditionFailed(Head),
     /// Normal file was requested using `GET` method
-    File(FileWrapper),
+    File(FileWrapper<F>),
     /// The `GET` file request includes `Range` field, and range is
     /// contiguous
-    FileRange(FileWrapper),
+    FileRange(FileWrapper<F>),
     /// The matching path is a directory
     Directory,
+    /// The `OPTIONS` request for existing file or `*`, should return 200
+    ///
+    /// Response should contain `Allow: GET, HEAD, OPTIONS`.
+    Options,
     /// Invalid method was requested
     InvalidMethod,
     /// Invalid `Range` header in request, should return 416
     InvalidRange,
+    /// Requested ranges don't overlap the file, should return 416
+    ///
+    /// Response should contain `Content-Range` with the file length, and
+    /// optionally a body if `Config::range_error_body` is set.
+    RangeNotSatisfiable(Unsatisfiable),
+    /// The file should be served by the proxy, should return 200
+    ///
+    /// The `Head` contains the header configured by
+    /// `Config::internal_redirect_header` and the body is empty. See
+    /// `Output::internal_redirect`.
+    InternalRedirect(Head),
+    /// File can't be read temporarily, should return 503
+    ///
+    /// The value is the number of seconds to send in `Retry-After` header.
+    /// This is never returned by `Input`, use `Output::from_io_error` to
+    /// convert transient errors into this value.
+    ServiceUnavailable(u32),
 }
 
 /// All the metadata of for the response headers
@@ -63,44 +100,101 @@ pub enum Output {
 pub struct Head {
     config: Arc<Config>,
     encoding: Encoding,
-    content_length: u64,
-    content_type: Option<ContentType>,
+    pub(crate) content_length: u64,
+    pub(crate) content_type: Option<ContentType>,
     last_modified: Option<HttpDate>,
     etag: Option<Etag>,
-    range: Option<ContentRange>,
+    pub(crate) range: Option<ContentRange>,
+    pub(crate) multipart: Option<Box<Multipart>>,
     not_modified: bool,
+    precondition_failed: bool,
+    date: Option<HttpDate>,
+    vary: Vary,
+    no_ranges: bool,
+    status: Option<u16>,
+    /// index in `Config::preloads`
+    preload: Option<u32>,
+    /// headers added by `set_header` and `append_header`
+    extra: Vec<(String, String)>,
+    /// ranges are served by the proxy, see `Output::internal_redirect`
+    redirect: bool,
+}
+
+/// Request headers that response depends on
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Vary {
+    pub(crate) encoding: bool,
+    pub(crate) accept: bool,
+}
+
+/// Result of checking a stored response against a new request
+///
+/// See `Output::revalidate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Revalidation {
+    /// Stored response is fresh for the request, should return 304
+    NotModified,
+    /// Conditions don't prevent sending the response (or there are none)
+    Modified,
+    /// Precondition is false, should return 412
+    PreconditionFailed,
+}
+
+/// Response on ranges that don't overlap the file
+#[derive(Debug)]
+pub struct Unsatisfiable {
+    file_size: u64,
+    body: Option<(&'static str, Box<str>)>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct ContentRange {
-    start: u64,
-    end: u64,
-    file_size: u64,
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+    pub(crate) file_size: u64,
 }
 
 /// Structure that contains all the metadata for response headers and
 /// the file which will be sent in response body.
 #[derive(Debug)]
-pub struct FileWrapper {
-    head: Head,
-    file: File,
+pub struct FileWrapper<F=File> {
+    pub(crate) head: Head,
+    pub(crate) file: F,
     bytes_left: u64,
+    multipart: Option<Box<MultipartState>>,
+    broken: bool,
+}
+
+/// State of the `multipart/byteranges` body
+#[derive(Debug)]
+struct MultipartState {
+    next_part: usize,
+    delimiter: Vec<u8>,
+    delimiter_pos: usize,
 }
 
 #[derive(Clone, Copy, Debug)]
 enum HeaderIterState {
+    Date,
+    Server,
     LastModified,
     Etag,
+    Vary,
+    CacheControl,
 
     // these not needed if NotModified
     Encoding,
     AcceptRanges,
     ContentRange,
     ContentType,
+    Link(usize),
 
+    Extra(usize),
     Done,
 }
 
+/// Iterator over response headers, see `Head::headers`
 #[derive(Debug)]
 pub struct HeaderIter<'a> {
     head: &'a Head,
@@ -114,6 +208,14 @@ impl<'a> Iterator for HeaderIter<'a> {
         use self::HeaderIterState as H;
         loop {
             let value = match self.state {
+                H::Date => {
+                    self.head.date.as_ref()
+                        .map(|x| ("Date", x as &Display))
+                }
+                H::Server => {
+                    self.head.config.server_header.as_ref()
+                        .map(|x| ("Server", x as &Display))
+                }
                 H::LastModified => {
                     self.head.last_modified.as_ref()
                         .map(|x| ("Last-Modified", x as &Display))
@@ -122,6 +224,18 @@ impl<'a> Iterator for HeaderIter<'a> {
                     self.head.etag.as_ref()
                         .map(|x| ("ETag", x as &Display))
                 }
+                H::Vary => {
+                    if self.head.vary.encoding || self.head.vary.accept {
+                        Some(("Vary", &self.head.vary as &Display))
+                    } else {
+                        None
+                    }
+                }
+                H::CacheControl if self.head.status.is_some() => None,
+                H::CacheControl => {
+                    self.head.config.cache_control.as_ref()
+                        .map(|x| ("Cache-Control", x as &Display))
+                }
                 H::Encoding => {
                     if self.head.encoding != Encoding::Identity {
                         Some(("Content-Encoding",
@@ -135,22 +249,55 @@ impl<'a> Iterator for HeaderIter<'a> {
                         .map(|x| ("Content-Range", x as &Display))
                 }
                 H::ContentType => {
-                    self.head.content_type.as_ref()
-                        .map(|x| ("Content-Type", x as &Display))
+                    if let Some(ref mp) = self.head.multipart {
+                        Some(("Content-Type", mp as &Display))
+                    } else {
+                        self.head.content_type.as_ref()
+                            .map(|x| ("Content-Type", x as &Display))
+                    }
+                }
+                H::AcceptRanges if self.head.redirect => None,
+                H::AcceptRanges if self.head.no_ranges => {
+                    Some(("Accept-Ranges", NONE_PTR as &Display))
                 }
                 H::AcceptRanges => {
                     Some(("Accept-Ranges", BYTES_PTR as &Display))
                 }
+                H::Link(idx) => {
+                    self.head.preload_links().get(idx)
+                        .map(|x| ("Link", x as &Display))
+                }
+                H::Extra(idx) => {
+                    self.head.extra.get(idx)
+                        .map(|(name, value)| (&name[..], value as &Display))
+                }
                 H::Done => None,
             };
             self.state = match self.state {
+                H::Date => H::Server,
+                H::Server if self.head.precondition_failed => H::Etag,
+                H::Server => H::LastModified,
                 H::LastModified => H::Etag,
-                H::Etag if self.head.not_modified => H::Done,
-                H::Etag => H::Encoding,
+                H::Etag if self.head.precondition_failed => H::Extra(0),
+                H::Etag => H::Vary,
+                H::Vary => H::CacheControl,
+                H::CacheControl if self.head.not_modified => H::Extra(0),
+                H::CacheControl => H::Encoding,
                 H::Encoding => H::AcceptRanges,
                 H::AcceptRanges => H::ContentRange,
                 H::ContentRange => H::ContentType,
-                H::ContentType => H::Done,
+                H::ContentType if !self.head.preload_links().is_empty() => {
+                    H::Link(0)
+                }
+                H::ContentType => H::Extra(0),
+                H::Link(idx) if idx + 1 < self.head.preload_links().len() => {
+                    H::Link(idx + 1)
+                }
+                H::Link(_) => H::Extra(0),
+                H::Extra(idx) if idx + 1 < self.head.extra.len() => {
+                    H::Extra(idx + 1)
+                }
+                H::Extra(_) => H::Done,
                 H::Done => return None,
             };
             match value {
@@ -161,97 +308,354 @@ impl<'a> Iterator for HeaderIter<'a> {
     }
 }
 
+/// Evaluates conditional headers of the request against the validators
+///
+/// The flag is false when 412 is caused by `If-Unmodified-Since`, so the
+/// etag is not sent in the response.
+fn evaluate(inp: &Input, etag: Option<&Etag>, mod_time: Option<SystemTime>,
+    now: SystemTime)
+    -> (Revalidation, bool)
+{
+    // If-Unmodified-Since is ignored when If-Match is present; failed
+    // If-Match is answered with the current etag. Matching If-None-Match
+    // is checked after both, and for methods other than GET and HEAD it
+    // fails with 412 instead of 304 (RFC 7232, section 3.2)
+    let none_matches = !inp.if_none.is_empty() && etag
+        .map(|etag| inp.if_none.iter().any(|x| *x == Etag::any() ||
+            if inp.config.strong_etags {
+                x.strong_eq(etag)
+            } else {
+                x.weak_eq(etag)
+            }))
+        .unwrap_or(false);
+    if !inp.if_match.is_empty() {
+        let matches = etag
+            .map(|etag| inp.if_match.iter()
+                 .any(|x| *x == Etag::any() || x.strong_eq(etag)))
+            .unwrap_or(false);
+        if !matches {
+            return (Revalidation::PreconditionFailed, true);
+        }
+    } else if let (Some(since), Some(mtime)) = (inp.if_unmodified, mod_time)
+    {
+        if is_modified_since(since, mtime) {
+            return (Revalidation::PreconditionFailed, false);
+        }
+    }
+    // etag is the stronger validator, so If-Modified-Since is ignored
+    // when If-None-Match is present, even if etag doesn't match
+    if !inp.if_none.is_empty() {
+        if none_matches && inp.mode.is_get_or_head() {
+            return (Revalidation::NotModified, true);
+        } else if none_matches {
+            return (Revalidation::PreconditionFailed, true);
+        }
+    } else if let Some(if_modified) = inp.if_modified
+        .filter(|_| inp.mode.is_get_or_head())
+    {
+        if mod_time.map(|x| is_not_modified_since(if_modified, x, now))
+            .unwrap_or(false)
+        {
+            return (Revalidation::NotModified, true);
+        }
+    }
+    (Revalidation::Modified, true)
+}
+
 impl Head {
     /// Returns true if response contains partial content (206)
     pub fn is_partial(&self) -> bool {
-        self.range.is_some()
+        self.range.is_some() || self.multipart.is_some()
+    }
+    /// Returns `(start, end, file_size)` of the range being served
+    ///
+    /// The `end` is inclusive, so the values are exactly the ones sent in
+    /// the `Content-Range` header. Returns `None` if the whole file is
+    /// served and also for `multipart/byteranges`, where each part has
+    /// own range.
+    pub fn content_range(&self) -> Option<(u64, u64, u64)> {
+        self.range.as_ref()
+            .filter(|r| r.file_size != 0)
+            .map(|r| (r.start, r.end, r.file_size))
     }
     /// Returns true if response is skipped because cache is fresh (304)
     pub fn is_not_modified(&self) -> bool {
         self.not_modified
     }
-    pub(crate) fn from_meta(inp: &Input, encoding: Encoding,
-        metadata: &Metadata, ctype: &'static str)
-        -> Result<Head, Output>
+    /// Returns true if response is skipped because precondition is false
+    /// (412)
+    pub fn is_precondition_failed(&self) -> bool {
+        self.precondition_failed
+    }
+    /// Returns the status code of the response
+    ///
+    /// This is 304 if not modified, 412 if precondition failed, 206 for
+    /// partial content and 200 otherwise. Except for `Output::error` where
+    /// the status is fixed (unless not modified or precondition failed).
+    pub fn status(&self) -> u16 {
+        if self.not_modified {
+            304
+        } else if self.precondition_failed {
+            412
+        } else if let Some(status) = self.status {
+            status
+        } else if self.is_partial() {
+            206
+        } else {
+            200
+        }
+    }
+    pub(crate) fn from_meta<F>(inp: &Input, encoding: Encoding,
+        metadata: &Metadata, ctype: Cow<'static, str>,
+        precomputed_etag: Option<Etag>, vary: Vary)
+        -> Result<Head, Box<Output<F>>>
     {
+        let now = SystemTime::now();
         let mod_time = if inp.config.last_modified {
-            metadata.modified().ok()
+            inp.last_modified_override().or_else(|| metadata.modified())
             .and_then(|x| if x < UNIX_EPOCH + Duration::new(MIN_DATE, 0) {
                 None
             } else {
-                Some(x)
+                Some(clamp_modified(x, now))
             })
         } else {
             None
         };
         let size = metadata.len();
         let etag = if inp.config.etag {
-            Some(Etag::from_metadata(metadata))
+            let etag = precomputed_etag
+                 .unwrap_or_else(|| Etag::from_metadata(metadata,
+                    inp.config.etag_strategy));
+            if inp.config.strong_etags {
+                Some(etag.into_strong())
+            } else {
+                Some(etag)
+            }
         } else {
             None
         };
-        if inp.if_none.len() > 0 {
-            if inp.if_none.iter().any(|x| Some(x) == etag.as_ref()) {
-                return Err(Output::NotModified(Head {
+        let date = if inp.config.date { Some(now.into()) } else { None };
+        let (check, send_etag) = evaluate(inp, etag.as_ref(), mod_time, now);
+        match check {
+            Revalidation::PreconditionFailed => {
+                return Err(Box::new(Output::PreconditionFailed(Head {
                     config: inp.config.clone(),
                     encoding: encoding,
-                    content_length: 0, // don't need to send
-                    content_type: None, // don't need to send
-                    last_modified: mod_time.map(Into::into),
-                    etag: etag,
+                    content_length: size,
+                    content_type: None,
+                    last_modified: None,
+                    etag: etag.filter(|_| send_etag),
                     range: None,
-                    not_modified: true,
-                }))
+                    multipart: None,
+                    not_modified: false,
+                    precondition_failed: true,
+                    date: date,
+                    vary: Vary::default(),
+                    no_ranges: false,
+                    status: None,
+                    preload: None,
+                    extra: Vec::new(),
+                    redirect: false,
+                })));
             }
-        } else if let Some(ref last_mod) = inp.if_modified {
-            if mod_time.as_ref().map(|x| last_mod <= x).unwrap_or(false) {
-                return Err(Output::NotModified(Head {
+            Revalidation::NotModified => {
+                return Err(Box::new(Output::NotModified(Head {
                     config: inp.config.clone(),
                     encoding: encoding,
-                    content_length: 0, // don't need to send
+                    // not sent, but used for `full_length()`
+                    content_length: size,
                     content_type: None, // don't need to send
                     last_modified: mod_time.map(Into::into),
                     etag: etag,
                     range: None,
+                    multipart: None,
                     not_modified: true,
-                }))
+                    precondition_failed: false,
+                    date: date,
+                    vary: vary,
+                    no_ranges: false,
+                    status: None,
+                    preload: None,
+                    extra: Vec::new(),
+                    redirect: false,
+                })))
             }
+            _ => {}
         }
-        let (range, clen) = resolve_range(&inp.range, size)?;
+        let content_type = if inp.config.content_type {
+            Some(ContentType(ctype, inp.config.clone()))
+        } else {
+            None
+        };
+        // With `If-Range` the whole file is sent if it was changed since
+        // the client got the first part, and also if the range isn't
+        // satisfiable anymore (i.e. file has shrunk) so the client restarts
+        // the download instead of failing on 416
+        let range = match inp.if_range {
+            Some(ref validator) if !if_range_matches(validator, mod_time,
+                                                     etag.as_ref()) ||
+                                   !is_satisfiable(&inp.range, size)
+            => &None,
+            _ => &inp.range,
+        };
+        let mut no_ranges = !inp.ranges;
+        let (range, multipart, clen) = match *range {
+            Some(Range::MultipleRangesOfBytes(ref slices)) => {
+                let mut parts = resolve_multiple(slices, size)
+                    .map_err(|()| Box::new(Unsatisfiable::output(inp, size)))?;
+                if parts.len() == 1 {
+                    let clen = size_of_range(&parts[0]).ok_or_else(|| {
+                        Box::new(Unsatisfiable::output(inp, size))
+                    })?;
+                    (parts.pop(), None, clen)
+                } else if encoding != Encoding::Identity {
+                    // Multipart body of compressed file can't be decoded
+                    // by clients, so serve whole file instead
+                    no_ranges = true;
+                    (None, None, size)
+                } else {
+                    let mp = Box::new(Multipart::new(parts,
+                        inp.config.multipart_boundary.as_ref()
+                        .map(|x| &x[..])));
+                    let clen = mp.content_length(
+                        content_type.as_ref().map(|x| x as &Display))
+                        .ok_or_else(|| {
+                            Box::new(Unsatisfiable::output(inp, size))
+                        })?;
+                    (None, Some(mp), clen)
+                }
+            }
+            ref range => {
+                let (range, clen) = resolve_range(range, size)
+                    .map_err(|()| Box::new(Unsatisfiable::output(inp, size)))?;
+                (range, None, clen)
+            }
+        };
         Ok(Head {
             config: inp.config.clone(),
             encoding: encoding,
             content_length: clen,
-            content_type: if inp.config.content_type {
-                Some(ContentType(ctype, inp.config.clone()))
-            } else {
-                None
-            },
+            content_type: content_type,
             last_modified: mod_time.map(Into::into),
             etag: etag,
             range: range,
+            multipart: multipart,
             not_modified: false,
+            precondition_failed: false,
+            date: date,
+            vary: vary,
+            no_ranges: no_ranges,
+            status: None,
+            preload: None,
+            extra: Vec::new(),
+            redirect: false,
         })
     }
+    fn preload_links(&self) -> &[String] {
+        match self.preload {
+            Some(idx) => &self.config.preloads[idx as usize].1,
+            None => &[],
+        }
+    }
     /// Returns the value of `Content-Length` header that should be sent
+    ///
+    /// This is zero for 304 and 412 responses, which have no body. The
+    /// header must not be sent for 304 though (as it would mean length of
+    /// the file), but `Content-Length: 0` should be sent for 412.
     pub fn content_length(&self) -> u64 {
-        self.content_length
+        if self.not_modified || self.precondition_failed {
+            0
+        } else {
+            self.content_length
+        }
+    }
+    /// Returns the size of the whole file being served
+    ///
+    /// This is the size of the encoded file if it's served, and is not
+    /// affected by ranges.
+    pub fn full_length(&self) -> u64 {
+        if let Some(ref range) = self.range {
+            range.file_size
+        } else if let Some(ref mp) = self.multipart {
+            mp.parts[0].file_size
+        } else {
+            self.content_length
+        }
+    }
+    /// Returns the encoding of the file being served
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
     }
     /// Returns the iterator over headers to send in response
     ///
     /// Note: this does not include `Content-Length` header,
     /// use `content_length()` method explicitly.
+    ///
+    /// Headers are always in this order (ones that don't apply are
+    /// skipped): `Date`, `Server`, `Last-Modified`, `ETag`, `Vary`,
+    /// `Cache-Control`, `Content-Encoding`, `Accept-Ranges`,
+    /// `Content-Range`, `Content-Type`, `Link` (in the order of
+    /// `Config::add_preload`), and then headers added by `set_header` and
+    /// `append_header` (and `Config::content_digest`) in the order added.
     pub fn headers(&self) -> HeaderIter {
         HeaderIter {
             head: self,
-            state: HeaderIterState::LastModified,
+            state: HeaderIterState::Date,
+        }
+    }
+    /// Check conditional headers of the new request against this response
+    ///
+    /// See `Output::revalidate`.
+    pub fn revalidate(&self, inp: &Input) -> Revalidation {
+        let mod_time = self.last_modified.map(SystemTime::from);
+        evaluate(inp, self.etag.as_ref(), mod_time, SystemTime::now()).0
+    }
+    /// Add a header to the response, replacing ones with the same name
+    ///
+    /// Only the headers added by this method and `append_header` are
+    /// replaced (names are compared case-insensitively), the ones
+    /// generated by this crate are never affected. Custom headers are
+    /// returned by `headers()` after generated ones, for every status
+    /// including 304 and 412.
+    pub fn set_header(&mut self, name: &str, value: &str) -> &mut Head {
+        self.extra.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.append_header(name, value)
+    }
+    /// Add a header to the response, keeping ones with the same name
+    ///
+    /// Use it for headers that may legitimately be sent multiple times,
+    /// like `Set-Cookie`.
+    pub fn append_header(&mut self, name: &str, value: &str) -> &mut Head {
+        self.extra.push((name.into(), value.into()));
+        self
+    }
+    /// Replaces fixed multipart boundary if it occurs in the parts served
+    ///
+    /// Must be called before the head is returned, for `HEAD` too, as the
+    /// boundary changes `Content-Length`. Random boundary isn't checked, as
+    /// 128 bits can't be guessed by the author of the file.
+    fn check_boundary<F: Read + Seek>(&mut self, file: &mut F)
+        -> io::Result<()>
+    {
+        if self.config.multipart_boundary.is_none() {
+            return Ok(());
+        }
+        if let Some(ref mut mp) = self.multipart {
+            if mp.boundary_collides(file)? {
+                mp.regenerate_boundary();
+                self.content_length = mp.content_length(
+                    self.content_type.as_ref().map(|x| x as &Display))
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                        "multipart body is too large"))?;
+            }
         }
+        Ok(())
     }
 }
 
-impl FileWrapper {
-    pub(crate) fn new(head: Head, mut file: File)
-        -> Result<FileWrapper, io::Error>
+impl<F: Read + Seek> FileWrapper<F> {
+    pub(crate) fn new(head: Head, mut file: F)
+        -> Result<FileWrapper<F>, io::Error>
     {
         let nbytes = match head.range {
             Some(ContentRange { start, end, .. }) => {
@@ -260,22 +664,50 @@ impl FileWrapper {
                 }
                 end - start + 1
             }
+            // multipart body starts with a delimiter, see `next_part`
+            _ if head.multipart.is_some() => 0,
             _ => head.content_length,
         };
+        let multipart = if head.multipart.is_some() {
+            Some(Box::new(MultipartState {
+                next_part: 0,
+                delimiter: Vec::new(),
+                delimiter_pos: 0,
+            }))
+        } else {
+            None
+        };
         Ok(FileWrapper {
             head: head,
             file: file,
             bytes_left: nbytes,
+            multipart: multipart,
+            broken: false,
         })
     }
+}
+
+impl<F> FileWrapper<F> {
     /// Returns true if response contains partial content (206)
     pub fn is_partial(&self) -> bool {
-        self.head.range.is_some()
+        self.head.is_partial()
+    }
+    /// Returns the range being served, see `Head::content_range`
+    pub fn content_range(&self) -> Option<(u64, u64, u64)> {
+        self.head.content_range()
+    }
+    /// Returns the status code of the response, see `Head::status`
+    pub fn status(&self) -> u16 {
+        self.head.status()
     }
     /// Returns the value of `Content-Length` header that should be sent
     pub fn content_length(&self) -> u64 {
         self.head.content_length
     }
+    /// Returns the encoding of the file being served
+    pub fn encoding(&self) -> Encoding {
+        self.head.encoding
+    }
     /// Returns the iterator over headers to send in response
     ///
     /// Note: this does not include `Content-Length` header,
@@ -283,18 +715,73 @@ impl FileWrapper {
     pub fn headers(&self) -> HeaderIter {
         self.head.headers()
     }
+    /// Add a header replacing ones with the same name, see
+    /// `Head::set_header`
+    pub fn set_header(&mut self, name: &str, value: &str) -> &mut Self {
+        self.head.set_header(name, value);
+        self
+    }
+    /// Add a header keeping ones with the same name, see
+    /// `Head::append_header`
+    pub fn append_header(&mut self, name: &str, value: &str) -> &mut Self {
+        self.head.append_header(name, value);
+        self
+    }
+    /// Returns true if connection must be closed after the response
+    ///
+    /// Body length is always known in advance, so this is false unless
+    /// `read_chunk` failed to read the file (e.g. it was truncated). In
+    /// that case the body can't be finished to match `Content-Length`,
+    /// so the only way to signal the error to the client (regardless of
+    /// HTTP version) is to close the connection.
+    pub fn must_close(&self) -> bool {
+        self.broken
+    }
+}
+
+impl<F: Read + Seek> FileWrapper<F> {
     /// Read chunk from file into an output file
     ///
+    /// Returns an error of kind `UnexpectedEof` if file was truncated after
+    /// headers were generated. Content-Length can't be fixed at this point,
+    /// so the connection must be closed without finishing the response.
+    ///
     /// **Must be run in disk thread**
     pub fn read_chunk<O>(&mut self, mut output: O) -> io::Result<usize>
         where O: Write
     {
-        if self.bytes_left == 0 {
-            return Ok(0)
+        loop {
+            if let Some(ref mut state) = self.multipart {
+                if state.delimiter_pos < state.delimiter.len() {
+                    let wbytes = output.write(
+                        &state.delimiter[state.delimiter_pos..])?;
+                    state.delimiter_pos += wbytes;
+                    return Ok(wbytes);
+                }
+            }
+            if self.bytes_left > 0 {
+                break;
+            }
+            if !self.next_part()? {
+                return Ok(0);
+            }
         }
         let mut buf = [0u8; 65536];
         let max = min(buf.len() as u64, self.bytes_left) as usize;
-        let bytes = self.file.read(&mut buf[..max])?;
+        let bytes = match self.file.read(&mut buf[..max]) {
+            Ok(0) => {
+                self.broken = true;
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    "file is shorter than Content-Length sent"));
+            }
+            Ok(bytes) => bytes,
+            Err(e) => {
+                if !is_transient(&e) {
+                    self.broken = true;
+                }
+                return Err(e);
+            }
+        };
         let wbytes = match output.write(&buf[..bytes]) {
             Ok(wbytes) if wbytes != bytes => {
                 assert!(wbytes < bytes);
@@ -312,9 +799,376 @@ impl FileWrapper {
         self.bytes_left -= wbytes as u64;
         Ok(wbytes)
     }
+    /// Prepares next part of multipart body, returns false at the end
+    fn next_part(&mut self) -> io::Result<bool> {
+        let (mp, state) = match
+            (self.head.multipart.as_ref(), self.multipart.as_mut())
+        {
+            (Some(mp), Some(state)) => (mp, state),
+            _ => return Ok(false),
+        };
+        if state.next_part < mp.parts.len() {
+            let part = &mp.parts[state.next_part];
+            self.file.seek(SeekFrom::Start(part.start))?;
+            self.bytes_left = size_of_range(part).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "invalid range")
+            })?;
+            state.delimiter = mp.part_header(state.next_part,
+                self.head.content_type.as_ref().map(|x| x as &Display));
+        } else if state.next_part == mp.parts.len() {
+            state.delimiter = mp.closing();
+        } else {
+            return Ok(false);
+        }
+        state.delimiter_pos = 0;
+        state.next_part += 1;
+        Ok(true)
+    }
+}
+
+impl<F: Read + Seek> Output<F> {
+    /// Build output for the file that is already opened
+    ///
+    /// This is what `Input::probe_file` does after opening the file. Use it
+    /// if files are opened by custom logic (e.g. after access checks), so
+    /// file isn't reopened and metadata isn't requested again. The `file`
+    /// must be the one `metadata` is for, and `encoding` is the content
+    /// encoding of the file (if it's not identity, `Vary` is sent).
+    pub fn from_open_file(inp: &Input, encoding: Encoding,
+        metadata: &Metadata, file: F, content_type: &str)
+        -> Result<Output<F>, io::Error>
+    {
+        Output::from_open_file_with(inp, encoding, metadata, file,
+            Cow::Owned(content_type.into()), None,
+            Vary { encoding: encoding != Encoding::Identity, accept: false })
+    }
+    pub(crate) fn from_open_file_with(inp: &Input, encoding: Encoding,
+        metadata: &Metadata, mut file: F, ctype: Cow<'static, str>,
+        etag: Option<Etag>, vary: Vary)
+        -> Result<Output<F>, io::Error>
+    {
+        match inp.mode {
+            Mode::InvalidMethod => return Ok(Output::InvalidMethod),
+            Mode::InvalidRange => return Ok(Output::InvalidRange),
+            Mode::Options => return Ok(Output::Options),
+            Mode::Head | Mode::Get => {}
+        }
+        let mut head = match Head::from_meta(inp, encoding, metadata, ctype,
+                                             etag, vary)
+        {
+            Err(output) => return Ok(*output),
+            Ok(head) => head,
+        };
+        head.check_boundary(&mut file)?;
+        if inp.config.content_digest {
+            add_digests(&mut head, &mut file, metadata.len,
+                        inp.mode != Mode::Head)?;
+        }
+        match inp.mode {
+            Mode::Head => Ok(Output::FileHead(head)),
+            _ => Ok(Output::File(FileWrapper::new(head, file)?)),
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> Output<Cursor<B>> {
+    /// Serve in-memory error page with the fixed `status`
+    ///
+    /// Conditional and range headers of the `inp` are handled as for
+    /// normal files (etag is a hash of the body), but `Head::status` returns
+    /// `status` instead of 200 or 206. So only `NotModified`,
+    /// `PreconditionFailed`, `FileHead`, `File` and `RangeNotSatisfiable`
+    /// are returned. Error page is returned
+    /// regardless of the request method (`FileHead` for `HEAD`).
+    pub fn error(inp: &Input, status: u16, body: B, content_type: &str)
+        -> Output<Cursor<B>>
+    {
+        let etag = Etag::from_bytes(body.as_ref());
+        let meta = Metadata::file(body.as_ref().len() as u64, None);
+        let ctype = Cow::Owned(content_type.into());
+        let head = Head::from_meta(inp, Encoding::Identity, &meta, ctype,
+                                   Some(etag), Vary::default());
+        let mut head = match head.map_err(|output| *output) {
+            Ok(head) => head,
+            Err(Output::NotModified(mut head)) => {
+                // status is overriden by 304, it's just to mark error page
+                head.status = Some(status);
+                return Output::NotModified(head);
+            }
+            Err(output) => return output,
+        };
+        head.status = Some(status);
+        let mut body = Cursor::new(body);
+        if let Err(e) = head.check_boundary(&mut body) {
+            unreachable!("reading memory failed: {}", e);
+        }
+        if inp.is_head() {
+            return Output::FileHead(head);
+        }
+        match FileWrapper::new(head, body) {
+            Ok(outf) => Output::File(outf),
+            Err(e) => unreachable!("reading memory failed: {}", e),
+        }
+    }
+}
+
+/// Returns true if the error might disappear if request is retried
+///
+/// This usually happens on networked filesystems
+fn is_transient(err: &io::Error) -> bool {
+    let kind = err.kind();
+    kind == io::ErrorKind::WouldBlock ||
+    kind == io::ErrorKind::TimedOut ||
+    kind == io::ErrorKind::Interrupted
+}
+
+impl<F> Output<F> {
+    pub(crate) fn set_preload(&mut self, preload: Option<u32>) {
+        match *self {
+            Output::FileHead(ref mut head) => head.preload = preload,
+            Output::File(ref mut wrapper) => wrapper.head.preload = preload,
+            _ => {}
+        }
+    }
+    /// Returns headers to send in `103 Early Hints` before the response
+    ///
+    /// These are `Link` headers configured by `Config::add_preload` (and
+    /// they are also sent in the final response). Returns `None` if there
+    /// are no preload links for the file.
+    pub fn early_hints(&self) -> Option<Vec<(&str, &[u8])>> {
+        let head = match *self {
+            Output::FileHead(ref head) => head,
+            Output::File(ref wrapper) => &wrapper.head,
+            _ => return None,
+        };
+        let links = head.preload_links();
+        if links.is_empty() {
+            return None;
+        }
+        Some(links.iter().map(|x| ("Link", x.as_bytes())).collect())
+    }
+    /// Let the proxy (e.g. nginx) serve the file at `path`
+    ///
+    /// No files are opened. The `Head` has `Content-Type` guessed from the
+    /// `path` (or `Input::override_content_type`), `Cache-Control` if
+    /// configured, and the header set by `Config::internal_redirect_header`
+    /// with the `path` as the value. Conditional and range requests are
+    /// handled by the proxy, so `InternalRedirect` is always returned
+    /// (unless request is invalid).
+    pub fn internal_redirect(inp: &Input, path: &str) -> Output<F> {
+        match inp.mode {
+            Mode::Head | Mode::Get => {}
+            Mode::Options => return Output::Options,
+            Mode::InvalidMethod => return Output::InvalidMethod,
+            Mode::InvalidRange => return Output::InvalidRange,
+        }
+        let content_type = if inp.config.content_type {
+            let ctype = inp.content_type_of(Path::new(path));
+            Some(ContentType(ctype, inp.config.clone()))
+        } else {
+            None
+        };
+        let date = if inp.config.date {
+            Some(SystemTime::now().into())
+        } else {
+            None
+        };
+        let mut head = Head {
+            config: inp.config.clone(),
+            encoding: Encoding::Identity,
+            content_length: 0,
+            content_type: content_type,
+            last_modified: None,
+            etag: None,
+            range: None,
+            multipart: None,
+            not_modified: false,
+            precondition_failed: false,
+            date: date,
+            vary: Vary::default(),
+            no_ranges: false,
+            status: None,
+            preload: None,
+            extra: Vec::new(),
+            redirect: true,
+        };
+        head.append_header(&inp.config.redirect_header, path);
+        Output::InternalRedirect(head)
+    }
+    /// Create a 503 response with `Retry-After` of `seconds`
+    pub fn service_unavailable(seconds: u32) -> Output<F> {
+        Output::ServiceUnavailable(seconds)
+    }
+    /// Convert transient error into `ServiceUnavailable`
+    ///
+    /// Errors of the kinds `WouldBlock`, `TimedOut` and `Interrupted` are
+    /// considered transient, other ones are returned unchanged. Use it as
+    /// `inp.probe_file(path).or_else(|e| Output::from_io_error(e, 5))`.
+    pub fn from_io_error(err: io::Error, retry_after: u32)
+        -> Result<Output<F>, io::Error>
+    {
+        if is_transient(&err) {
+            Ok(Output::ServiceUnavailable(retry_after))
+        } else {
+            Err(err)
+        }
+    }
+    /// Drop the body of the response keeping all the headers
+    ///
+    /// This converts `File` and `FileRange` into `FileHead`, other outputs
+    /// are returned unchanged. Note: headers, including `Content-Length`,
+    /// of the `HEAD` request are exactly the same as for `GET`, so this is
+    /// only useful if method is known after the file is opened.
+    pub fn suppress_body(self) -> Output<F> {
+        match self {
+            Output::File(outf) | Output::FileRange(outf) => {
+                Output::FileHead(outf.head)
+            }
+            x => x,
+        }
+    }
+    /// Returns the size of the whole file (entity) being served
+    ///
+    /// This is the same for `GET`, `HEAD` and conditional requests, but is
+    /// the size of the encoded file if it's served. Zero for outputs that
+    /// don't serve a file.
+    pub fn full_length(&self) -> u64 {
+        match *self {
+            Output::FileHead(ref head) => head.full_length(),
+            Output::NotModified(ref head) => head.full_length(),
+            Output::PreconditionFailed(ref head) => head.full_length(),
+            Output::File(ref outf) => outf.head.full_length(),
+            Output::FileRange(ref outf) => outf.head.full_length(),
+            _ => 0,
+        }
+    }
+    /// Returns number of bytes of the body that will be sent
+    ///
+    /// This includes multipart delimiters, and is zero for `HEAD` and
+    /// not modified responses. Bodies of error outputs (like `NotFound`)
+    /// are generated by caller and are not counted.
+    pub fn body_length(&self) -> u64 {
+        match *self {
+            Output::File(ref outf) => outf.content_length(),
+            Output::FileRange(ref outf) => outf.content_length(),
+            _ => 0,
+        }
+    }
+    /// Returns true if connection must be closed after the response
+    ///
+    /// See `FileWrapper::must_close`, this is false for other outputs as
+    /// they have no body or body is generated by caller.
+    pub fn must_close(&self) -> bool {
+        match *self {
+            Output::File(ref outf) => outf.must_close(),
+            Output::FileRange(ref outf) => outf.must_close(),
+            _ => false,
+        }
+    }
+    /// Returns `(start, end, file_size)` of a single range being served
+    ///
+    /// See `Head::content_range`, this is `None` for outputs that don't
+    /// serve a file.
+    pub fn content_range(&self) -> Option<(u64, u64, u64)> {
+        match *self {
+            Output::FileHead(ref head) => head.content_range(),
+            Output::File(ref outf) => outf.content_range(),
+            Output::FileRange(ref outf) => outf.content_range(),
+            _ => None,
+        }
+    }
+    /// Check conditional headers of the new request against this output
+    ///
+    /// This is useful for caches that store the output (or just the
+    /// `Head`) to answer later conditional requests without touching the
+    /// disk. The `ETag` and `Last-Modified` of the stored response are
+    /// compared by the same rules as in `Input::probe_file`.
+    ///
+    /// Outputs without file (like `NotFound`) are always `Modified`. Note:
+    /// `PreconditionFailed` has no `Last-Modified` (and may have no etag),
+    /// so it's not useful to store it.
+    pub fn revalidate(&self, inp: &Input) -> Revalidation {
+        match *self {
+            Output::FileHead(ref head) => head.revalidate(inp),
+            Output::NotModified(ref head) => head.revalidate(inp),
+            Output::PreconditionFailed(ref head) => head.revalidate(inp),
+            Output::File(ref outf) => outf.head.revalidate(inp),
+            Output::FileRange(ref outf) => outf.head.revalidate(inp),
+            _ => Revalidation::Modified,
+        }
+    }
+    /// Returns the iterator over headers if there is a `Head`
+    ///
+    /// See `Head::headers` for the order of headers. Returns `None` for
+    /// outputs that have no `Head` (like `NotFound`).
+    pub fn headers(&self) -> Option<HeaderIter> {
+        match *self {
+            Output::FileHead(ref head)
+            | Output::InternalRedirect(ref head)
+            | Output::NotModified(ref head)
+            | Output::PreconditionFailed(ref head)
+            => Some(head.headers()),
+            Output::File(ref outf) | Output::FileRange(ref outf) => {
+                Some(outf.headers())
+            }
+            _ => None,
+        }
+    }
+    /// Returns the encoding of the file being served (if any)
+    pub fn encoding(&self) -> Option<Encoding> {
+        match *self {
+            Output::FileHead(ref head) => Some(head.encoding()),
+            Output::NotModified(ref head) => Some(head.encoding()),
+            Output::PreconditionFailed(ref head) => Some(head.encoding()),
+            Output::File(ref outf) => Some(outf.encoding()),
+            Output::FileRange(ref outf) => Some(outf.encoding()),
+            _ => None,
+        }
+    }
+}
+
+impl Unsatisfiable {
+    fn output<F>(inp: &Input, file_size: u64) -> Output<F> {
+        let body = match inp.config.range_error_body {
+            RangeErrorBody::Text => Some(("text/plain; charset=utf-8",
+                format!("Range not satisfiable, file length is {} bytes\n",
+                        file_size).into())),
+            RangeErrorBody::Json => Some(("application/json",
+                format!("{{\"length\": {}}}", file_size).into())),
+            _ => None,
+        };
+        Output::RangeNotSatisfiable(Unsatisfiable {
+            file_size: file_size,
+            body: body,
+        })
+    }
+    /// Returns the length of the file
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+    /// Returns the value of `Content-Range` header, e.g. `bytes */1234`
+    pub fn content_range(&self) -> String {
+        format!("bytes */{}", self.file_size)
+    }
+    /// Returns the value of `Content-Type` header if there is a body
+    pub fn content_type(&self) -> Option<&'static str> {
+        self.body.as_ref().map(|&(ctype, _)| ctype)
+    }
+    /// Returns the body of the response, empty by default
+    pub fn body(&self) -> &[u8] {
+        self.body.as_ref().map(|(_, body)| body.as_bytes()).unwrap_or(b"")
+    }
 }
 
-impl Output {
+impl fmt::Display for Vary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.accept, self.encoding) {
+            (true, true) => f.write_str("Accept, Accept-Encoding"),
+            (true, false) => f.write_str("Accept"),
+            (false, true) => f.write_str("Accept-Encoding"),
+            (false, false) => Ok(()),
+        }
+    }
 }
 
 impl fmt::Display for ContentRange {
@@ -329,25 +1183,60 @@ impl fmt::Display for ContentRange {
 
 impl fmt::Display for ContentType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if is_text_file(self.0) {
+        if is_text_file(&self.0) && !self.0.contains("charset=") {
             if let Some(ref charset) = self.1.text_charset {
                 write!(f, "{}; charset={}", self.0, charset)
             } else {
-                f.write_str(self.0)
+                f.write_str(&self.0)
             }
         } else {
-            f.write_str(self.0)
+            f.write_str(&self.0)
         }
     }
 }
 
+/// Number of bytes in the range, `None` if the range is invalid
+pub(crate) fn size_of_range(range: &ContentRange) -> Option<u64> {
+    range.end.checked_sub(range.start)?.checked_add(1)
+}
+
+fn is_satisfiable(range: &Option<Range>, size: u64) -> bool {
+    match *range {
+        Some(Range::MultipleRangesOfBytes(ref slices)) => {
+            resolve_multiple(slices, size).is_ok()
+        }
+        ref range => resolve_range(range, size).is_ok(),
+    }
+}
+
+/// Resolves all satisfiable ranges, skipping the unsatisfiable ones
+fn resolve_multiple(slices: &[Slice], size: u64)
+    -> Result<Vec<ContentRange>, ()>
+{
+    let parts = slices.iter()
+        .filter_map(|&slice| {
+            resolve_range(&Some(Range::SingleRangeOfBytes(slice)), size).ok()
+        })
+        .filter_map(|(range, clen)| if clen > 0 { range } else { None })
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        return Err(());
+    }
+    Ok(parts)
+}
+
 fn resolve_range(inp_range: &Option<Range>, size: u64)
-    -> Result<(Option<ContentRange>, u64), Output>
+    -> Result<(Option<ContentRange>, u64), ()>
 {
+    if size == 0 && inp_range.is_some() {
+        // there are no bytes to send, even for suffix range (`-10`), so
+        // it's 416 rather than 206 with an empty body
+        return Err(());
+    }
     let range = match *inp_range {
         Some(Range::SingleRangeOfBytes(Slice::FromTo(s, e))) => {
             if s >= size {
-                return Err(Output::InvalidRange);
+                return Err(());
             } else {
                 let nbytes = min(size - s, (e - s).saturating_add(1));
                 Some(ContentRange {
@@ -357,22 +1246,21 @@ fn resolve_range(inp_range: &Option<Range>, size: u64)
                 })
             }
         }
-        Some(Range::SingleRangeOfBytes(Slice::Last(mut nbytes))) => {
-            let start = if nbytes > size {
-                nbytes = size;
-                0
-            } else {
-                size - nbytes
-            };
+        Some(Range::SingleRangeOfBytes(Slice::Last(0))) => {
+            // empty suffix can't be satisfied, as in `Slice::resolve`
+            return Err(());
+        }
+        Some(Range::SingleRangeOfBytes(Slice::Last(nbytes))) => {
+            let start = size - min(nbytes, size);
             Some(ContentRange {
                 start: start,
-                end: (start + nbytes).saturating_sub(1),
+                end: size - 1,
                 file_size: size,
             })
         }
         Some(Range::SingleRangeOfBytes(Slice::AllFrom(start))) => {
             if start >= size {
-                return Err(Output::InvalidRange);
+                return Err(());
             } else {
                 Some(ContentRange {
                     start: start,
@@ -381,11 +1269,16 @@ fn resolve_range(inp_range: &Option<Range>, size: u64)
                 })
             }
         }
+        Some(Range::MultipleRangesOfBytes(ref slices)) => {
+            // callers are expected to use `resolve_multiple` instead,
+            // still if we're here, just serve the first range
+            return resolve_range(
+                &Some(Range::SingleRangeOfBytes(slices[0])), size);
+        }
         None => None,
     };
     let clen = match range {
-        Some(_) if size == 0 => 0,
-        Some(ref rng) => rng.end - rng.start + 1,
+        Some(ref rng) => size_of_range(rng).ok_or(())?,
         None => size,
     };
     return Ok((range, clen));
@@ -393,24 +1286,278 @@ fn resolve_range(inp_range: &Option<Range>, size: u64)
 
 #[cfg(test)]
 mod test {
+    use std::iter::empty;
     use std::mem::size_of;
+    use std::panic::{UnwindSafe, RefUnwindSafe};
+    use source::ManifestEntry;
+    use source::mock::{MockSource, MockFile};
     use super::*;
 
     fn send<T: Send>(_: &T) {}
     fn self_contained<T: 'static>(_: &T) {}
+    fn unwind_safe<T: UnwindSafe + RefUnwindSafe>() {}
 
     #[test]
     #[cfg(unix)]
     fn traits() {
-        let v = Output::NotFound;
+        let v: Output = Output::NotFound;
         send(&v);
         self_contained(&v);
     }
 
+    #[test]
+    fn unwind_safety() {
+        // so they can be used in handlers run under `catch_unwind`
+        unwind_safe::<Config>();
+        unwind_safe::<Arc<Config>>();
+        unwind_safe::<Input>();
+        unwind_safe::<Head>();
+    }
+
     #[cfg(all(target_arch="x86_64", target_os="linux"))]
     #[test]
     fn size() {
-        assert_eq!(size_of::<Output>(), 128);
+        assert_eq!(size_of::<Output>(), 208);
+    }
+
+    fn encoding_for(accept: &str) -> Option<Encoding> {
+        let headers = vec![("Accept-Encoding", accept.as_bytes())];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        inp.probe_file("public/index.html").unwrap().encoding()
+    }
+
+    const NOT_FOUND: &[u8] = b"<h1>Page Not Found</h1>";
+
+    fn error_page(headers: Vec<(&str, &[u8])>)
+        -> Output<Cursor<&'static [u8]>>
+    {
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        Output::error(&inp, 404, NOT_FOUND, "text/html")
+    }
+
+    #[test]
+    fn error_page_range() {
+        let mut outf = match error_page(vec![("Range", b"bytes=4-7")]) {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert_eq!(outf.status(), 404);
+        assert!(outf.is_partial());
+        assert_eq!(outf.content_length(), 4);
+        assert!(outf.headers().any(|(name, value)| name == "Content-Range" &&
+            value.to_string() == "bytes 4-7/23"));
+        let mut body = Vec::new();
+        while outf.read_chunk(&mut body).unwrap() > 0 {}
+        assert_eq!(body, b"Page");
+    }
+
+    #[test]
+    fn error_page_not_modified() {
+        let outf = match error_page(vec![]) {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert_eq!(outf.status(), 404);
+        assert!(outf.headers().any(|(name, value)| name == "Content-Type" &&
+            value.to_string() == "text/html; charset=utf-8"));
+        let etag = outf.headers().find(|&(name, _)| name == "ETag")
+            .map(|(_, value)| value.to_string()).unwrap();
+        match error_page(vec![("If-None-Match", etag.as_bytes())]) {
+            Output::NotModified(head) => assert_eq!(head.status(), 304),
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn transient_error() {
+        let err = io::Error::new(io::ErrorKind::TimedOut, "nfs timeout");
+        match Output::<File>::from_io_error(err, 5) {
+            Ok(Output::ServiceUnavailable(5)) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        let err = io::Error::new(io::ErrorKind::WouldBlock, "busy");
+        match Output::<File>::from_io_error(err, 1) {
+            Ok(Output::ServiceUnavailable(1)) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        let err = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        match Output::<File>::from_io_error(err, 5) {
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        match Output::<File>::service_unavailable(30) {
+            Output::ServiceUnavailable(30) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn served_encoding() {
+        assert_eq!(encoding_for(""), Some(Encoding::Identity));
+        assert_eq!(encoding_for("gzip"), Some(Encoding::Gzip));
+        assert_eq!(encoding_for("gzip, br"), Some(Encoding::Brotli));
+        // there is no `.zst` file
+        assert_eq!(encoding_for("zstd"), Some(Encoding::Identity));
+    }
+
+    fn headers_of(head: &Head) -> Vec<(String, String)> {
+        head.headers()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn probe_method(method: &str) -> Output {
+        let headers = vec![
+            ("Accept-Encoding", &b"gzip"[..]),
+            ("Range", &b"bytes=1-10"[..]),
+        ];
+        let inp = Input::from_headers(&Config::new().done(), method,
+                                      headers.into_iter());
+        inp.probe_file("public/index.html").unwrap()
+    }
+
+    fn lengths(method: &str, headers: Vec<(&str, &[u8])>) -> (u64, u64) {
+        let source = MockSource::new().file("/www/a.txt", &[b'x'; 100]);
+        let cfg = Config::new().multipart_boundary("B").done();
+        let inp = Input::from_headers(&cfg, method, headers.into_iter());
+        let out = inp.probe_with(&source, "/www/a.txt").unwrap();
+        (out.full_length(), out.body_length())
+    }
+
+    #[test]
+    fn from_open_file() {
+        use std::env::temp_dir;
+        use std::fs::remove_file;
+        use std::process;
+
+        let path = temp_dir().join(format!("http-file-headers-open-{}.txt",
+                                           process::id()));
+        File::create(&path).unwrap().write_all(b"hello world").unwrap();
+        let file = File::open(&path).unwrap();
+        let meta = Metadata::from(&file.metadata().unwrap());
+        remove_file(&path).unwrap();
+
+        let headers = vec![("Range", &b"bytes=6-"[..])];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        let mut outf = match Output::from_open_file(&inp, Encoding::Identity,
+            &meta, file, "text/x-custom").unwrap()
+        {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert!(outf.headers().any(|(name, value)| name == "Content-Type" &&
+            value.to_string() == "text/x-custom; charset=utf-8"));
+        assert!(!outf.headers().any(|(name, _)| name == "Vary"));
+        let mut body = Vec::new();
+        while outf.read_chunk(&mut body).unwrap() > 0 {}
+        assert_eq!(body, b"world");
+    }
+
+    #[test]
+    fn full_and_body_length() {
+        assert_eq!(lengths("GET", vec![]), (100, 100));
+        assert_eq!(lengths("GET", vec![("Range", b"bytes=10-19")]), (100, 10));
+        assert_eq!(lengths("HEAD", vec![]), (100, 0));
+        assert_eq!(lengths("HEAD", vec![("Range", b"bytes=10-19")]), (100, 0));
+        let delimiters = "\
+            --B\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Range: bytes 0-9/100\r\n\r\n\
+            \r\n--B\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Range: bytes 50-54/100\r\n\r\n\
+            \r\n--B--\r\n".len() as u64;
+        assert_eq!(lengths("GET", vec![("Range", b"bytes=0-9,50-54")]),
+                   (100, 15 + delimiters));
+        let etag = {
+            let source = MockSource::new().file("/www/a.txt", &[b'x'; 100]);
+            let inp = Input::from_headers(&Config::new().done(), "GET",
+                                          empty());
+            match inp.probe_with(&source, "/www/a.txt").unwrap() {
+                Output::File(outf) => outf.headers()
+                    .find(|&(name, _)| name == "ETag")
+                    .map(|(_, value)| value.to_string()).unwrap(),
+                x => panic!("unexpected output {:?}", x),
+            }
+        };
+        assert_eq!(lengths("GET", vec![("If-None-Match", etag.as_bytes())]),
+                   (100, 0));
+        assert_eq!(lengths("POST", vec![]), (0, 0));
+    }
+
+    #[test]
+    fn head_and_get() {
+        let get = match probe_method("GET") {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        let head = match probe_method("HEAD") {
+            Output::FileHead(head) => head,
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert_eq!(headers_of(&get.head), headers_of(&head));
+        assert_eq!(get.content_length(), head.content_length());
+        assert_eq!(get.is_partial(), head.is_partial());
+        assert_eq!(get.content_length(), 10);
+        let get_headers = headers_of(&get.head);
+        match Output::File(get).suppress_body() {
+            Output::FileHead(head) => {
+                assert_eq!(headers_of(&head), get_headers);
+                assert_eq!(head.content_length(), 10);
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn x_gzip_encoding() {
+        let headers = vec![("Accept-Encoding", &b"x-gzip"[..])];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        let outf = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert_eq!(outf.encoding(), Encoding::Gzip);
+        assert!(outf.headers().any(|(name, value)|
+            name == "Content-Encoding" && value.to_string() == "gzip"));
+    }
+
+    #[test]
+    fn truncated_file() {
+        let source = MockSource::new()
+            .file_with_len("/www/file.txt", b"hello", 10);
+        let inp = Input::from_headers(&Config::new().done(), "GET", empty());
+        let mut outf = match inp.probe_with(&source, "/www/file.txt") {
+            Ok(Output::File(outf)) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert_eq!(outf.content_length(), 10);
+        let mut body = Vec::new();
+        assert_eq!(outf.read_chunk(&mut body).unwrap(), 5);
+        assert!(!outf.must_close());
+        let err = outf.read_chunk(&mut body).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(outf.must_close());
+        assert!(Output::File(outf).must_close());
+    }
+
+    #[test]
+    fn complete_file_keeps_connection() {
+        let source = MockSource::new().file("/www/file.txt", b"hello");
+        let inp = Input::from_headers(&Config::new().done(), "GET", empty());
+        let mut outf = match inp.probe_with(&source, "/www/file.txt") {
+            Ok(Output::File(outf)) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        let mut body = Vec::new();
+        while outf.read_chunk(&mut body).unwrap() > 0 {}
+        assert_eq!(body, b"hello");
+        assert!(!outf.must_close());
+        assert!(!Output::<File>::NotFound.must_close());
     }
 
     #[test]
@@ -459,10 +1606,30 @@ mod test {
 
     #[test]
     fn range_on_zero_length() {
-        assert_eq!(resolve(last(100), 0), res(0, 0, 0));
-        assert_eq!(resolve_clen(last(100), 0), 0);
+        resolve_range(&Some(last(100)), 0).unwrap_err();
+        resolve_range(&Some(from(0)), 0).unwrap_err();
         resolve_range(&Some(from(100)), 0).unwrap_err();
         resolve_range(&Some(range(0, 100)), 0).unwrap_err();
+        assert_eq!(resolve_range(&None, 0), Ok((None, 0)));
+    }
+
+    #[test]
+    fn range_on_empty_file() {
+        let source = MockSource::new().file("/www/empty.txt", b"");
+        let probe = |range: &str| {
+            let headers = vec![("Range", range.as_bytes())];
+            let inp = Input::from_headers(&Config::new().done(), "GET",
+                                          headers.into_iter());
+            inp.probe_with(&source, "/www/empty.txt").unwrap()
+        };
+        for range in &["bytes=0-", "bytes=-10", "bytes=0-0,-1"] {
+            match probe(range) {
+                Output::RangeNotSatisfiable(err) => {
+                    assert_eq!(err.content_range(), "bytes */0");
+                }
+                x => panic!("unexpected output {:?}", x),
+            }
+        }
     }
 
     #[test]
@@ -474,10 +1641,652 @@ mod test {
         assert_eq!(resolve_clen(range(10, 1000), 100), 90);
     }
 
+    #[test]
+    fn range_at_the_end() {
+        resolve_range(&Some(from(100)), 100).unwrap_err();
+        resolve_range(&Some(range(100, 200)), 100).unwrap_err();
+        assert_eq!(resolve(from(99), 100), res(99, 99, 100));
+        assert_eq!(resolve_clen(from(99), 100), 1);
+        assert_eq!(resolve(range(99, 200), 100), res(99, 99, 100));
+        assert_eq!(resolve_clen(range(99, 200), 100), 1);
+        assert_eq!(resolve(last(1), 100), res(99, 99, 100));
+    }
+
+    #[test]
+    fn range_at_the_end_of_file() {
+        let source = MockSource::new().file("/www/file.txt", b"0123456789");
+        let cfg = Config::new().done();
+        let headers = vec![("Range", &b"bytes=10-"[..])];
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        match inp.probe_with(&source, "/www/file.txt") {
+            Ok(Output::RangeNotSatisfiable(_)) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        let headers = vec![("Range", &b"bytes=9-"[..])];
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        let mut outf = match inp.probe_with(&source, "/www/file.txt") {
+            Ok(Output::File(outf)) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert!(outf.is_partial());
+        assert_eq!(outf.content_length(), 1);
+        let mut body = Vec::new();
+        while outf.read_chunk(&mut body).unwrap() > 0 {}
+        assert_eq!(body, b"9");
+    }
+
+    fn probe_huge(range: &str) -> Output<MockFile> {
+        let source = MockSource::new()
+            .file_with_len("/www/huge", b"", u64::MAX);
+        let headers = vec![("Range", range.as_bytes())];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        inp.probe_with(&source, "/www/huge").unwrap()
+    }
+
+    #[test]
+    fn huge_file_ranges() {
+        match probe_huge("bytes=0-") {
+            Output::File(ref outf) => {
+                assert_eq!(outf.content_length(), u64::MAX);
+                assert!(outf.headers().any(|(name, value)|
+                    name == "Content-Range" &&
+                    value.to_string() == format!("bytes 0-{}/{}",
+                        u64::MAX - 1, u64::MAX)));
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        match probe_huge("bytes=-18446744073709551615") {
+            Output::File(ref outf) => {
+                assert_eq!(outf.content_length(), u64::MAX);
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        match probe_huge("bytes=18446744073709551614-18446744073709551615") {
+            Output::File(ref outf) => assert_eq!(outf.content_length(), 1),
+            x => panic!("unexpected output {:?}", x),
+        }
+        match probe_huge("bytes=18446744073709551615-") {
+            Output::RangeNotSatisfiable(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        // empty suffix would be a range where start is after the end
+        match probe_huge("bytes=-0") {
+            Output::RangeNotSatisfiable(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn multipart_overflow() {
+        match probe_huge("bytes=0-9223372036854775807, \
+                          -9223372036854775807")
+        {
+            Output::RangeNotSatisfiable(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        // empty suffix is skipped as any other unsatisfiable part
+        match probe_huge("bytes=0-0,-0") {
+            Output::File(ref outf) => {
+                assert_eq!(outf.content_length(), 1);
+                assert!(outf.headers().any(|(name, value)|
+                    name == "Content-Range" &&
+                    value.to_string() == format!("bytes 0-0/{}", u64::MAX)));
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        match probe_huge("bytes=-0,5-6") {
+            Output::File(ref outf) => assert_eq!(outf.content_length(), 2),
+            x => panic!("unexpected output {:?}", x),
+        }
+        match probe_huge("bytes=-0,-0") {
+            Output::RangeNotSatisfiable(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
     #[test]
     fn norm_ranges() {
         assert_eq!(resolve(last(1000), 10000), res(9000, 9999, 10000));
         assert_eq!(resolve(range(100, 1000), 10000), res(100, 1000, 10000));
         assert_eq!(resolve(from(777), 10000), res(777, 9999, 10000));
     }
+
+    fn conditional(headers: Vec<(&str, &[u8])>) -> Output<MockFile> {
+        let source = MockSource::new()
+            .file("/www/doc.txt", b"hello")
+            .file("/www/strong.txt", b"hello")
+            .manifest("/www/strong.txt", ManifestEntry::new("v1"));
+        let mut cfg = Config::new();
+        cfg.date(true);
+        let inp = Input::from_headers(&cfg.done(), "GET",
+                                      headers.into_iter());
+        let path = if !inp.if_match.is_empty() {
+            "/www/strong.txt"
+        } else {
+            "/www/doc.txt"
+        };
+        inp.probe_with(&source, path).unwrap()
+    }
+
+    fn names(head: &Head) -> Vec<&str> {
+        head.headers().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn not_modified_headers() {
+        let etag = match conditional(vec![]) {
+            Output::File(outf) => outf.headers()
+                .find(|&(name, _)| name == "ETag")
+                .map(|(_, value)| value.to_string()).unwrap(),
+            x => panic!("unexpected output {:?}", x),
+        };
+        match conditional(vec![("If-None-Match", etag.as_bytes())]) {
+            Output::NotModified(head) => {
+                assert_eq!(head.status(), 304);
+                assert_eq!(head.content_length(), 0);
+                assert_eq!(names(&head), vec!["Date", "Last-Modified", "ETag"]);
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn cache_control_on_not_modified() {
+        let source = MockSource::new().file("/www/doc.txt", b"hello");
+        let cfg = Config::new()
+            .date(true)
+            .cache_control("public, max-age=3600")
+            .done();
+        let probe = |headers: Vec<(&str, &[u8])>| {
+            let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+            inp.probe_with(&source, "/www/doc.txt").unwrap()
+        };
+        let etag = match probe(vec![]) {
+            Output::File(outf) => {
+                assert!(outf.headers().any(|(name, value)| {
+                    name == "Cache-Control" &&
+                    value.to_string() == "public, max-age=3600"
+                }));
+                outf.headers().find(|&(name, _)| name == "ETag")
+                    .map(|(_, value)| value.to_string()).unwrap()
+            }
+            x => panic!("unexpected output {:?}", x),
+        };
+        match probe(vec![("If-None-Match", etag.as_bytes())]) {
+            Output::NotModified(head) => {
+                assert_eq!(headers_of(&head), vec![
+                    (String::from("Date"),
+                     head.date.unwrap().to_string()),
+                    (String::from("Last-Modified"),
+                     String::from("Tue, 22 Aug 2017 20:47:13 GMT")),
+                    (String::from("ETag"), etag.clone()),
+                    (String::from("Cache-Control"),
+                     String::from("public, max-age=3600")),
+                ]);
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        let inp = Input::from_headers(&cfg, "GET", empty());
+        match Output::error(&inp, 404, NOT_FOUND, "text/html") {
+            Output::File(outf) => {
+                assert!(!outf.headers().any(|(n, _)| n == "Cache-Control"));
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn server_header() {
+        let source = MockSource::new().file("/www/doc.txt", b"hello");
+        let server = |cfg: &Arc<Config>, headers: Vec<(&str, &[u8])>| {
+            let inp = Input::from_headers(cfg, "GET", headers.into_iter());
+            let head = match inp.probe_with(&source, "/www/doc.txt").unwrap() {
+                Output::File(outf) => outf.head,
+                Output::NotModified(head) => head,
+                x => panic!("unexpected output {:?}", x),
+            };
+            assert_eq!(names(&head)[0], "Server");
+            head.headers().find(|&(name, _)| name == "Server")
+                .map(|(_, value)| value.to_string())
+        };
+        let cfg = Config::new().server_header(Some("tk/1.0")).done();
+        assert_eq!(server(&cfg, vec![]), Some(String::from("tk/1.0")));
+        let date = b"Tue, 22 Aug 2017 20:47:13 GMT";
+        assert_eq!(server(&cfg, vec![("If-Modified-Since", date)]),
+                   Some(String::from("tk/1.0")));
+        let inp = Input::from_headers(&cfg, "GET", empty());
+        match Output::error(&inp, 404, NOT_FOUND, "text/html") {
+            Output::File(outf) => assert_eq!(names(&outf.head)[0], "Server"),
+            x => panic!("unexpected output {:?}", x),
+        }
+
+        let inp = Input::from_headers(&Config::new().done(), "GET", empty());
+        match inp.probe_with(&source, "/www/doc.txt").unwrap() {
+            Output::File(outf) => {
+                assert!(!names(&outf.head).contains(&"Server"));
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn golden_headers() {
+        let source = MockSource::new()
+            .file("/www/index.html", b"<html>")
+            .file("/www/index.html.gz", b"compressed");
+        let cfg = Config::new()
+            .date(true)
+            .server_header(Some("tk/1.0"))
+            .cache_control("no-cache")
+            .add_preload("/www/index.html", "/app.js", "script")
+            .done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+        let mut output = inp.probe_with(&source, "/www/index.html").unwrap();
+        let date = match output {
+            Output::File(ref mut outf) => {
+                outf.append_header("X-Frame-Options", "DENY");
+                outf.head.date.unwrap().to_string()
+            }
+            ref x => panic!("unexpected output {:?}", x),
+        };
+        // etag of the identity file with the encoding appended
+        let mtime = UNIX_EPOCH + Duration::new(1503434833, 0);
+        let etag = Etag::from_metadata(&Metadata::file(6, Some(mtime)),
+                                       cfg.etag_strategy)
+            .encoded(Encoding::Gzip);
+        let headers = output.headers().unwrap()
+            .map(|(name, value)| format!("{}: {}\n", name, value))
+            .collect::<String>();
+        assert_eq!(headers, format!("\
+            Date: {}\n\
+            Server: tk/1.0\n\
+            Last-Modified: Tue, 22 Aug 2017 20:47:13 GMT\n\
+            ETag: {}\n\
+            Vary: Accept-Encoding\n\
+            Cache-Control: no-cache\n\
+            Content-Encoding: gzip\n\
+            Accept-Ranges: bytes\n\
+            Content-Type: text/html; charset=utf-8\n\
+            Link: </app.js>; rel=preload; as=script\n\
+            X-Frame-Options: DENY\n\
+            ", date, etag));
+        assert!(Output::<MockFile>::NotFound.headers().is_none());
+    }
+
+    #[test]
+    fn internal_redirect() {
+        let redirect = |cfg: &Arc<Config>, method: &str| {
+            let headers = vec![("Range", &b"bytes=0-10"[..])];
+            let inp = Input::from_headers(cfg, method, headers.into_iter());
+            Output::<MockFile>::internal_redirect(&inp, "/protected/a.txt")
+        };
+        let cfg = Config::new()
+            .internal_redirect_header("X-Sendfile")
+            .cache_control("no-cache")
+            .done();
+        match redirect(&cfg, "GET") {
+            Output::InternalRedirect(head) => {
+                assert_eq!(head.status(), 200);
+                assert_eq!(head.content_length(), 0);
+                assert_eq!(headers_of(&head), vec![
+                    (String::from("Cache-Control"), String::from("no-cache")),
+                    (String::from("Content-Type"),
+                     String::from("text/plain; charset=utf-8")),
+                    (String::from("X-Sendfile"),
+                     String::from("/protected/a.txt")),
+                ]);
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        match redirect(&Config::new().done(), "HEAD") {
+            Output::InternalRedirect(head) => {
+                assert!(head.headers().any(|(name, value)| {
+                    name == "X-Accel-Redirect" &&
+                    value.to_string() == "/protected/a.txt"
+                }));
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        match redirect(&Config::new().done(), "POST") {
+            Output::InvalidMethod => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn strong_etags() {
+        let source = MockSource::new().file("/www/doc.txt", b"hello");
+        let cfg = Config::new().strong_etags(true).done();
+        let probe = |headers: Vec<(&str, &[u8])>| {
+            let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+            inp.probe_with(&source, "/www/doc.txt").unwrap()
+        };
+        let etag = match probe(vec![]) {
+            Output::File(outf) => outf.headers()
+                .find(|&(name, _)| name == "ETag")
+                .map(|(_, value)| value.to_string()).unwrap(),
+            x => panic!("unexpected output {:?}", x),
+        };
+        assert!(etag.starts_with('"'));
+        match probe(vec![("If-None-Match", etag.as_bytes())]) {
+            Output::NotModified(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        let weak = format!("W/{}", etag);
+        match probe(vec![("If-None-Match", weak.as_bytes())]) {
+            Output::File(outf) => assert_eq!(outf.status(), 200),
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn custom_headers() {
+        let mut outf = match conditional(vec![]) {
+            Output::File(outf) => outf,
+            x => panic!("unexpected output {:?}", x),
+        };
+        outf.append_header("Set-Cookie", "a=1")
+            .append_header("Set-Cookie", "b=2")
+            .set_header("X-Frame-Options", "DENY")
+            .set_header("x-frame-options", "SAMEORIGIN")
+            // generated headers are never replaced
+            .set_header("ETag", "\"custom\"");
+        let headers = headers_of(&outf.head);
+        let custom = &headers[headers.len() - 4..];
+        assert_eq!(custom, &[
+            (String::from("Set-Cookie"), String::from("a=1")),
+            (String::from("Set-Cookie"), String::from("b=2")),
+            (String::from("x-frame-options"), String::from("SAMEORIGIN")),
+            (String::from("ETag"), String::from("\"custom\"")),
+        ]);
+        assert_eq!(headers.iter().filter(|(n, _)| n == "ETag").count(), 2);
+
+        let mut head = match conditional(vec![
+            ("If-Modified-Since", b"Tue, 22 Aug 2017 20:47:13 GMT"),
+        ]) {
+            Output::NotModified(head) => head,
+            x => panic!("unexpected output {:?}", x),
+        };
+        head.append_header("Set-Cookie", "a=1");
+        assert_eq!(names(&head),
+                   vec!["Date", "Last-Modified", "ETag", "Set-Cookie"]);
+    }
+
+    #[test]
+    fn revalidate() {
+        let stored = conditional(vec![]);
+        let etag = match stored {
+            Output::File(ref outf) => outf.headers()
+                .find(|&(name, _)| name == "ETag")
+                .map(|(_, value)| value.to_string()).unwrap(),
+            ref x => panic!("unexpected output {:?}", x),
+        };
+        let check = |method: &str, headers: Vec<(&str, &[u8])>| {
+            let inp = Input::from_headers(&Config::new().done(), method,
+                                          headers.into_iter());
+            stored.revalidate(&inp)
+        };
+        assert_eq!(check("GET", vec![("If-None-Match", etag.as_bytes())]),
+                   Revalidation::NotModified);
+        assert_eq!(check("GET", vec![("If-None-Match", b"\"other\"")]),
+                   Revalidation::Modified);
+        assert_eq!(check("GET", vec![
+            ("If-Modified-Since", b"Tue, 22 Aug 2017 20:47:13 GMT"),
+        ]), Revalidation::NotModified);
+        assert_eq!(check("GET", vec![
+            ("If-Unmodified-Since", b"Mon, 21 Aug 2017 20:47:13 GMT"),
+        ]), Revalidation::PreconditionFailed);
+        assert_eq!(check("GET", vec![]), Revalidation::Modified);
+        assert_eq!(Output::<MockFile>::NotFound.revalidate(
+            &Input::from_headers(&Config::new().done(), "GET",
+                vec![("If-None-Match", etag.as_bytes())].into_iter())),
+            Revalidation::Modified);
+    }
+
+    #[test]
+    fn etag_takes_precedence_over_date() {
+        let (etag, last_modified) = match conditional(vec![]) {
+            Output::File(outf) => {
+                let header = |name: &str| outf.headers()
+                    .find(|&(n, _)| n == name)
+                    .map(|(_, value)| value.to_string()).unwrap();
+                (header("ETag"), header("Last-Modified"))
+            }
+            x => panic!("unexpected output {:?}", x),
+        };
+        // date says modified, etag matches
+        let old = b"Thu, 01 Jan 2015 00:00:00 GMT";
+        match conditional(vec![("If-None-Match", etag.as_bytes()),
+                               ("If-Modified-Since", &old[..])]) {
+            Output::NotModified(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        // date says not modified, etag doesn't match
+        match conditional(vec![("If-None-Match", &b"W/\"other\""[..]),
+                               ("If-Modified-Since",
+                                last_modified.as_bytes())]) {
+            Output::File(outf) => assert_eq!(outf.status(), 200),
+            x => panic!("unexpected output {:?}", x),
+        }
+        match conditional(vec![("If-Modified-Since",
+                                last_modified.as_bytes())]) {
+            Output::NotModified(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn precondition_failed_headers() {
+        let date = b"Thu, 01 Jan 2015 00:00:00 GMT";
+        match conditional(vec![("If-Unmodified-Since", &date[..])]) {
+            Output::PreconditionFailed(head) => {
+                assert_eq!(head.status(), 412);
+                assert_eq!(head.content_length(), 0);
+                assert_eq!(names(&head), vec!["Date"]);
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        match conditional(vec![("If-Match", &b"\"v2\""[..])]) {
+            Output::PreconditionFailed(head) => {
+                assert_eq!(head.status(), 412);
+                assert_eq!(names(&head), vec!["Date", "ETag"]);
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        for val in &[&b"\"v1\""[..], &b"*"[..], &b"\"v2\", \"v1\""[..]] {
+            match conditional(vec![("If-Match", *val),
+                                   ("If-Unmodified-Since", &date[..])]) {
+                Output::File(outf) => assert_eq!(outf.status(), 200),
+                x => panic!("unexpected output {:?}", x),
+            }
+        }
+        // weak etags never match strongly
+        match conditional(vec![("If-Match", &b"W/\"v1\""[..])]) {
+            Output::PreconditionFailed(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn none_match_on_other_methods() {
+        let etag = match error_page(vec![]) {
+            Output::File(outf) => outf.headers()
+                .find(|&(name, _)| name == "ETag")
+                .map(|(_, value)| value.to_string()).unwrap(),
+            x => panic!("unexpected output {:?}", x),
+        };
+        let page = |method: &str, headers: Vec<(&str, &[u8])>| {
+            let inp = Input::from_headers(&Config::new().done(), method,
+                                          headers.into_iter());
+            Output::error(&inp, 404, NOT_FOUND, "text/html")
+        };
+        let matching = vec![("If-None-Match", etag.as_bytes())];
+        match page("GET", matching.clone()) {
+            Output::NotModified(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        match page("OPTIONS", matching) {
+            Output::PreconditionFailed(head) => {
+                assert_eq!(head.status(), 412);
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        // passing If-Match doesn't skip the check of If-None-Match
+        let both = vec![("If-Match", &b"*"[..]),
+                        ("If-None-Match", etag.as_bytes())];
+        match page("OPTIONS", both.clone()) {
+            Output::PreconditionFailed(head) => {
+                assert_eq!(head.status(), 412);
+            }
+            x => panic!("unexpected output {:?}", x),
+        }
+        match page("GET", both) {
+            Output::NotModified(_) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        // If-Modified-Since is ignored for other methods
+        let meta = Metadata::file(5,
+            Some(UNIX_EPOCH + Duration::new(1503434833, 0)));
+        let modified = |method: &str| {
+            let headers = vec![("If-Modified-Since",
+                                &b"Tue, 22 Aug 2017 20:47:13 GMT"[..])];
+            let inp = Input::from_headers(&Config::new().done(), method,
+                                          headers.into_iter());
+            Head::from_meta::<MockFile>(&inp, Encoding::Identity, &meta,
+                Cow::Borrowed("text/plain"), None, Vary::default())
+        };
+        match modified("GET").map_err(|output| *output) {
+            Err(Output::NotModified(_)) => {}
+            x => panic!("unexpected output {:?}", x),
+        }
+        match modified("OPTIONS") {
+            Ok(head) => assert_eq!(head.status(), 200),
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn content_range_accessor() {
+        let source = MockSource::new().file("/www/file.txt", b"0123456789");
+        let probe = |range: &str| {
+            let headers = vec![("Range", range.as_bytes())];
+            let inp = Input::from_headers(&Config::new().done(), "GET",
+                                          headers.into_iter());
+            let output = inp.probe_with(&source, "/www/file.txt").unwrap();
+            let header = match output {
+                Output::File(ref outf) => outf.headers()
+                    .find(|&(name, _)| name == "Content-Range")
+                    .map(|(_, value)| value.to_string()),
+                ref x => panic!("unexpected output {:?}", x),
+            };
+            (output.content_range(), header)
+        };
+        for range in &["bytes=2-5", "bytes=-3", "bytes=7-", "bytes=0-100"] {
+            let (value, header) = probe(range);
+            let (start, end, size) = value.unwrap();
+            assert_eq!(format!("bytes {}-{}/{}", start, end, size),
+                       header.unwrap());
+        }
+        assert_eq!(probe("bytes=2-5").0, Some((2, 5, 10)));
+        assert_eq!(probe("bytes=0-1,5-6"), (None, None));
+    }
+
+    fn unsatisfiable(cfg: &Config) -> Unsatisfiable {
+        let source = MockSource::new().file("/www/file.txt", b"0123456789");
+        let headers = vec![("Range", &b"bytes=20-30"[..])];
+        let inp = Input::from_headers(&cfg.done(), "GET",
+                                      headers.into_iter());
+        match inp.probe_with(&source, "/www/file.txt").unwrap() {
+            Output::RangeNotSatisfiable(err) => err,
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn range_error_body() {
+        let err = unsatisfiable(&Config::new());
+        assert_eq!(err.content_range(), "bytes */10");
+        assert_eq!(err.content_type(), None);
+        assert_eq!(err.body(), b"");
+
+        let err = unsatisfiable(
+            Config::new().range_error_body(RangeErrorBody::Json));
+        assert_eq!(err.content_range(), "bytes */10");
+        assert_eq!(err.content_type(), Some("application/json"));
+        assert_eq!(err.body(), &b"{\"length\": 10}"[..]);
+
+        let err = unsatisfiable(
+            Config::new().range_error_body(RangeErrorBody::Text));
+        assert_eq!(err.content_type(), Some("text/plain; charset=utf-8"));
+        assert_eq!(err.body(),
+                   &b"Range not satisfiable, file length is 10 bytes\n"[..]);
+    }
+
+    #[test]
+    fn mtime_size_etag() {
+        use config::EtagStrategy;
+
+        let served = |cfg: &Config, nanos: u32, inode: u64, etag: &str| {
+            let mut meta = Metadata::file(5,
+                Some(UNIX_EPOCH + Duration::new(1503434833, nanos)));
+            meta.unix = Some((1, inode, 1503434833, nanos as i64));
+            let headers = vec![("If-None-Match", etag.as_bytes())];
+            let inp = Input::from_headers(&cfg.done(), "GET",
+                                          headers.into_iter());
+            let file = Cursor::new(&b"hello"[..]);
+            match Output::from_open_file(&inp, Encoding::Identity, &meta,
+                                         file, "text/plain").unwrap()
+            {
+                Output::File(outf) => outf.headers()
+                    .find(|&(name, _)| name == "ETag")
+                    .map(|(_, value)| value.to_string()).unwrap(),
+                Output::NotModified(_) => String::from("304"),
+                x => panic!("unexpected output {:?}", x),
+            }
+        };
+        let mut weak = Config::new();
+        weak.etag_strategy(EtagStrategy::MtimeSizeWeak);
+        let etag = served(&weak, 100, 7, "");
+        assert!(etag.starts_with("W/\""));
+        assert_eq!(served(&weak, 200, 8, &etag), "304");
+        assert_eq!(served(&weak, 200, 8, "W/\"other\""), etag);
+
+        let default = Config::new();
+        let etag = served(&default, 100, 7, "");
+        assert_ne!(served(&default, 200, 8, &etag), "304");
+    }
+
+    fn resume(if_range: &str, range: &str) -> (u16, u64) {
+        let source = MockSource::new()
+            .file("/www/video.mp4", b"0123456789")
+            .manifest("/www/video.mp4", ManifestEntry::new("v2"));
+        let headers = vec![("Range", range.as_bytes()),
+                           ("If-Range", if_range.as_bytes())];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        match inp.probe_with(&source, "/www/video.mp4").unwrap() {
+            Output::File(outf) => (outf.status(), outf.content_length()),
+            Output::RangeNotSatisfiable(_) => (416, 0),
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn if_range_resume() {
+        let date = "Tue, 22 Aug 2017 20:47:13 GMT";
+        // etag or date still matches, resume from the offset
+        assert_eq!(resume("\"v2\"", "bytes=4-"), (206, 6));
+        assert_eq!(resume(date, "bytes=4-"), (206, 6));
+        // file is changed, restart
+        assert_eq!(resume("\"v1\"", "bytes=4-"), (200, 10));
+        assert_eq!(resume("W/\"v2\"", "bytes=4-"), (200, 10));
+        assert_eq!(resume("Tue, 22 Aug 2017 20:47:12 GMT", "bytes=4-"),
+                   (200, 10));
+        assert_eq!(resume("garbage", "bytes=4-"), (200, 10));
+        // file has shrunk, restart instead of failing
+        assert_eq!(resume("\"v2\"", "bytes=20-"), (200, 10));
+        assert_eq!(resume(date, "bytes=20-,30-"), (200, 10));
+    }
 }
@@ -0,0 +1,12 @@
+//! Header parsers that don't need `std`
+//!
+//! These are used by `Input` internally, but are also available when
+//! crate is built without `std` feature (only `alloc` is required), so
+//! that servers which can't use files may still reuse negotiation logic.
+//!
+//! Dates (`If-Modified-Since`) and entity tags are not here, because
+//! their parsing and comparison depends on the system time and on our
+//! own etag format.
+pub use accept_encoding::{AcceptEncoding, AcceptEncodingParser};
+pub use range::{Range, RangeSet, Resolved, Slice, RangeParser};
+pub use range::InvalidRange;
@@ -1,28 +1,86 @@
-use std::u64;
-use std::str::from_utf8;
+use core::u64;
+use core::slice;
+use core::str::from_utf8;
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
+
+/// Single range of bytes as specified in the header
+///
+/// Bounds are not checked against the size of the file here
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Slice {
+    /// Range `start-end`, both inclusive
     FromTo(u64, u64),
+    /// Range `start-`, till the end of file
     AllFrom(u64),
+    /// Range `-num`, last `num` bytes of the file
     Last(u64),
 }
 
+/// Parsed `Range` header
 #[derive(Clone, Debug, PartialEq)]
 pub enum Range {
+    /// Single range (or multiple ones that are merged into one)
     SingleRangeOfBytes(Slice),
-    // TODO(tailhook) support muliple ranges
-    //                this requires mutlipart/byteranges though which isn't
-    //                easy to implement
+    /// Ranges that can't be merged, served as `multipart/byteranges`
+    MultipleRangesOfBytes(Box<[Slice]>),
     // TODO(tailhook) maybe support other range units
 }
 
+/// Ranges of the request regardless of whether there is one or many
+///
+/// Returned by `Range::as_set` (and `Input::range`). Ranges are kept as
+/// specified in the header (after merging), they are resolved against
+/// the length of the file by `iter`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RangeSet<'a> {
+    slices: &'a [Slice],
+}
+
+/// Iterator over resolved ranges, see `RangeSet::iter`
+#[derive(Debug)]
+pub struct Resolved<'a> {
+    slices: slice::Iter<'a, Slice>,
+    total: u64,
+}
+
+/// Error of `RangeParser`, the `Range` header is invalid or duplicate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidRange;
+
+/// Parser of `Range` header
+#[derive(Debug)]
 pub struct RangeParser {
     // TODO(tailhook) maybe have better error
     result: Result<Option<Range>, ()>,
+    /// bytes of header values that can still be parsed
+    bytes_left: usize,
 }
 
+/// Default limit of the length of `Range` header
+///
+/// This is enough for hundreds of ranges.
+const DEFAULT_LIMIT: usize = 8192;
+
+
+/// Parses `1*DIGIT`, leading zeros are allowed, overflow is an error
+///
+/// Unlike `str::parse` this doesn't accept a `+` sign.
+fn parse_u64(value: &str) -> Result<u64, ()> {
+    if value.is_empty() {
+        return Err(());
+    }
+    value.bytes().try_fold(0u64, |acc, c| {
+        if !c.is_ascii_digit() {
+            return Err(());
+        }
+        acc.checked_mul(10)
+            .and_then(|x| x.checked_add((c - b'0') as u64))
+            .ok_or(())
+    })
+}
 
 fn parse_slice(slc: &str) -> Result<Slice, ()> {
     let mut pair = slc.splitn(2, "-");
@@ -31,14 +89,14 @@ fn parse_slice(slc: &str) -> Result<Slice, ()> {
         (None, _) => Err(()),
         (_, None) => Err(()),
         (Some(""), Some(x)) => {
-            Ok(Slice::Last(x.parse().map_err(|_| ())?))
+            Ok(Slice::Last(parse_u64(x)?))
         }
         (Some(x), Some("")) => {
-            Ok(Slice::AllFrom(x.parse().map_err(|_| ())?))
+            Ok(Slice::AllFrom(parse_u64(x)?))
         }
         (Some(x), Some(y)) => {
-            let x = x.parse().map_err(|_| ())?;
-            let y = y.parse().map_err(|_| ())?;
+            let x = parse_u64(x)?;
+            let y = parse_u64(y)?;
             if x > y {
                 return Err(());
             }
@@ -48,6 +106,24 @@ fn parse_slice(slc: &str) -> Result<Slice, ()> {
 }
 
 impl Slice {
+    /// Returns `(start, end)` (both inclusive) for the file of `total` bytes
+    ///
+    /// Returns `None` if the slice doesn't overlap the file.
+    pub fn resolve(&self, total: u64) -> Option<(u64, u64)> {
+        if total == 0 {
+            return None;
+        }
+        match *self {
+            Slice::FromTo(start, end) if start < total => {
+                Some((start, if end < total { end } else { total - 1 }))
+            }
+            Slice::AllFrom(start) if start < total => Some((start, total - 1)),
+            Slice::Last(0) => None,
+            Slice::Last(num) if num < total => Some((total - num, total - 1)),
+            Slice::Last(_) => Some((0, total - 1)),
+            _ => None,
+        }
+    }
     fn merge(&mut self, other: Slice) -> bool {
         use self::Slice::*;
 
@@ -55,12 +131,12 @@ impl Slice {
 
             // contained range
             (&mut FromTo(x1, y1), FromTo(x2, y2))
-            if x1 >= x2 && y1 <= y2
+            if x2 >= x1 && y2 <= y1
             => true,
 
             // reverse contained range
             (&mut FromTo(ref mut x1, ref mut y1), FromTo(x2, y2))
-            if x2 >= *x1 && y2 <= *y1
+            if *x1 >= x2 && *y1 <= y2
             => {
                 *x1 = x2;
                 *y1 = y2;
@@ -69,7 +145,7 @@ impl Slice {
 
             // adjancent range
             (&mut FromTo(x1, ref mut y1), FromTo(x2, y2))
-            if x2 >= x1 && x2 <= *y1+1
+            if x2 >= x1 && x2 <= y1.saturating_add(1)
             => {
                 *y1 = y2;
                 true
@@ -77,7 +153,7 @@ impl Slice {
 
             // reverse adjacent range
             (&mut FromTo(ref mut x1, _y1), FromTo(x2, y2))
-            if y2+1 >= *x1 && x2 < *x1
+            if y2.saturating_add(1) >= *x1 && x2 < *x1
             => {
                 *x1 = x2;
                 true
@@ -89,6 +165,71 @@ impl Slice {
     }
 }
 
+impl Range {
+    /// Returns the ranges as a set, that hides the difference between
+    /// single and multiple ranges
+    pub fn as_set(&self) -> RangeSet {
+        match *self {
+            Range::SingleRangeOfBytes(ref slice) => RangeSet {
+                slices: slice::from_ref(slice),
+            },
+            Range::MultipleRangesOfBytes(ref slices) => RangeSet {
+                slices: slices,
+            },
+        }
+    }
+}
+
+impl<'a> RangeSet<'a> {
+    /// Number of ranges requested (ranges that overlap are merged)
+    pub fn len(&self) -> usize {
+        self.slices.len()
+    }
+    /// Returns true if there are no ranges (never for a parsed header)
+    pub fn is_empty(&self) -> bool {
+        self.slices.is_empty()
+    }
+    /// Returns true if multiple ranges are requested
+    ///
+    /// The response is `multipart/byteranges` then, unless only one of
+    /// them overlaps the file.
+    pub fn is_multipart(&self) -> bool {
+        self.slices.len() > 1
+    }
+    /// Returns the ranges as specified in the header
+    pub fn slices(&self) -> &'a [Slice] {
+        self.slices
+    }
+    /// Iterate over `(start, end)` pairs for the file of `total` bytes
+    ///
+    /// Both ends are inclusive. Ranges that don't overlap the file are
+    /// skipped.
+    pub fn iter(&self, total: u64) -> Resolved<'a> {
+        Resolved {
+            slices: self.slices.iter(),
+            total: total,
+        }
+    }
+    /// Number of bytes of the file sent for the file of `total` bytes
+    ///
+    /// This doesn't include delimiters and headers of the parts of the
+    /// `multipart/byteranges` body. Returns `None` if the sum doesn't fit
+    /// `u64` (ranges may overlap, so it can be larger than `total`).
+    pub fn total_body_bytes(&self, total: u64) -> Option<u64> {
+        self.iter(total).try_fold(0u64, |sum, (start, end)| {
+            (end - start).checked_add(1).and_then(|len| sum.checked_add(len))
+        })
+    }
+}
+
+impl<'a> Iterator for Resolved<'a> {
+    type Item = (u64, u64);
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let total = self.total;
+        self.slices.by_ref().filter_map(|s| s.resolve(total)).next()
+    }
+}
+
 fn parse_header(header: &[u8]) -> Result<Range, ()> {
     let header = from_utf8(header).map_err(|_| {
         // Invalid utf-8 in range header
@@ -97,28 +238,51 @@ fn parse_header(header: &[u8]) -> Result<Range, ()> {
         // Invalid unit in range header
         return Err(());
     }
-    let mut slices = header[6..].split(",");
-    let slice = slices.next()
-        .ok_or_else(|| {
-            // Empty range header
-        })?;
-    let mut slice = parse_slice(slice)?;
-    for item in slices {
-        if !slice.merge(parse_slice(item)?) {
-            // Can't merge two ranges
-            return Err(());
+    let mut items = header[6..].split(",");
+    // most requests have a single range, which is parsed with no allocation
+    let mut first = parse_slice(items.next().unwrap_or(""))?;
+    let mut rest = Vec::new();
+    for item in items {
+        let slice = parse_slice(item)?;
+        if !first.merge(slice) &&
+           !rest.iter_mut().any(|s: &mut Slice| s.merge(slice))
+        {
+            rest.push(slice);
         }
     }
-    Ok(Range::SingleRangeOfBytes(slice))
+    if rest.is_empty() {
+        Ok(Range::SingleRangeOfBytes(first))
+    } else {
+        rest.insert(0, first);
+        Ok(Range::MultipleRangesOfBytes(rest.into_boxed_slice()))
+    }
 }
 
 impl RangeParser {
+    /// Create a parser
     pub fn new() -> RangeParser {
+        RangeParser::with_limit(DEFAULT_LIMIT)
+    }
+    /// Create a parser that accepts at most `max_bytes` of headers
+    ///
+    /// Longer header is invalid, and it is rejected before parsing, so
+    /// memory used for parsing is bounded. The default (in `new`) is
+    /// 8192 bytes.
+    pub fn with_limit(max_bytes: usize) -> RangeParser {
         RangeParser {
             result: Ok(None),
+            bytes_left: max_bytes,
         }
     }
+    /// Add a value of `Range` header
+    ///
+    /// Duplicate `Range` header makes the whole result invalid
     pub fn add_header(&mut self, header: &[u8]) {
+        if header.len() > self.bytes_left {
+            self.result = Err(());
+            return;
+        }
+        self.bytes_left -= header.len();
         match self.result {
             Err(()) => {}
             ref mut r @ Ok(Some(_)) => {
@@ -133,15 +297,27 @@ impl RangeParser {
             }
         }
     }
-    pub fn done(self) -> Result<Option<Range>, ()> {
-        self.result
+    /// Finish parsing
+    ///
+    /// Returns `Ok(None)` if there was no header and `Err(InvalidRange)`
+    /// if header is invalid (such header should be ignored, according to
+    /// the spec)
+    pub fn done(self) -> Result<Option<Range>, InvalidRange> {
+        self.result.map_err(|()| InvalidRange)
     }
 }
 
+impl Default for RangeParser {
+    fn default() -> RangeParser {
+        RangeParser::new()
+    }
+}
 
 #[cfg(test)]
 mod test {
-    use std::mem::size_of;
+    use core::mem::size_of;
+    use alloc::{format, vec};
+    use alloc::vec::Vec;
     use super::*;
 
     fn send<T: Send>(_: &T) {}
@@ -162,7 +338,7 @@ mod test {
         self_contained(&v);
     }
 
-    fn parse(x: &str) -> Result<Option<Range>, ()> {
+    fn parse(x: &str) -> Result<Option<Range>, InvalidRange> {
         let mut parser = RangeParser::new();
         parser.add_header(x.as_bytes());
         parser.done()
@@ -179,9 +355,87 @@ mod test {
             Ok(Some(Range::SingleRangeOfBytes(Slice::AllFrom(1000)))));
     }
 
+    fn resolved(x: &str, total: u64) -> Vec<(u64, u64)> {
+        parse(x).unwrap().unwrap().as_set().iter(total).collect()
+    }
+
+    #[test]
+    fn set_single() {
+        let range = parse("bytes=10-").unwrap().unwrap();
+        let set = range.as_set();
+        assert_eq!(set.len(), 1);
+        assert!(!set.is_multipart());
+        assert_eq!(set.iter(100).collect::<Vec<_>>(), vec![(10, 99)]);
+        assert_eq!(set.total_body_bytes(100), Some(90));
+        assert_eq!(set.total_body_bytes(5), Some(0));
+        assert_eq!(resolved("bytes=-10", 100), vec![(90, 99)]);
+        assert_eq!(resolved("bytes=-200", 100), vec![(0, 99)]);
+        assert_eq!(resolved("bytes=50-500", 100), vec![(50, 99)]);
+        assert_eq!(resolved("bytes=-0", 100), vec![]);
+        assert_eq!(resolved("bytes=0-", 0), vec![]);
+    }
+
+    #[test]
+    fn set_multi() {
+        let range = parse("bytes=0-9, 50-59, 200-").unwrap().unwrap();
+        let set = range.as_set();
+        assert_eq!(set.len(), 3);
+        assert!(set.is_multipart());
+        assert_eq!(set.iter(100).collect::<Vec<_>>(),
+                   vec![(0, 9), (50, 59)]);
+        assert_eq!(set.total_body_bytes(100), Some(20));
+        assert_eq!(set.total_body_bytes(1000), Some(820));
+    }
+
+    #[test]
+    fn set_body_bytes_overflow() {
+        let range = parse("bytes=0-, -10").unwrap().unwrap();
+        let set = range.as_set();
+        assert_eq!(set.total_body_bytes(100), Some(110));
+        assert_eq!(set.total_body_bytes(u64::MAX), None);
+    }
+
+    #[test]
+    fn set_coalesced() {
+        let range = parse("bytes=0-9, 10-19, 5-15").unwrap().unwrap();
+        let set = range.as_set();
+        assert_eq!(set.len(), 1);
+        assert!(!set.is_multipart());
+        assert_eq!(set.slices(), &[Slice::FromTo(0, 19)]);
+        assert_eq!(set.total_body_bytes(100), Some(20));
+    }
+
     #[test]
     fn bad_ranges() {
-        assert_eq!(parse("bytes=1000-100"), Err(()));
+        assert_eq!(parse("bytes=1000-100"), Err(InvalidRange));
+    }
+
+    #[test]
+    fn leading_zeros() {
+        assert_eq!(parse("bytes=00-09"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::FromTo(0, 9)))));
+        assert_eq!(parse("bytes=-007"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::Last(7)))));
+        assert_eq!(parse("bytes=000000000000000000000000001-"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::AllFrom(1)))));
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(parse("bytes=99999999999999999999-"), Err(InvalidRange));
+        assert_eq!(parse("bytes=0-18446744073709551616"), Err(InvalidRange));
+        assert_eq!(parse("bytes=-99999999999999999999"), Err(InvalidRange));
+        assert_eq!(parse("bytes=18446744073709551615-"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::AllFrom(u64::MAX)))));
+    }
+
+    #[test]
+    fn not_digits() {
+        assert_eq!(parse("bytes=+1-5"), Err(InvalidRange));
+        assert_eq!(parse("bytes=1-+5"), Err(InvalidRange));
+        assert_eq!(parse("bytes=-+5"), Err(InvalidRange));
+        assert_eq!(parse("bytes=0x10-"), Err(InvalidRange));
+        assert_eq!(parse("bytes=1 0-20"), Err(InvalidRange));
     }
 
     #[test]
@@ -192,6 +446,14 @@ mod test {
             Ok(Some(Range::SingleRangeOfBytes(Slice::FromTo(0, 2000)))));
     }
 
+    #[test]
+    fn merge_contained() {
+        assert_eq!(parse("bytes=0-19, 5-15"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::FromTo(0, 19)))));
+        assert_eq!(parse("bytes=5-15, 0-19"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::FromTo(0, 19)))));
+    }
+
     #[test]
     fn merge_overlapping() {
         assert_eq!(parse("bytes=0-1000, 1000-2000"),
@@ -206,7 +468,77 @@ mod test {
 
     #[test]
     fn no_merge() {
-        assert_eq!(parse("bytes=0-500,1000-2000"), Err(()));
+        assert_eq!(parse("bytes=0-500,1000-2000"),
+            Ok(Some(Range::MultipleRangesOfBytes(vec![
+                Slice::FromTo(0, 500),
+                Slice::FromTo(1000, 2000),
+            ].into_boxed_slice()))));
+        assert_eq!(parse("bytes=0-500,-100"),
+            Ok(Some(Range::MultipleRangesOfBytes(vec![
+                Slice::FromTo(0, 500),
+                Slice::Last(100),
+            ].into_boxed_slice()))));
+    }
+
+    #[test]
+    fn merge_into_multiple() {
+        assert_eq!(parse("bytes=0-500,1000-2000,400-600"),
+            Ok(Some(Range::MultipleRangesOfBytes(vec![
+                Slice::FromTo(0, 600),
+                Slice::FromTo(1000, 2000),
+            ].into_boxed_slice()))));
+    }
+
+    #[test]
+    fn no_merge_overflow() {
+        assert_eq!(parse("bytes=0-5, 10-18446744073709551615"),
+            Ok(Some(Range::MultipleRangesOfBytes(vec![
+                Slice::FromTo(0, 5),
+                Slice::FromTo(10, u64::MAX),
+            ].into_boxed_slice()))));
+        assert_eq!(parse("bytes=10-18446744073709551615, 0-5"),
+            Ok(Some(Range::MultipleRangesOfBytes(vec![
+                Slice::FromTo(10, u64::MAX),
+                Slice::FromTo(0, 5),
+            ].into_boxed_slice()))));
+    }
+
+    #[test]
+    fn benchmark_values() {
+        // same values as in `benches/parsers.rs`
+        assert_eq!(parse("bytes=0-"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::AllFrom(0)))));
+        assert_eq!(parse("bytes=1048576-2097151"),
+            Ok(Some(Range::SingleRangeOfBytes(
+                Slice::FromTo(1048576, 2097151)))));
+        assert_eq!(parse("bytes=0-499, 1000-1499, 2000-"),
+            Ok(Some(Range::MultipleRangesOfBytes(vec![
+                Slice::FromTo(0, 499),
+                Slice::FromTo(1000, 1499),
+                Slice::AllFrom(2000),
+            ].into_boxed_slice()))));
+        // merged into the first range after others are seen
+        assert_eq!(parse("bytes=0-10, 100-200, 5-20"),
+            Ok(Some(Range::MultipleRangesOfBytes(vec![
+                Slice::FromTo(0, 20),
+                Slice::FromTo(100, 200),
+            ].into_boxed_slice()))));
+        assert_eq!(parse("bytes="), Err(InvalidRange));
+        assert_eq!(parse("bytes=0-10,"), Err(InvalidRange));
+    }
+
+    #[test]
+    fn oversized() {
+        let header = format!("bytes={}0-1", "0-1, ".repeat(10));
+        let mut parser = RangeParser::with_limit(header.len());
+        parser.add_header(header.as_bytes());
+        assert!(parser.done().is_ok());
+        let mut parser = RangeParser::with_limit(header.len() - 1);
+        parser.add_header(header.as_bytes());
+        assert_eq!(parser.done(), Err(InvalidRange));
+
+        let huge = format!("bytes={}0-1", "0-1, 4-5, ".repeat(1 << 20));
+        assert_eq!(parse(&huge), Err(InvalidRange));
     }
 
     #[test]
@@ -0,0 +1,211 @@
+use std::fmt::Display;
+use std::io::{self, Read, Write, Seek};
+
+use output::{Output, Head, HeaderIter};
+
+
+/// Counts bytes written, to return them from `write_to`
+struct Counter<'a, W: 'a> {
+    inner: &'a mut W,
+    bytes: u64,
+}
+
+impl<'a, W: Write> Write for Counter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes = self.inner.write(buf)?;
+        self.bytes += bytes as u64;
+        Ok(bytes)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        412 => "Precondition Failed",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        // reason phrase may be empty
+        _ => "",
+    }
+}
+
+fn header<W: Write>(w: &mut W, name: &str, value: &Display)
+    -> io::Result<()>
+{
+    write!(w, "{}: {}\r\n", name, value)
+}
+
+fn head<W: Write>(w: &mut W, status_line: bool, status: u16,
+    headers: HeaderIter, content_length: Option<u64>)
+    -> io::Result<()>
+{
+    if status_line {
+        write!(w, "HTTP/1.1 {} {}\r\n", status, reason(status))?;
+    }
+    for (name, value) in headers {
+        header(w, name, value)?;
+    }
+    if let Some(len) = content_length {
+        header(w, "Content-Length", &len)?;
+    }
+    Ok(())
+}
+
+/// Response with no body and only `Content-Length: 0` (and `extra`)
+fn empty<W: Write>(w: &mut W, status_line: bool, status: u16,
+    extra: Option<(&str, &Display)>)
+    -> io::Result<()>
+{
+    if status_line {
+        write!(w, "HTTP/1.1 {} {}\r\n", status, reason(status))?;
+    }
+    if let Some((name, value)) = extra {
+        header(w, name, value)?;
+    }
+    header(w, "Content-Length", &0)?;
+    w.write_all(b"\r\n")
+}
+
+fn head_only<W: Write>(w: &mut W, status_line: bool, head: &Head)
+    -> io::Result<()>
+{
+    let clen = if head.is_not_modified() {
+        None
+    } else {
+        Some(head.content_length())
+    };
+    self::head(w, status_line, head.status(), head.headers(), clen)?;
+    w.write_all(b"\r\n")
+}
+
+impl<F: Read + Seek> Output<F> {
+    /// Write the whole response into a blocking `sink`
+    ///
+    /// This writes the status line (only if `status_line` is true, e.g.
+    /// for CGI-like protocols it's not needed), headers including
+    /// `Content-Length`, and the body. So it can be used directly by
+    /// servers serving on `std::net::TcpStream`.
+    ///
+    /// Responses that keep the decision to caller are written as in the
+    /// example: `NotFound` and `Directory` are 404, `InvalidRange` is 416,
+    /// `InvalidMethod` is 405 and `Options` is 200 (both with `Allow`).
+    /// Error bodies are empty. Headers added by `Head::append_header` and
+    /// `Config::server_header` are written too, but the latter is not
+    /// known for responses without a `Head`. For them write own headers
+    /// (like `Server`) after the status line and pass `status_line = false`.
+    ///
+    /// Returns number of bytes written.
+    ///
+    /// **Must be run in disk thread**
+    pub fn write_to<W: Write>(&mut self, status_line: bool, sink: &mut W)
+        -> io::Result<u64>
+    {
+        let mut w = Counter { inner: sink, bytes: 0 };
+        const ALLOW: &str = "GET, HEAD, OPTIONS";
+        match *self {
+            Output::File(ref mut outf) | Output::FileRange(ref mut outf) => {
+                head(&mut w, status_line, outf.status(), outf.headers(),
+                     Some(outf.content_length()))?;
+                w.write_all(b"\r\n")?;
+                while outf.read_chunk(&mut w)? > 0 {}
+            }
+            Output::FileHead(ref head)
+            | Output::InternalRedirect(ref head)
+            | Output::NotModified(ref head)
+            | Output::PreconditionFailed(ref head)
+            => head_only(&mut w, status_line, head)?,
+            Output::RangeNotSatisfiable(ref err) => {
+                if status_line {
+                    w.write_all(b"HTTP/1.1 416 Range Not Satisfiable\r\n")?;
+                }
+                header(&mut w, "Content-Range", &err.content_range())?;
+                if let Some(ctype) = err.content_type() {
+                    header(&mut w, "Content-Type", &ctype)?;
+                }
+                header(&mut w, "Content-Length", &err.body().len())?;
+                w.write_all(b"\r\n")?;
+                w.write_all(err.body())?;
+            }
+            Output::NotFound | Output::Directory => {
+                empty(&mut w, status_line, 404, None)?;
+            }
+            Output::Options => {
+                empty(&mut w, status_line, 200, Some(("Allow", &ALLOW)))?;
+            }
+            Output::InvalidMethod => {
+                empty(&mut w, status_line, 405, Some(("Allow", &ALLOW)))?;
+            }
+            Output::InvalidRange => empty(&mut w, status_line, 416, None)?,
+            Output::ServiceUnavailable(seconds) => {
+                empty(&mut w, status_line, 503,
+                      Some(("Retry-After", &seconds)))?;
+            }
+        }
+        Ok(w.bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::Config;
+    use input::Input;
+    use source::mock::MockSource;
+
+    fn write(method: &str, range: &str) -> String {
+        let source = MockSource::new().file("/www/file.txt", b"0123456789");
+        let headers = vec![("Range", range.as_bytes())];
+        let inp = Input::from_headers(&Config::new().etag(false).done(),
+                                      method, headers.into_iter());
+        let mut output = inp.probe_with(&source, "/www/file.txt").unwrap();
+        let mut buf = Vec::new();
+        let bytes = output.write_to(true, &mut buf).unwrap();
+        assert_eq!(bytes, buf.len() as u64);
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn ranged_response() {
+        assert_eq!(write("GET", "bytes=2-5"),
+            "HTTP/1.1 206 Partial Content\r\n\
+             Last-Modified: Tue, 22 Aug 2017 20:47:13 GMT\r\n\
+             Accept-Ranges: bytes\r\n\
+             Content-Range: bytes 2-5/10\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 4\r\n\
+             \r\n\
+             2345");
+        assert_eq!(write("HEAD", "bytes=2-5"),
+            "HTTP/1.1 206 Partial Content\r\n\
+             Last-Modified: Tue, 22 Aug 2017 20:47:13 GMT\r\n\
+             Accept-Ranges: bytes\r\n\
+             Content-Range: bytes 2-5/10\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 4\r\n\
+             \r\n");
+        assert_eq!(write("GET", "bytes=20-"),
+            "HTTP/1.1 416 Range Not Satisfiable\r\n\
+             Content-Range: bytes */10\r\n\
+             Content-Length: 0\r\n\
+             \r\n");
+    }
+
+    #[test]
+    fn multipart_body() {
+        let response = write("GET", "bytes=0-1,5-6");
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        let clen = response.lines()
+            .find(|line| line.starts_with("Content-Length: "))
+            .map(|line| line[16..].parse::<usize>().unwrap()).unwrap();
+        assert_eq!(body.len(), clen);
+        assert!(body.contains("\r\n\r\n01\r\n") && body.contains("\r\n\r\n56"));
+    }
+}
@@ -0,0 +1,63 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+
+/// Number of bytes read to detect the type
+const SNIFF_LEN: usize = 8;
+
+/// Only binary formats with unambiguous signatures are detected, so the
+/// sniffed type can't turn a file into something that browser executes
+/// (like `text/html`)
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\0asm", "application/wasm"),
+];
+
+/// Detect content type by magic bytes at the start of the file
+///
+/// The file is rewound to the start afterwards, so the whole file is
+/// still served as the body.
+pub(crate) fn sniff<F: Read + Seek>(file: &mut F)
+    -> io::Result<Option<&'static str>>
+{
+    let mut buf = [0u8; SNIFF_LEN];
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(bytes) => len += bytes,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(MAGIC.iter()
+        .find(|&&(magic, _)| buf[..len].starts_with(magic))
+        .map(|&(_, ctype)| ctype))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read};
+    use super::sniff;
+
+    #[test]
+    fn rewinds() {
+        let mut file = Cursor::new(&b"\0asm\x01\0\0\0"[..]);
+        assert_eq!(sniff(&mut file).unwrap(), Some("application/wasm"));
+        let mut body = Vec::new();
+        file.read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"\0asm\x01\0\0\0");
+    }
+
+    #[test]
+    fn short_and_unknown() {
+        assert_eq!(sniff(&mut Cursor::new(&b"GIF"[..])).unwrap(), None);
+        assert_eq!(sniff(&mut Cursor::new(&b"<html>"[..])).unwrap(), None);
+        assert_eq!(sniff(&mut Cursor::new(&b""[..])).unwrap(), None);
+    }
+}
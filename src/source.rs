@@ -0,0 +1,355 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek};
+use std::path::Path;
+use std::time::SystemTime;
+
+use etag::Etag;
+
+
+/// Abstraction over the filesystem used by `Input::probe_with`
+///
+/// Default implementation is `Filesystem`, which uses `std::fs`. Custom
+/// implementation may serve files from another storage (or a mock).
+pub trait FileSource {
+    /// The type of the opened file
+    type File: Read + Seek;
+    /// Returns metadata for the path (following symlinks)
+    ///
+    /// This is used to check whether the file exists without opening it.
+    fn stat(&self, path: &Path) -> io::Result<Metadata>;
+    /// Opens file for reading
+    fn open(&self, path: &Path) -> io::Result<Self::File>;
+    /// Returns metadata of the opened file
+    fn metadata(&self, file: &Self::File) -> io::Result<Metadata>;
+    /// Returns precomputed headers for the file at `path`
+    ///
+    /// Path includes the suffix of the encoding (e.g. `.gz`), as etag
+    /// of an encoded file is different. When this returns `None` (which is
+    /// the default), etag is derived from metadata and content type is
+    /// detected by extension.
+    fn manifest(&self, _path: &Path) -> Option<ManifestEntry> {
+        None
+    }
+    /// Returns whether the file at `path` is known to exist
+    ///
+    /// This is a hook for a presence map, e.g. collected at startup for
+    /// the immutable directory, so files that are known to be absent
+    /// (like most of the `.br` and `.gz` variants) are never opened nor
+    /// `stat`ed. It's consulted only for the files probed, the requested
+    /// path itself is always `stat`ed (as it may be a directory). When
+    /// this returns `None` (which is the default), filesystem is checked.
+    fn exists(&self, _path: &Path) -> Option<bool> {
+        None
+    }
+    /// Returns true if the file at `path` itself is a symlink
+    ///
+    /// This is called only if `Config::symlink_policy` is set. Default
+    /// implementation returns false, i.e. the source has no symlinks.
+    fn is_symlink(&self, _path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Headers of the file known in advance, e.g. from a build manifest
+#[derive(Debug, Clone)]
+pub struct ManifestEntry {
+    pub(crate) etag: Etag,
+    pub(crate) content_type: Option<String>,
+}
+
+/// Serves files from the real filesystem
+#[derive(Debug, Clone, Copy)]
+pub struct Filesystem;
+
+/// File metadata needed to build response headers
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub(crate) is_dir: bool,
+    pub(crate) is_file: bool,
+    pub(crate) len: u64,
+    pub(crate) modified: Option<SystemTime>,
+    pub(crate) created: Option<SystemTime>,
+    /// device, inode, ctime and ctime nanoseconds on unix systems
+    pub(crate) unix: Option<(u64, u64, i64, i64)>,
+    pub(crate) mode: Option<u32>,
+}
+
+impl Metadata {
+    /// Metadata of a regular file with specified length and modification
+    /// time
+    pub fn file(len: u64, modified: Option<SystemTime>) -> Metadata {
+        Metadata {
+            is_dir: false,
+            is_file: true,
+            len: len,
+            modified: modified,
+            created: None,
+            unix: None,
+            mode: None,
+        }
+    }
+    /// Metadata of a directory
+    pub fn dir() -> Metadata {
+        Metadata {
+            is_dir: true,
+            is_file: false,
+            len: 0,
+            modified: None,
+            created: None,
+            unix: None,
+            mode: None,
+        }
+    }
+    /// Set unix permission bits (e.g. `0o644`)
+    pub fn with_mode(mut self, mode: u32) -> Metadata {
+        self.mode = Some(mode);
+        self
+    }
+    /// Returns true if metadata describes a directory
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+    /// Returns true if metadata describes a regular file
+    pub fn is_file(&self) -> bool {
+        self.is_file
+    }
+    /// Returns the size of the file
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    /// Returns true if the file has no bytes
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Returns the modification time of the file
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+    /// Returns unix permission bits if known
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+}
+
+impl ManifestEntry {
+    /// Entry with the etag, that is quoted unless it's already quoted
+    pub fn new(etag: &str) -> ManifestEntry {
+        ManifestEntry {
+            etag: Etag::opaque(etag),
+            content_type: None,
+        }
+    }
+    /// Set content type instead of detecting it by extension
+    pub fn content_type(mut self, content_type: &str) -> ManifestEntry {
+        self.content_type = Some(content_type.into());
+        self
+    }
+}
+
+#[cfg(unix)]
+fn unix_fields(meta: &fs::Metadata) -> Option<(u64, u64, i64, i64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino(), meta.ctime(), meta.ctime_nsec()))
+}
+
+#[cfg(not(unix))]
+fn unix_fields(_: &fs::Metadata) -> Option<(u64, u64, i64, i64)> {
+    None
+}
+
+#[cfg(unix)]
+fn unix_mode(meta: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn unix_mode(_: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+impl From<&fs::Metadata> for Metadata {
+    fn from(meta: &fs::Metadata) -> Metadata {
+        Metadata {
+            is_dir: meta.is_dir(),
+            is_file: meta.is_file(),
+            len: meta.len(),
+            modified: meta.modified().ok(),
+            created: meta.created().ok(),
+            unix: unix_fields(meta),
+            mode: unix_mode(meta),
+        }
+    }
+}
+
+impl FileSource for Filesystem {
+    type File = File;
+    fn stat(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(|m| Metadata::from(&m))
+    }
+    fn open(&self, path: &Path) -> io::Result<File> {
+        File::open(path)
+    }
+    fn metadata(&self, file: &File) -> io::Result<Metadata> {
+        file.metadata().map(|m| Metadata::from(&m))
+    }
+    fn is_symlink(&self, path: &Path) -> io::Result<bool> {
+        fs::symlink_metadata(path).map(|m| m.file_type().is_symlink())
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use std::cell::RefCell;
+    use std::collections::{HashMap, HashSet};
+    use std::io::{self, Read, Seek, SeekFrom, Cursor};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{FileSource, Metadata, ManifestEntry};
+
+    #[derive(Debug)]
+    struct Entry {
+        data: Vec<u8>,
+        len: u64,
+    }
+
+    /// In-memory filesystem that records all the accesses
+    #[derive(Debug, Default)]
+    pub struct MockSource {
+        files: HashMap<PathBuf, Entry>,
+        dirs: HashSet<PathBuf>,
+        broken: HashSet<PathBuf>,
+        manifest: HashMap<PathBuf, ManifestEntry>,
+        presence: Option<HashSet<PathBuf>>,
+        symlinks: HashSet<PathBuf>,
+        pub stats: RefCell<Vec<PathBuf>>,
+        pub opens: RefCell<Vec<PathBuf>>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    #[derive(Debug)]
+    pub struct MockFile {
+        data: Cursor<Vec<u8>>,
+        len: u64,
+        broken: bool,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl MockSource {
+        pub fn new() -> MockSource {
+            MockSource::default()
+        }
+        pub fn file(self, path: &str, data: &[u8]) -> MockSource {
+            let len = data.len() as u64;
+            self.file_with_len(path, data, len)
+        }
+        /// File that reports `len` in metadata regardless of actual data
+        pub fn file_with_len(mut self, path: &str, data: &[u8], len: u64)
+            -> MockSource
+        {
+            self.files.insert(PathBuf::from(path), Entry {
+                data: data.to_vec(),
+                len: len,
+            });
+            self
+        }
+        pub fn manifest(mut self, path: &str, entry: ManifestEntry)
+            -> MockSource
+        {
+            self.manifest.insert(PathBuf::from(path), entry);
+            self
+        }
+        pub fn dir(mut self, path: &str) -> MockSource {
+            self.dirs.insert(PathBuf::from(path));
+            self
+        }
+        /// File that can be opened, but `metadata` of it fails
+        pub fn broken_file(mut self, path: &str, data: &[u8]) -> MockSource {
+            self.broken.insert(PathBuf::from(path));
+            self.file(path, data)
+        }
+        /// File that is reported as a symlink
+        pub fn symlink(mut self, path: &str, data: &[u8]) -> MockSource {
+            self.symlinks.insert(PathBuf::from(path));
+            self.file(path, data)
+        }
+        /// Report existence of the files added so far from memory
+        pub fn presence_map(mut self) -> MockSource {
+            self.presence = Some(self.files.keys().cloned().collect());
+            self
+        }
+        /// Number of `read` calls on all the files opened so far
+        pub fn reads(&self) -> usize {
+            self.reads.load(Ordering::SeqCst)
+        }
+        fn not_found() -> io::Error {
+            io::ErrorKind::NotFound.into()
+        }
+    }
+
+    fn meta(len: u64) -> Metadata {
+        Metadata::file(len, Some(UNIX_EPOCH + Duration::new(1503434833, 0)))
+    }
+
+    impl Read for MockFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.data.read(buf)
+        }
+    }
+
+    impl Seek for MockFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.data.seek(pos)
+        }
+    }
+
+    impl FileSource for MockSource {
+        type File = MockFile;
+        fn stat(&self, path: &Path) -> io::Result<Metadata> {
+            self.stats.borrow_mut().push(path.to_path_buf());
+            if self.dirs.contains(path) {
+                return Ok(Metadata::dir());
+            }
+            self.files.get(path)
+                .map(|entry| meta(entry.len))
+                .ok_or_else(MockSource::not_found)
+        }
+        fn open(&self, path: &Path) -> io::Result<MockFile> {
+            self.opens.borrow_mut().push(path.to_path_buf());
+            self.files.get(path)
+                .map(|entry| MockFile {
+                    data: Cursor::new(entry.data.clone()),
+                    len: entry.len,
+                    broken: self.broken.contains(path),
+                    reads: self.reads.clone(),
+                })
+                .ok_or_else(MockSource::not_found)
+        }
+        fn metadata(&self, file: &MockFile) -> io::Result<Metadata> {
+            if file.broken {
+                // e.g. a file on FUSE that is removed after opening
+                return Err(MockSource::not_found());
+            }
+            Ok(meta(file.len))
+        }
+        fn manifest(&self, path: &Path) -> Option<ManifestEntry> {
+            self.manifest.get(path).cloned()
+        }
+        fn exists(&self, path: &Path) -> Option<bool> {
+            self.presence.as_ref().map(|files| files.contains(path))
+        }
+        fn is_symlink(&self, path: &Path) -> io::Result<bool> {
+            if self.symlinks.contains(path) {
+                Ok(true)
+            } else if self.files.contains_key(path) {
+                Ok(false)
+            } else {
+                Err(MockSource::not_found())
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::borrow::Cow;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use accept_encoding::Encoding;
+use etag::Etag;
+use input::Input;
+use output::{Output, Vary};
+use source::Metadata;
+
+
+/// A part of the file that is served as a whole file
+///
+/// Offsets of `Read` and `Seek` are relative to the start of the window
+/// and reading stops at the end of the window.
+#[derive(Debug)]
+pub struct FileWindow<F> {
+    file: F,
+    offset: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<F: Seek> FileWindow<F> {
+    /// Wrap the file and seek it to the start of the window
+    pub fn new(mut file: F, offset: u64, len: u64)
+        -> io::Result<FileWindow<F>>
+    {
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(FileWindow {
+            file: file,
+            offset: offset,
+            len: len,
+            pos: 0,
+        })
+    }
+}
+
+impl<F> FileWindow<F> {
+    /// Returns the wrapped file
+    pub fn into_inner(self) -> F {
+        self.file
+    }
+}
+
+impl<F: Read> Read for FileWindow<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.len.saturating_sub(self.pos);
+        let max = if (buf.len() as u64) < left {
+            buf.len()
+        } else {
+            left as usize
+        };
+        let bytes = self.file.read(&mut buf[..max])?;
+        self.pos += bytes as u64;
+        Ok(bytes)
+    }
+}
+
+impl<F: Seek> Seek for FileWindow<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::Current(x) => offset(self.pos, x),
+            SeekFrom::End(x) => offset(self.len, x),
+        };
+        let pos = pos.ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position"))?;
+        let abs = self.offset.checked_add(pos).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput, "seek position overflows"))?;
+        self.file.seek(SeekFrom::Start(abs))?;
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+fn offset(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    }
+}
+
+impl<F: Read + Seek> Output<FileWindow<F>> {
+    /// Build output for the window of the file that is already opened
+    ///
+    /// The `len` bytes at `offset` are served as the whole file, i.e.
+    /// `Content-Length`, ranges and etag are relative to the window. This
+    /// is useful to serve files from packed archives. The `metadata` is
+    /// for the whole `file`, which must contain the window.
+    pub fn from_file_window(inp: &Input, metadata: &Metadata, file: F,
+        offset: u64, len: u64, content_type: &str)
+        -> io::Result<Output<FileWindow<F>>>
+    {
+        let end = offset.checked_add(len);
+        if end.map(|end| end > metadata.len()).unwrap_or(true) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "window is out of the file bounds"));
+        }
+        let mut meta = metadata.clone();
+        meta.len = len;
+        let etag = Etag::from_window(&meta, offset,
+                                     inp.config.etag_strategy);
+        let window = FileWindow::new(file, offset, len)?;
+        Output::from_open_file_with(inp, Encoding::Identity, &meta, window,
+            Cow::Owned(content_type.into()), Some(etag), Vary::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor, Read, Seek, SeekFrom};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use source::Metadata;
+    use super::FileWindow;
+
+    const BLOB: &[u8] = b"aaaaHELLO WORLDbbbb";
+
+    fn window(range: &str, offset: u64, len: u64)
+        -> Output<FileWindow<Cursor<&'static [u8]>>>
+    {
+        let headers = vec![("Range", range.as_bytes())];
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      headers.into_iter());
+        let meta = Metadata::file(BLOB.len() as u64,
+            Some(UNIX_EPOCH + Duration::new(1503434833, 0)));
+        Output::from_file_window(&inp, &meta, Cursor::new(BLOB),
+                                 offset, len, "text/plain").unwrap()
+    }
+
+    fn header(outf: &Output<FileWindow<Cursor<&'static [u8]>>>, name: &str)
+        -> String
+    {
+        match *outf {
+            Output::File(ref outf) => outf.headers()
+                .find(|&(n, _)| n == name)
+                .map(|(_, value)| value.to_string()).unwrap(),
+            ref x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn range_in_window() {
+        let mut output = window("bytes=6-10", 4, 11);
+        assert_eq!(header(&output, "Content-Range"), "bytes 6-10/11");
+        let mut body = Vec::new();
+        match output {
+            Output::File(ref mut outf) => {
+                assert_eq!(outf.content_length(), 5);
+                while outf.read_chunk(&mut body).unwrap() > 0 {}
+            }
+            ref x => panic!("unexpected output {:?}", x),
+        }
+        assert_eq!(body, b"WORLD");
+
+        let output = window("bytes=-8", 4, 11);
+        assert_eq!(header(&output, "Content-Range"), "bytes 3-10/11");
+        match window("bytes=11-", 4, 11) {
+            Output::RangeNotSatisfiable(e) => assert_eq!(e.file_size(), 11),
+            x => panic!("unexpected output {:?}", x),
+        }
+    }
+
+    #[test]
+    fn window_etag() {
+        assert_ne!(header(&window("bytes=0-", 0, 4), "ETag"),
+                   header(&window("bytes=0-", 15, 4), "ETag"));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let inp = Input::from_headers(&Config::new().done(), "GET",
+                                      Vec::new().into_iter());
+        let meta = Metadata::file(BLOB.len() as u64, None);
+        let err = Output::from_file_window(&inp, &meta, Cursor::new(BLOB),
+                                           10, 10, "text/plain").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn seek_and_read() {
+        let mut win = FileWindow::new(Cursor::new(BLOB), 4, 11).unwrap();
+        let mut buf = String::new();
+        win.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "HELLO WORLD");
+        assert_eq!(win.seek(SeekFrom::End(-5)).unwrap(), 6);
+        buf.clear();
+        win.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "WORLD");
+        assert!(win.seek(SeekFrom::Current(-20)).is_err());
+    }
+}
@@ -0,0 +1,50 @@
+//! Checks that a single `If-None-Match` etag is parsed without allocation
+//!
+//! It's a separate test binary because it replaces the global allocator
+extern crate http_file_headers;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use http_file_headers::{Config, Input};
+
+
+/// Counts allocations made by the current thread
+struct Counting;
+
+thread_local!(static ALLOCATIONS: Cell<usize> = const { Cell::new(0) });
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.try_with(|x| x.set(x.get() + 1)).ok();
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(|x| x.get());
+    f();
+    ALLOCATIONS.with(|x| x.get()) - before
+}
+
+#[test]
+fn single_etag_no_allocation() {
+    let etag = br#"W/"tYJT9KJUI0KX2I5q""#;
+    let cfg = Config::new().done();
+    let headers = [("If-None-Match", &etag[..])];
+    let without = allocations(|| {
+        Input::from_headers(&cfg, "GET", Vec::new().into_iter());
+    });
+    let mut inp = None;
+    let with = allocations(|| {
+        inp = Some(Input::from_headers(&cfg, "GET", headers.iter().cloned()));
+    });
+    assert_eq!(with, without);
+    assert_eq!(inp.unwrap().conditionals().if_none_match.len(), 1);
+}